@@ -0,0 +1,244 @@
+//! Screenshots and short clips of what is on screen, in the colours it was shown in.
+//! They are stored with as few colours as the screen used, so they come out small and exact.
+
+use std::borrow::Cow;
+use std::collections::{HashMap, VecDeque};
+use std::collections::hash_map::Entry;
+use std::fs::File;
+use std::io::BufWriter;
+
+use gif;
+use png;
+
+/// Gameboy frames a second.
+const FPS: f64 = 4194304.0 / 70224.0;
+
+/// Every colour used, in the order first seen, or None if there are more than `most`.
+fn colour_table<'a, I>(screens: I, most: usize) -> Option<Vec<[u8; 3]>>
+    where I: Iterator<Item = &'a [u8]> {
+    let mut seen = HashMap::new();
+    let mut table = vec![];
+    for screen in screens {
+        for pixel in screen.chunks(3) {
+            let colour = [pixel[0], pixel[1], pixel[2]];
+            if let Entry::Vacant(entry) = seen.entry(colour) {
+                if table.len() == most {
+                    return None;
+                }
+                entry.insert(table.len() as u8);
+                table.push(colour);
+            }
+        }
+    }
+    Some(table)
+}
+
+/// A screen as indexes into a table that holds all its colours.
+fn indexes(screen: &[u8], table: &[[u8; 3]]) -> Vec<u8> {
+    let lookup: HashMap<[u8; 3], u8> = table.iter().enumerate()
+        .map(|(i, colour)| (*colour, i as u8))
+        .collect();
+    screen.chunks(3).map(|pixel| lookup[&[pixel[0], pixel[1], pixel[2]]]).collect()
+}
+
+/// The last few seconds of frames shown, all the same size.
+pub struct Clip {
+    // Each frame with how many gameboy frames it stands for, more than one when frames
+    // were skipped to go faster.
+    frames: VecDeque<(Vec<u8>, usize)>,
+    length: usize, // Gameboy frames held.
+    capacity: usize,
+    width: usize,
+}
+
+impl Clip {
+    pub fn new(seconds: u32) -> Clip {
+        Clip {
+            frames: VecDeque::new(),
+            length: 0,
+            capacity: (seconds as f64 * FPS) as usize,
+            width: 0,
+        }
+    }
+
+    /// Frames of a different width from those held start a new clip.
+    pub fn push(&mut self, screen: &[u8], width: usize, frames: usize) {
+        if width != self.width {
+            self.frames.clear();
+            self.length = 0;
+            self.width = width;
+        }
+        self.frames.push_back((screen.to_vec(), frames));
+        self.length += frames;
+        while self.length > self.capacity {
+            match self.frames.pop_front() {
+                Some((_, frames)) => self.length -= frames,
+                None => break,
+            }
+        }
+    }
+
+    /// Copy out what is held, so it can be written without holding anything up.
+    pub fn frames(&self) -> Vec<(Vec<u8>, usize)> {
+        self.frames.iter().cloned().collect()
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+}
+
+/// The smallest rectangle holding every pixel that differs, as left, top, width and height.
+fn changed(before: &[u8], after: &[u8], width: usize) -> Option<(usize, usize, usize, usize)> {
+    let (mut left, mut top, mut right, mut bottom) = (width, usize::MAX, 0, 0);
+    let pixels = before.chunks(3).zip(after.chunks(3));
+    for (i, _) in pixels.enumerate().filter(|(_, (b, a))| b != a) {
+        let (x, y) = (i % width, i / width);
+        left = left.min(x);
+        right = right.max(x);
+        top = top.min(y);
+        bottom = y;
+    }
+    match top {
+        usize::MAX => None,
+        _ => Some((left, top, right - left + 1, bottom - top + 1)),
+    }
+}
+
+/// Write frames as a looping gif.  Frames that do not change are merged into the one before,
+/// and only the part of each frame that changed is stored.  When the whole clip fits in 256
+/// colours they share one table, otherwise each frame gets its own.
+///
+/// Gif delays are in hundredths of a second and players slow anything under two right
+/// down, so frames are dropped to keep each one up for at least that long.
+pub fn save_gif(path: &str, width: usize, frames: &[(Vec<u8>, usize)]) -> Result<(), String> {
+    let file = File::create(path).map_err(|e| format!("{}: {}", path, e))?;
+    let height = frames.first().map_or(0, |(screen, _)| screen.len() / 3 / width.max(1));
+    let table = colour_table(frames.iter().map(|(screen, _)| &screen[..]), 256);
+    let colours: Vec<u8> = table.iter().flatten().flat_map(|c| c.iter().cloned()).collect();
+    let mut encoder = gif::Encoder::new(BufWriter::new(file), width as u16, height as u16,
+                                        &colours)
+        .map_err(|e| format!("{}: {}", path, e))?;
+    encoder.set_repeat(gif::Repeat::Infinite).map_err(|e| format!("{}: {}", path, e))?;
+
+    // Delays are worked out from the total so the rounding does not add up.
+    let mut shown = 0.0;
+    let mut written = 0;
+    let mut pending: Option<gif::Frame> = None;
+    let mut previous: Option<&[u8]> = None;
+    for (screen, frames) in frames {
+        let due = pending.as_ref().is_none_or(|frame| frame.delay >= 2);
+        let region = match previous {
+            _ if !due => None,
+            Some(before) => changed(before, screen, width),
+            None => Some((0, 0, width, height)),
+        };
+        if let Some((left, top, changed_width, changed_height)) = region {
+            if let Some(frame) = pending.take() {
+                encoder.write_frame(&frame).map_err(|e| format!("{}: {}", path, e))?;
+            }
+            let row = width * 3;
+            let rgb: Vec<u8> = (top..top + changed_height)
+                .flat_map(|y| screen[y * row + left * 3..y * row + (left + changed_width) * 3].iter())
+                .cloned()
+                .collect();
+            let mut frame = match table {
+                Some(ref table) => gif::Frame {
+                    width: changed_width as u16,
+                    height: changed_height as u16,
+                    buffer: Cow::Owned(indexes(&rgb, table)),
+                    ..gif::Frame::default()
+                },
+                None => gif::Frame::from_rgb_speed(changed_width as u16, changed_height as u16,
+                                                   &rgb, 10),
+            };
+            frame.left = left as u16;
+            frame.top = top as u16;
+            pending = Some(frame);
+            previous = Some(screen);
+        }
+        shown += *frames as f64 * 100.0 / FPS;
+        if let Some(ref mut frame) = pending {
+            let delay = shown.round() as u16 - written;
+            frame.delay += delay;
+            written += delay;
+        }
+    }
+    if let Some(mut frame) = pending {
+        frame.delay = frame.delay.max(2);
+        encoder.write_frame(&frame).map_err(|e| format!("{}: {}", path, e))?;
+    }
+    Ok(())
+}
+
+/// Write one screen as a png, indexed with as few bits as its colours need.
+pub fn save_png(path: &str, screen: &[u8], width: usize) -> Result<(), String> {
+    let file = File::create(path).map_err(|e| format!("{}: {}", path, e))?;
+    let height = screen.len() / 3 / width;
+    let mut encoder = png::Encoder::new(BufWriter::new(file), width as u32, height as u32);
+    let data = match colour_table(Some(screen).into_iter(), 256) {
+        Some(table) => {
+            let (depth, bits) = match table.len() {
+                0..=4 => (png::BitDepth::Two, 2),
+                5..=16 => (png::BitDepth::Four, 4),
+                _ => (png::BitDepth::Eight, 8),
+            };
+            encoder.set_color(png::ColorType::Indexed);
+            encoder.set_depth(depth);
+            encoder.set_palette(table.iter().flat_map(|c| c.iter().cloned()).collect::<Vec<u8>>());
+            // Rows are a whole number of bytes for all three depths.
+            indexes(screen, &table).chunks(8 / bits)
+                .map(|pixels| pixels.iter().fold(0, |byte, index| byte << bits | index))
+                .collect()
+        }
+        None => {
+            encoder.set_color(png::ColorType::Rgb);
+            encoder.set_depth(png::BitDepth::Eight);
+            screen.to_vec()
+        }
+    };
+    encoder.write_header().and_then(|mut writer| writer.write_image_data(&data))
+        .map_err(|e| format!("{}: {}", path, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use capture::{changed, colour_table, Clip};
+    use ::{GAMEBOY_WIDTH, GAMEBOY_HEIGHT};
+
+    const PIXELS: usize = (GAMEBOY_WIDTH * GAMEBOY_HEIGHT) as usize;
+
+    #[test]
+    fn clip_keeps_the_last_seconds() {
+        let mut clip = Clip::new(1);
+        let screen = vec![255; PIXELS * 3];
+        for _ in 0..100 {
+            clip.push(&screen, 160, 1);
+        }
+        assert_eq!(clip.frames().len(), 59);
+        clip.push(&screen, 160, 4);
+        assert_eq!(clip.frames().len(), 56);
+        // A Super Game Boy border coming or going starts again.
+        clip.push(&vec![255; 256 * 224 * 3], 256, 1);
+        assert_eq!(clip.frames().len(), 1);
+        assert_eq!(clip.width(), 256);
+    }
+
+    #[test]
+    fn only_changes_are_kept() {
+        let before = vec![0; PIXELS * 3];
+        let mut after = before.clone();
+        assert_eq!(changed(&before, &after, 160), None);
+        after[(160 * 10 + 20) * 3] = 1;
+        after[(160 * 12 + 5) * 3 + 2] = 2;
+        assert_eq!(changed(&before, &after, 160), Some((5, 10, 16, 3)));
+    }
+
+    #[test]
+    fn colours_are_counted() {
+        let screen = [0, 0, 0, 255, 255, 255, 0, 0, 0, 1, 2, 3];
+        assert_eq!(colour_table(Some(&screen[..]).into_iter(), 4),
+                   Some(vec![[0, 0, 0], [255, 255, 255], [1, 2, 3]]));
+        assert_eq!(colour_table(Some(&screen[..]).into_iter(), 2), None);
+    }
+}
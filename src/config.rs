@@ -0,0 +1,394 @@
+//! Settings from a TOML file, by default ~/.config/fegabo/config.toml.
+//!
+//! Everything is optional, anything missing keeps its default:
+//!
+//! ```toml
+//! scale = 3
+//! palette = "dmg"
+//! colourise = true
+//! sgb = true
+//! sgb_border = false
+//! boot_rom = "/home/me/roms/dmg_boot.bin"
+//!
+//! [palettes]
+//! mint = ["#E0F8D0", "#88C070", "#346856", "#081820"]
+//! sunset = "/home/me/palettes/sunset.hex"
+//!
+//! [keys]
+//! a = "Z"
+//! start = "Return"
+//!
+//! [hotkeys]
+//! pause = "Space"
+//!
+//! [controller]
+//! a = "b"
+//! b = "a"
+//! deadzone = 12000
+//! ```
+//!
+//! Keys use SDL key names, controller buttons SDL game controller button names.
+//!
+//! The palette to start with is four colours, one of the built in palettes (grey, dmg and
+//! pocket), one from `[palettes]` or a palette file.  The palette hotkey goes through
+//! them all.  Colourising shows monochrome games in the colours a Game Boy Color would
+//! pick instead, holding a direction and A or B when it starts or resets picks others.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+use toml;
+
+use palette::{self, Palette};
+
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Window size as a multiple of the gameboy screen.
+    pub scale: u32,
+    pub palette: PaletteSetting,
+    /// More palettes to switch between.
+    pub palettes: BTreeMap<String, PaletteSetting>,
+    pub colourise: bool,
+    /// Run games that support it on a Super Game Boy.
+    pub sgb: bool,
+    /// Show the Super Game Boy border around the screen.
+    pub sgb_border: bool,
+    /// Boot rom to use when none is given on the command line.
+    pub boot_rom: Option<String>,
+    /// How much the gif hotkey saves.
+    pub gif_seconds: u32,
+    pub keys: Keys,
+    pub hotkeys: Hotkeys,
+    pub controller: Controller,
+}
+
+/// Four shades, lightest first, as "#RRGGBB", or where to find them.
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+#[serde(untagged)]
+pub enum PaletteSetting {
+    Colours(Vec<String>),
+    /// The name of a palette, or failing that a palette file.
+    Name(String),
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Keys {
+    pub a: String,
+    pub b: String,
+    pub start: String,
+    pub select: String,
+    pub up: String,
+    pub down: String,
+    pub left: String,
+    pub right: String,
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Hotkeys {
+    pub quit: String,
+    pub fast_forward: String,
+    pub slower: String,
+    pub faster: String,
+    pub pause: String,
+    pub frame_advance: String,
+    pub reset: String,
+    pub reload: String,
+    /// Start or stop recording video.
+    pub video: String,
+    /// Start or stop recording a movie from here.
+    pub movie: String,
+    /// Save the last few seconds as a gif.
+    pub gif: String,
+    pub screenshot: String,
+    /// Switch to the next palette.
+    pub palette: String,
+    /// Show or hide the Super Game Boy border.
+    pub border: String,
+}
+
+/// Game controller buttons, the left stick works as the d-pad too.
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Controller {
+    pub a: String,
+    pub b: String,
+    pub start: String,
+    pub select: String,
+    pub up: String,
+    pub down: String,
+    pub left: String,
+    pub right: String,
+    /// How far the stick has to move, out of 32767, to press a direction.
+    pub deadzone: i16,
+}
+
+/// What a key does.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    A,
+    B,
+    Start,
+    Select,
+    Up,
+    Down,
+    Left,
+    Right,
+    Quit,
+    FastForward,
+    Slower,
+    Faster,
+    Pause,
+    FrameAdvance,
+    Reset,
+    Reload,
+    Video,
+    Movie,
+    Gif,
+    Screenshot,
+    Palette,
+    Border,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            scale: 4,
+            palette: PaletteSetting::Name(palette::BUILT_IN[0].0.to_string()),
+            palettes: BTreeMap::new(),
+            colourise: false,
+            sgb: false,
+            sgb_border: true,
+            boot_rom: None,
+            gif_seconds: 10,
+            keys: Keys::default(),
+            hotkeys: Hotkeys::default(),
+            controller: Controller::default(),
+        }
+    }
+}
+
+impl Default for Keys {
+    fn default() -> Self {
+        Keys {
+            a: "C".to_string(),
+            b: "X".to_string(),
+            start: "B".to_string(),
+            select: "V".to_string(),
+            up: "Up".to_string(),
+            down: "Down".to_string(),
+            left: "Left".to_string(),
+            right: "Right".to_string(),
+        }
+    }
+}
+
+impl Default for Hotkeys {
+    fn default() -> Self {
+        Hotkeys {
+            quit: "Escape".to_string(),
+            fast_forward: "Tab".to_string(),
+            slower: "[".to_string(),
+            faster: "]".to_string(),
+            pause: "P".to_string(),
+            frame_advance: "N".to_string(),
+            reset: "R".to_string(),
+            reload: "F5".to_string(),
+            video: "F9".to_string(),
+            movie: "F8".to_string(),
+            gif: "F10".to_string(),
+            screenshot: "F12".to_string(),
+            palette: "F2".to_string(),
+            border: "F3".to_string(),
+        }
+    }
+}
+
+impl Default for Controller {
+    fn default() -> Self {
+        Controller {
+            a: "a".to_string(),
+            b: "b".to_string(),
+            start: "start".to_string(),
+            select: "back".to_string(),
+            up: "dpup".to_string(),
+            down: "dpdown".to_string(),
+            left: "dpleft".to_string(),
+            right: "dpright".to_string(),
+            deadzone: 8000,
+        }
+    }
+}
+
+impl Config {
+    /// Read the config at `path`, or the default location if there is none given.
+    /// Only a missing file at the default location is not an error.
+    pub fn load(path: Option<&str>) -> Result<Config, String> {
+        let (path, required) = match path {
+            Some(path) => (PathBuf::from(path), true),
+            None => match Self::default_path() {
+                Some(path) => (path, false),
+                None => return Ok(Config::default()),
+            },
+        };
+        match fs::read_to_string(&path) {
+            Ok(text) => Self::parse(&text)
+                .map_err(|e| format!("{}: {}", path.display(), e)),
+            Err(_) if !required => Ok(Config::default()),
+            Err(e) => Err(format!("{}: {}", path.display(), e)),
+        }
+    }
+
+    fn default_path() -> Option<PathBuf> {
+        let dir = match env::var_os("XDG_CONFIG_HOME") {
+            Some(dir) => PathBuf::from(dir),
+            None => PathBuf::from(env::var_os("HOME")?).join(".config"),
+        };
+        Some(dir.join("fegabo").join("config.toml"))
+    }
+
+    pub fn parse(text: &str) -> Result<Config, String> {
+        let config: Config = toml::from_str(text).map_err(|e| e.to_string())?;
+        if config.scale == 0 {
+            return Err("scale must be at least 1".to_string());
+        }
+        if config.gif_seconds == 0 {
+            return Err("gif_seconds must be at least 1".to_string());
+        }
+        if config.controller.deadzone < 0 {
+            return Err("deadzone can not be negative".to_string());
+        }
+        for setting in Some(&config.palette).into_iter().chain(config.palettes.values()) {
+            if let PaletteSetting::Colours(ref colours) = setting {
+                palette::parse(colours)?;
+            }
+        }
+        Ok(config)
+    }
+
+    /// Key names and what they do.
+    pub fn bindings(&self) -> Vec<(&str, Action)> {
+        let (k, h) = (&self.keys, &self.hotkeys);
+        vec![
+            (&k.a, Action::A),
+            (&k.b, Action::B),
+            (&k.start, Action::Start),
+            (&k.select, Action::Select),
+            (&k.up, Action::Up),
+            (&k.down, Action::Down),
+            (&k.left, Action::Left),
+            (&k.right, Action::Right),
+            (&h.quit, Action::Quit),
+            (&h.fast_forward, Action::FastForward),
+            (&h.slower, Action::Slower),
+            (&h.faster, Action::Faster),
+            (&h.pause, Action::Pause),
+            (&h.frame_advance, Action::FrameAdvance),
+            (&h.reset, Action::Reset),
+            (&h.reload, Action::Reload),
+            (&h.video, Action::Video),
+            (&h.movie, Action::Movie),
+            (&h.gif, Action::Gif),
+            (&h.screenshot, Action::Screenshot),
+            (&h.palette, Action::Palette),
+            (&h.border, Action::Border),
+        ].into_iter().map(|(key, action)| (key.as_str(), action)).collect()
+    }
+
+    /// Controller button names and the gameboy button they press.
+    pub fn controller_bindings(&self) -> Vec<(&str, Action)> {
+        let c = &self.controller;
+        vec![
+            (&c.a, Action::A),
+            (&c.b, Action::B),
+            (&c.start, Action::Start),
+            (&c.select, Action::Select),
+            (&c.up, Action::Up),
+            (&c.down, Action::Down),
+            (&c.left, Action::Left),
+            (&c.right, Action::Right),
+        ].into_iter().map(|(button, action)| (button.as_str(), action)).collect()
+    }
+
+    /// Every palette to switch between, and which to start with.
+    pub fn palettes(&self) -> Result<(Vec<Palette>, usize), String> {
+        let mut palettes = palette::built_in();
+        for (name, setting) in &self.palettes {
+            let colours = match setting {
+                PaletteSetting::Colours(colours) => palette::parse(colours)?,
+                PaletteSetting::Name(path) => palette::load(path)?,
+            };
+            palettes.push(Palette { name: name.clone(), colours });
+        }
+        let start = match self.palette {
+            PaletteSetting::Name(ref name) => match palettes.iter().position(|p| &p.name == name) {
+                Some(start) => start,
+                None => {
+                    palettes.push(Palette { name: name.clone(), colours: palette::load(name)? });
+                    palettes.len() - 1
+                }
+            },
+            PaletteSetting::Colours(ref colours) => {
+                let colours = palette::parse(colours)?;
+                palettes.push(Palette { name: "custom".to_string(), colours });
+                palettes.len() - 1
+            }
+        };
+        Ok((palettes, start))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use config::{Config, Action, PaletteSetting};
+
+    #[test]
+    fn missing_values_keep_defaults() {
+        let config = Config::parse("
+            scale = 2
+            [keys]
+            a = \"Z\"
+        ").unwrap();
+        assert_eq!(config.scale, 2);
+        assert_eq!(config.keys.a, "Z");
+        assert_eq!(config.keys.b, "X");
+        assert_eq!(config.hotkeys, Config::default().hotkeys);
+        assert_eq!(config.controller.deadzone, 8000);
+        assert!(config.bindings().contains(&("Z", Action::A)));
+        let (palettes, start) = config.palettes().unwrap();
+        assert_eq!(palettes[start].name, "grey");
+    }
+
+    #[test]
+    fn palettes() {
+        let config = Config::parse("
+            palette = \"mint\"
+            [palettes]
+            mint = [\"#E0F8D0\", \"#88C070\", \"346856\", \"#081820\"]
+        ").unwrap();
+        let (palettes, start) = config.palettes().unwrap();
+        assert_eq!(palettes.len(), 4);
+        assert_eq!(palettes[start].colours[3], [0x08, 0x18, 0x20]);
+
+        let config = Config::parse("palette = [\"#FFFFFF\", \"#AAAAAA\", \"#555555\", \"#000000\"]")
+            .unwrap();
+        assert!(matches!(config.palette, PaletteSetting::Colours(_)));
+        assert_eq!(config.palettes().unwrap().1, 3);
+
+        assert!(Config::parse("palette = [\"#FFFFFF\"]").is_err());
+        assert!(Config::parse("[palettes]\nbad = [\"white\", \"#AAAAAA\", \"#555555\", \"#000000\"]")
+                .is_err());
+        assert!(Config::parse("palette = \"nowhere.hex\"").unwrap().palettes().is_err());
+    }
+
+    #[test]
+    fn mistakes_are_errors() {
+        assert!(Config::parse("scael = 2").is_err());
+        assert!(Config::parse("scale = 0").is_err());
+        assert!(Config::parse("[keys]\nstrat = \"Return\"").is_err());
+    }
+}
@@ -0,0 +1,472 @@
+//! Assembler for the syntax printed by `Op`'s `Display`.
+//!
+//! Instructions are looked up by their shape, the mnemonic and operands with any immediate
+//! replaced by `#`, in a table built from the decoder itself.  Immediates use the same radix
+//! the disassembly prints them in: hex for data and addresses, decimal for relative jumps,
+//! stack offsets, bit numbers and restart vectors.  `$` or `0x` force hex and `%` binary.
+//!
+//! Labels end with `:` and can be used anywhere a value is expected, along with `+` and `-`.
+//! A relative jump to an expression using a label jumps to that address, otherwise the value
+//! is the offset itself.  Names made only of hex digits are read as numbers where hex is
+//! expected, so `add:` cannot be used as a label there.
+//!
+//! `DB` emits bytes and `ORG` moves the output forward, padding with zero.  An `ORG` before
+//! anything else only sets the address the output starts at.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::OnceLock;
+
+use super::decode;
+use super::decode::{ByteR, Op, WordR};
+
+#[derive(Debug)]
+pub struct AsmError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Line {}: {}", self.line, self.message)
+    }
+}
+
+/// How the immediate of an instruction is encoded.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Imm {
+    None,
+    U8,
+    U16,
+    /// Signed offset from the end of the instruction.
+    Rel8,
+    /// Signed offset added to SP.
+    S8,
+}
+
+impl Imm {
+    fn size(self) -> u16 {
+        match self {
+            Imm::None => 0,
+            Imm::U8 | Imm::Rel8 | Imm::S8 => 1,
+            Imm::U16 => 2,
+        }
+    }
+
+    fn radix(self) -> u32 {
+        match self {
+            Imm::Rel8 | Imm::S8 => 10,
+            _ => 16,
+        }
+    }
+}
+
+struct Entry {
+    bytes: Vec<u8>,
+    imm: Imm,
+}
+
+fn imm_kind(op: &Op) -> Imm {
+    use self::Op::*;
+    match op {
+        JR(_, _) => Imm::Rel8,
+        SPADD(_) | SPLD(_) => Imm::S8,
+        CALL(_, WordR::IMM(_)) | JP(_, WordR::IMM(_)) => Imm::U16,
+        LD16(_, WordR::IMM(_)) | LDMem(WordR::IMM(_), _) => Imm::U16,
+        LD8(ByteR::Mem(WordR::IMM(_)), _) | LD8(_, ByteR::Mem(WordR::IMM(_))) => Imm::U16,
+        LD8(ByteR::Mem(WordR::High(_)), _) | LD8(_, ByteR::Mem(WordR::High(_))) => Imm::U8,
+        LD8(_, ByteR::IMM(_)) => Imm::U8,
+        ADD8(ByteR::IMM(_)) | ADC(ByteR::IMM(_)) | SUB(ByteR::IMM(_)) | SBC(ByteR::IMM(_)) |
+        AND(ByteR::IMM(_)) | OR(ByteR::IMM(_)) | XOR(ByteR::IMM(_)) | CP(ByteR::IMM(_)) => Imm::U8,
+        _ => Imm::None,
+    }
+}
+
+/// Every instruction the decoder knows, by shape.
+fn table() -> &'static HashMap<String, Entry> {
+    static TABLE: OnceLock<HashMap<String, Entry>> = OnceLock::new();
+    TABLE.get_or_init(build_table)
+}
+
+fn build_table() -> HashMap<String, Entry> {
+    let mut table = HashMap::new();
+    let ops = (0..=0xFFu8)
+        .filter(|op| *op != 0xCB && !decode::is_illegal(*op))
+        .map(|op| (vec![op], decode::decode_internal(op, 0, 0).1))
+        .chain((0..=0xFFu8).map(|op| (vec![0xCB, op], decode::cb_table(op).1)));
+    for (bytes, op) in ops {
+        let (mnemonic, operands) = split(&op.to_string());
+        let (shape, _) = shape(&mnemonic, &operands, false)
+            .expect("The decoder should only print valid shapes");
+        let bytes = match op {
+            Op::STOP => vec![0x10, 0x00],
+            _ => bytes,
+        };
+        table.insert(shape, Entry { bytes, imm: imm_kind(&op) });
+    }
+    table
+}
+
+/// Split a line into an upper case mnemonic and its operands.
+fn split(line: &str) -> (String, Vec<String>) {
+    let line = line.trim();
+    let (mnemonic, rest) = match line.find(char::is_whitespace) {
+        Some(i) => (&line[..i], &line[i..]),
+        None => (line, ""),
+    };
+    let operands = rest.split(',')
+        .map(|o| o.trim().to_string())
+        .filter(|o| !o.is_empty())
+        .collect();
+    (mnemonic.to_uppercase(), operands)
+}
+
+const LITERALS: [&str; 23] = [
+    "A", "B", "C", "D", "E", "H", "L", "F", "AF", "BC", "DE", "HL", "SP", "PC",
+    "Z", "NZ", "NC",
+    "(BC)", "(DE)", "(HL)", "(HL+)", "(HL-)", "(FF00+C)",
+];
+
+/// Shape of an instruction and the expression of its immediate, if any.
+/// Immediates such as `AF` or `DE` read like registers, so `hex_names` reads them as values.
+fn shape(mnemonic: &str, operands: &[String], hex_names: bool)
+    -> Result<(String, Option<String>), String> {
+    let mut expr = None;
+    let mut parts = vec![];
+    for (i, operand) in operands.iter().enumerate() {
+        let upper = operand.to_uppercase();
+        let (part, value) = match mnemonic {
+            // The vector and bit number are part of the opcode.
+            "RST" | "BIT" | "RES" | "SET" if i == 0 =>
+                (eval(operand, 10, None)?.0.to_string(), None),
+            _ if LITERALS.contains(&upper.as_str()) &&
+                !(hex_names && upper.len() == 2 &&
+                  upper.chars().all(|c| c.is_ascii_hexdigit())) => (upper, None),
+            _ if upper.starts_with("(FF00+") && upper.ends_with(')') =>
+                ("(FF00+#)".to_string(), Some(&operand[6..operand.len() - 1])),
+            _ if upper.starts_with('(') && upper.ends_with(')') =>
+                ("(#)".to_string(), Some(&operand[1..operand.len() - 1])),
+            _ if upper.starts_with("SP+") => ("SP+#".to_string(), Some(&operand[3..])),
+            _ if upper.starts_with("SP-") => ("SP+#".to_string(), Some(&operand[2..])),
+            _ => ("#".to_string(), Some(&operand[..])),
+        };
+        if let Some(value) = value {
+            if expr.is_some() {
+                return Err(format!("More than one immediate in {} {}",
+                                   mnemonic, operands.join(", ")));
+            }
+            expr = Some(value.trim().to_string());
+        }
+        parts.push(part);
+    }
+    let shape = match parts.len() {
+        0 => mnemonic.to_string(),
+        _ => format!("{} {}", mnemonic, parts.join(", ")),
+    };
+    Ok((shape, expr))
+}
+
+/// Evaluate an expression, returning its value and if it used a label.
+/// Without labels, any label is taken as 0.
+fn eval(expr: &str, radix: u32, labels: Option<&HashMap<String, u16>>)
+    -> Result<(i64, bool), String> {
+    let mut total = 0i64;
+    let mut used_label = false;
+    let mut negative = false;
+    let mut term = String::new();
+    let mut terms = vec![];
+    for c in expr.chars().chain(Some('+')) {
+        match c {
+            '+' | '-' => {
+                let trimmed = term.trim().to_string();
+                if trimmed.is_empty() {
+                    // Unary sign
+                    if c == '-' { negative = !negative; }
+                }
+                else {
+                    terms.push((negative, trimmed));
+                    negative = c == '-';
+                }
+                term.clear();
+            }
+            _ => term.push(c),
+        }
+    }
+    if terms.is_empty() {
+        return Err(format!("Missing value in \"{}\"", expr));
+    }
+    for (negative, term) in terms {
+        let (value, label) = eval_term(&term, radix, labels)?;
+        used_label |= label;
+        total += if negative { -value } else { value };
+    }
+    Ok((total, used_label))
+}
+
+fn eval_term(term: &str, radix: u32, labels: Option<&HashMap<String, u16>>)
+    -> Result<(i64, bool), String> {
+    let number = |digits: &str, radix| i64::from_str_radix(digits, radix)
+        .map_err(|_| format!("Invalid number \"{}\"", term));
+    if let Some(digits) = term.strip_prefix('$') {
+        Ok((number(digits, 16)?, false))
+    }
+    else if term.starts_with("0x") || term.starts_with("0X") {
+        Ok((number(&term[2..], 16)?, false))
+    }
+    else if let Some(digits) = term.strip_prefix('%') {
+        Ok((number(digits, 2)?, false))
+    }
+    else if term.chars().all(|c| c.is_digit(radix)) {
+        Ok((number(term, radix)?, false))
+    }
+    else if is_label(term) {
+        match labels {
+            Some(labels) => match labels.get(term) {
+                Some(addr) => Ok((*addr as i64, true)),
+                None => Err(format!("Unknown label \"{}\"", term)),
+            },
+            None => Ok((0, true)),
+        }
+    }
+    else {
+        Err(format!("Invalid value \"{}\"", term))
+    }
+}
+
+fn is_label(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' || c == '.' =>
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.'),
+        _ => false,
+    }
+}
+
+fn in_range(value: i64, min: i64, max: i64) -> Result<i64, String> {
+    if value < min || value > max {
+        Err(format!("Value {} does not fit in {}..{}", value, min, max))
+    }
+    else { Ok(value) }
+}
+
+enum Item<'a> {
+    Instruction(&'a Entry, Option<String>),
+    Data(Vec<String>),
+    Org(String),
+}
+
+/// Assemble source into bytes that start at `origin`.
+pub fn assemble(source: &str, origin: u16) -> Result<Vec<u8>, AsmError> {
+    let table = table();
+    let mut labels = HashMap::new();
+    let mut items = vec![];
+
+    // First pass: find the size of everything to place the labels.
+    let mut addr = origin;
+    for (n, line) in source.lines().enumerate() {
+        let error = |message| AsmError { line: n + 1, message };
+        let mut line = match line.find(';') {
+            Some(i) => &line[..i],
+            None => line,
+        }.trim();
+        while let Some(i) = line.find(':') {
+            let label = line[..i].trim();
+            if !is_label(label) {
+                return Err(error(format!("Invalid label \"{}\"", label)));
+            }
+            if labels.insert(label.to_string(), addr).is_some() {
+                return Err(error(format!("Label \"{}\" defined twice", label)));
+            }
+            line = line[i + 1..].trim();
+        }
+        if line.is_empty() {
+            continue;
+        }
+
+        let (mnemonic, operands) = split(line);
+        let item = match mnemonic.as_str() {
+            "DB" => {
+                addr = addr.wrapping_add(operands.len() as u16);
+                Item::Data(operands)
+            }
+            "ORG" => {
+                let expr = operands.join(",");
+                addr = eval(&expr, 16, Some(&labels)).map_err(&error)?.0 as u16;
+                Item::Org(expr)
+            }
+            _ => {
+                // Also allow the accumulator to be written out for arithmetic.
+                let alu = ["ADD", "ADC", "SUB", "SBC", "AND", "OR", "XOR", "CP"];
+                let operands = match operands.len() {
+                    2 if alu.contains(&mnemonic.as_str()) &&
+                        operands[0].to_uppercase() == "A" &&
+                        !table.contains_key(&shape(&mnemonic, &operands, false)
+                                            .map_err(&error)?.0) => operands[1..].to_vec(),
+                    _ => operands,
+                };
+                let mut found = None;
+                for hex_names in [false, true].iter() {
+                    let (shape, expr) = shape(&mnemonic, &operands, *hex_names)
+                        .map_err(&error)?;
+                    if let Some(entry) = table.get(&shape) {
+                        found = Some((entry, expr));
+                        break;
+                    }
+                }
+                let (entry, expr) = match found {
+                    Some(found) => found,
+                    None => return Err(error(format!("Unknown instruction \"{}\"", line))),
+                };
+                addr = addr.wrapping_add(entry.bytes.len() as u16 + entry.imm.size());
+                Item::Instruction(entry, expr)
+            }
+        };
+        items.push((n + 1, item));
+    }
+
+    // Second pass: encode with every label known.  Addresses count from the last ORG, and
+    // where its output starts.
+    let mut out = vec![];
+    let (mut base, mut start) = (origin, 0);
+    let mut addr = origin;
+    for (i, (line, item)) in items.into_iter().enumerate() {
+        let error = |message| AsmError { line, message };
+        match item {
+            Item::Data(values) => {
+                for value in values {
+                    let (value, _) = eval(&value, 16, Some(&labels)).map_err(&error)?;
+                    out.push(in_range(value, -128, 255).map_err(&error)? as u8);
+                }
+            }
+            Item::Org(expr) => {
+                let target = eval(&expr, 16, Some(&labels)).map_err(&error)?.0 as u16;
+                if i > 0 {
+                    if target < addr {
+                        return Err(error(format!("ORG {:04X} is before {:04X}", target, addr)));
+                    }
+                    out.extend(vec![0; (target - addr) as usize]);
+                }
+                base = target;
+                start = out.len();
+            }
+            Item::Instruction(entry, expr) => {
+                out.extend(&entry.bytes);
+                let size = entry.bytes.len() as u16 + entry.imm.size();
+                if let Some(expr) = expr {
+                    let (value, used_label) = eval(&expr, entry.imm.radix(), Some(&labels))
+                        .map_err(&error)?;
+                    match entry.imm {
+                        Imm::None => unreachable!("Shapes with a value always have an immediate"),
+                        Imm::U8 => out.push(in_range(value, -128, 255).map_err(&error)? as u8),
+                        Imm::U16 => {
+                            let value = in_range(value, -0x8000, 0xFFFF).map_err(&error)? as u16;
+                            out.push(value as u8);
+                            out.push((value >> 8) as u8);
+                        }
+                        Imm::S8 => out.push(in_range(value, -128, 127).map_err(&error)? as u8),
+                        Imm::Rel8 => {
+                            let offset = match used_label {
+                                true => value - addr.wrapping_add(size) as i64,
+                                false => value,
+                            };
+                            out.push(in_range(offset, -128, 127).map_err(&error)? as u8);
+                        }
+                    }
+                }
+            }
+        }
+        addr = base.wrapping_add((out.len() - start) as u16);
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use gb::asm::{assemble, table};
+    use gb::decode;
+
+    fn round_trip(bytes: [u8; 3]) {
+        let (_, op, size, _) = match bytes[0] {
+            0xCB => decode::cb_table(bytes[1]),
+            _ => decode::decode_internal(bytes[0], bytes[1], bytes[2]),
+        };
+        let size = size as usize;
+        let text = op.to_string();
+        match assemble(&text, 0x0150) {
+            Ok(out) => assert_eq!(&out[..], &bytes[..size], "\"{}\" assembled wrong", text),
+            Err(e) => panic!("\"{}\" failed to assemble: {}", text, e),
+        }
+    }
+
+    #[test]
+    fn shapes_are_unique() {
+        assert_eq!(table().len(), 256 - 12 + 256);
+    }
+
+    #[test]
+    fn round_trips_every_opcode() {
+        for op in (0..=0xFFu8).filter(|op| !decode::is_illegal(*op)) {
+            for op2 in 0..=0xFFu8 {
+                // STOP ignores its second byte, and is assembled with a zero.
+                if op == 0x10 && op2 != 0 { continue }
+                for op3 in [0x00u8, 0x01, 0x7F, 0x80, 0xA5, 0xFF].iter() {
+                    round_trip([op, op2, *op3]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn labels_and_expressions() {
+        let source = "
+            start:  LD A, $10 + 2   ; comment
+                    LD (data+1), A
+            loop:   DEC A
+                    JR NZ, loop
+                    JP start
+                    add a, b
+            data:   DB 01, %10, -1
+        ";
+        assert_eq!(assemble(source, 0x0150).unwrap(), vec![
+            0x3E, 0x12,
+            0xEA, 0x5D, 0x01,
+            0x3D,
+            0x20, 0xFD,
+            0xC3, 0x50, 0x01,
+            0x80,
+            0x01, 0x02, 0xFF,
+        ]);
+    }
+
+    #[test]
+    fn a_leading_org_sets_the_address() {
+        let source = "
+                    ORG $150
+            loop:   DEC A
+                    JR NZ, loop
+                    JP loop
+                    ORG $158
+                    JR loop
+        ";
+        assert_eq!(assemble(source, 0).unwrap(), vec![
+            0x3D,
+            0x20, 0xFD,
+            0xC3, 0x50, 0x01,
+            0x00, 0x00,
+            0x18, 0xF6,
+        ]);
+        let err = assemble("ORG $150
+NOP
+NOP
+ORG $151", 0).unwrap_err();
+        assert_eq!(err.line, 4);
+    }
+
+    #[test]
+    fn errors_report_the_line() {
+        let err = assemble("NOP\nLD A, nowhere", 0).unwrap_err();
+        assert_eq!(err.line, 2);
+        assert!(assemble("JR 200", 0).is_err());
+        assert!(assemble("LD Q, A", 0).is_err());
+    }
+}
@@ -0,0 +1,76 @@
+//! Headless frames per second, for seeing what changes to the cpu and memory do to speed.
+//!
+//! Runs the rom in FEGABO_BENCH_ROM, or a small built in one that loops over ram and calls
+//! into a switched bank, for FEGABO_BENCH_FRAMES frames (600 by default).
+//! It is ignored normally, run it with
+//! `cargo test --release bench -- --ignored --nocapture`.
+
+use std::env;
+use std::fs;
+use std::time::Instant;
+
+use gb::asm::assemble;
+use gb::Gb;
+
+const OUTPUT: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/target/bench");
+const REAL_FPS: f64 = 59.73;
+
+/// A 64K MBC1 rom that keeps the cpu busy with the lcd on.
+fn built_in_rom() -> String {
+    let mut rom = vec![0; 0x10000];
+    let mut place = |source: &str, addr: u16, offset: usize| {
+        let bytes = assemble(source, addr).unwrap();
+        rom[offset..offset + bytes.len()].copy_from_slice(&bytes);
+    };
+    place("JP 0150", 0x0100, 0x0100);
+    place("
+                LD A, 91
+                LD (FF00+40), A
+        loop:   LD HL, C000
+                LD B, 40
+        fill:   LD A, B
+                LD (HL+), A
+                XOR (HL)
+                ADD A, 3
+                DEC B
+                JR NZ, fill
+                LD A, 2
+                LD (2000), A
+                CALL 4000
+                JP loop
+    ", 0x0150, 0x0150);
+    place("
+                LD C, 10
+        spin:   DEC C
+                JR NZ, spin
+                RET
+    ", 0x4000, 0x8000);
+    rom[0x147] = 0x01; // MBC1
+
+    fs::create_dir_all(OUTPUT).unwrap();
+    let path = format!("{}/bench.gb", OUTPUT);
+    fs::write(&path, rom).unwrap();
+    path
+}
+
+#[test]
+#[ignore]
+fn frames_per_second() {
+    let rom = env::var("FEGABO_BENCH_ROM").unwrap_or_else(|_| built_in_rom());
+    let frames = env::var("FEGABO_BENCH_FRAMES").ok()
+        .and_then(|f| f.parse().ok())
+        .unwrap_or(600);
+
+    let mut gb = Gb::headless(rom.clone());
+    let start = Instant::now();
+    let mut count = 0;
+    while count < frames {
+        if gb.step() {
+            count += 1;
+        }
+    }
+    let seconds = start.elapsed().as_secs_f64();
+    let fps = frames as f64 / seconds;
+    println!("{}: {} frames in {:.2}s, {:.0} fps, {:.1}x real time",
+             rom, frames, seconds, fps, fps / REAL_FPS);
+}
@@ -1,6 +1,8 @@
 use super::decode;
 use super::decode::{ByteR, Flag, WordR};
 use super::mem;
+use super::state::{Snapshot, State};
+use super::GbKind;
 
 #[derive(Debug)]
 pub struct Cpu {
@@ -16,6 +18,9 @@ pub struct Cpu {
     pc: u16,
     print: bool,
     state: CPUState,
+    ime_delay: u8, // EI takes effect after the next instruction.
+    halt_bug: bool, // The next opcode byte is read twice.
+    cache: decode::DecodeCache,
 }
 
 #[derive(Debug)]
@@ -23,6 +28,8 @@ enum CPUState {
     Running,
     Halt,
     Stop,
+    /// Hung on an illegal opcode at this address, nothing but a reset gets out.
+    Locked(u16, u8),
 }
 
 impl Cpu {
@@ -40,25 +47,75 @@ impl Cpu {
             pc: 0,
             print: false,
             state: CPUState::Running,
+            ime_delay: 0,
+            halt_bug: false,
+            cache: decode::DecodeCache::new(),
         }
     }
 
-    pub fn new_after_boot() -> Self {
+    /// Games tell a Game Boy Color by the 11 it leaves in A.
+    pub fn new_after_boot(kind: GbKind) -> Self {
         let mut cpu = Cpu::new();
-        cpu.write_16(WordR::AF, 0x01B0);
-        cpu.write_16(WordR::BC, 0x0013);
-        cpu.write_16(WordR::DE, 0x00D8);
-        cpu.write_16(WordR::HL, 0x014D);
+        match kind {
+            GbKind::GB => {
+                cpu.write_16(WordR::AF, 0x01B0);
+                cpu.write_16(WordR::BC, 0x0013);
+                cpu.write_16(WordR::DE, 0x00D8);
+                cpu.write_16(WordR::HL, 0x014D);
+            }
+            GbKind::SGB => {
+                cpu.write_16(WordR::AF, 0x0100);
+                cpu.write_16(WordR::BC, 0x0014);
+                cpu.write_16(WordR::DE, 0x0000);
+                cpu.write_16(WordR::HL, 0xC060);
+            }
+            GbKind::GBC => {
+                cpu.write_16(WordR::AF, 0x1180);
+                cpu.write_16(WordR::BC, 0x0000);
+                cpu.write_16(WordR::DE, 0xFF56);
+                cpu.write_16(WordR::HL, 0x000D);
+            }
+        }
         cpu.sp = 0xFFFE;
         cpu.pc = 0x0100;
         cpu
     }
 
-    pub fn handle_interupt(&mut self, location: u16, mem: &mut mem::Mem) {
-        self.push(WordR::PC, mem);
+    /// Registers B, C, D, E, H and L, where test roms leave their results.
+    #[cfg(test)]
+    pub fn bcdehl(&self) -> [u8; 6] {
+        [self.b, self.c, self.d, self.e, self.h, self.l]
+    }
+
+    #[cfg(test)]
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    /// Address and opcode of the illegal instruction the cpu is stuck on.
+    pub fn lockup(&self) -> Option<(u16, u8)> {
+        match self.state {
+            CPUState::Locked(pc, op) => Some((pc, op)),
+            _ => None,
+        }
+    }
+
+    /// Dispatch takes 5 M-cycles: two idle, two pushing pc and one jumping.
+    /// Which interupt is taken is decided between the pushes, so a push that
+    /// clears IE can cancel it, leaving pc at 0.
+    pub fn handle_interupt(&mut self, mem: &mut mem::Mem) {
         mem.set_ime(false);
-        self.pc = location;
+        self.ime_delay = 0;
         self.state = CPUState::Running;
+        mem.tick();
+        mem.tick();
+        self.sp = self.sp.wrapping_sub(1);
+        mem.write_cycle(self.sp, (self.pc >> 8) as u8);
+        let location = mem.acknowledge_interupt();
+        self.sp = self.sp.wrapping_sub(1);
+        mem.write_cycle(self.sp, self.pc as u8);
+        self.pc = location.unwrap_or(0x0000);
+        mem.tick();
     }
 
     fn execute_op(&mut self, opcode: decode::Op, mem: &mut mem::Mem)  -> usize{
@@ -66,8 +123,19 @@ impl Cpu {
         let mut overtime = 0;
         match opcode {
             NOP => {}
-            HALT => self.state = CPUState::Halt,
-            STOP => self.state = CPUState::Stop,
+            HALT => {
+                // With an interupt already waiting and IME off, HALT does
+                // not halt, and fails to move pc past the next byte.
+                mem.catch_up();
+                if !mem.ime() && mem.interupt_pending() { self.halt_bug = true }
+                else { self.state = CPUState::Halt }
+            }
+            Illegal(op) => self.state = CPUState::Locked(self.pc.wrapping_sub(1), op),
+            STOP => {
+                if mem.stop() {
+                    self.state = CPUState::Stop;
+                }
+            }
             DAA => self.daa(),
 
             CPL => {
@@ -117,7 +185,8 @@ impl Cpu {
             LDMem(o1, o2) => {
                 let addr = self.read_16(o1);
                 let data = self.read_16(o2);
-                mem.write_16(addr, data);
+                mem.write_cycle(addr, data as u8);
+                mem.write_cycle(addr.wrapping_add(1), (data >> 8) as u8);
             }
             SPLD(o) => {
                 let data = self.spadd(o);
@@ -199,33 +268,53 @@ impl Cpu {
             RES(n, o) => self.res(n, o, mem),
             SET(n, o) => self.set(n, o, mem),
 
-            DI => mem.set_ime(false),
-            EI => mem.set_ime(true),
+            DI => {
+                mem.set_ime(false);
+                self.ime_delay = 0;
+            }
+            EI => if !mem.ime() && self.ime_delay == 0 { self.ime_delay = 2 },
         };
         overtime
     }
 
+    /// Run an instruction, or one M-cycle of waiting, ticking the rest of
+    /// the system as it goes.  Returns the cycles taken.
     pub fn cycle(&mut self, mem: &mut mem::Mem) -> usize {
         match self.state {
             CPUState::Running => self.cycle_running(mem),
             CPUState::Stop => {
-                if mem.load_8(0xFF00) & 0x0F > 0 {
+                if mem.wake() {
                     self.state = CPUState::Running;
                 };
-                4
+                mem.tick();
+                mem.take_cycles()
+            }
+            CPUState::Locked(_, _) => {
+                mem.tick();
+                mem.take_cycles()
             }
             CPUState::Halt => {
-                if mem.load_8(0xFFFF) & mem.load_8(0xFF0F) > 0 {
+                // Any enabled interupt wakes, IME only decides if it is taken.
+                if mem.interupt_pending() {
                     self.state = CPUState::Running;
                 }
-                4
+                mem.tick();
+                mem.take_cycles()
             },
         }
     }
 
     pub fn cycle_running(&mut self, mem: &mut mem::Mem) -> usize {
+        mem.take_cycles();
         // Load opcode
-        let (instruction, opcode, op_size, op_time) = decode::decode(self.pc, mem);
+        let (instruction, opcode, op_size, op_time) = if self.halt_bug {
+            let op = mem.load_8(self.pc);
+            decode::decode_internal(op, op, mem.load_8(self.pc.wrapping_add(1)))
+        }
+        else {
+            let rom_offset = mem.rom_offset(self.pc);
+            self.cache.decode(self.pc, rom_offset, mem)
+        };
         // let mut flag = false;
         if self.print {
             println!("CPU: {:0X?}", self);
@@ -236,14 +325,31 @@ impl Cpu {
             // self.print = true;
         }
 
+        // Each byte of the instruction takes an M-cycle to fetch, the halt bug fetching the
+        // first one twice.
+        for i in 0..op_size {
+            mem.fetch_cycle(self.pc.wrapping_add(i - (self.halt_bug && i > 0) as u16));
+        }
         //Increment PC
-        self.pc += op_size;
+        self.pc = self.pc.wrapping_add(op_size - self.halt_bug as u16);
+        self.halt_bug = false;
 
-        // Execute
-        op_time + self.execute_op(opcode, mem)
+        // Execute, memory accesses tick as they happen.
+        let time = op_time + self.execute_op(opcode, mem);
+
+        // Whatever is left was spent inside the cpu.
+        while mem.cycles() < time {
+            mem.idle_cycle();
+        }
+        mem.catch_up();
+        if self.ime_delay > 0 {
+            self.ime_delay -= 1;
+            if self.ime_delay == 0 { mem.set_ime(true) }
+        }
+        mem.take_cycles()
     }
 
-    fn read_8(&mut self, reg: ByteR, mem: &mem::Mem) -> u8 {
+    fn read_8(&mut self, reg: ByteR, mem: &mut mem::Mem) -> u8 {
         match reg {
             ByteR::A => self.a,
             ByteR::B => self.b,
@@ -254,7 +360,10 @@ impl Cpu {
             ByteR::L => self.l,
             ByteR::F => self.f,
             ByteR::IMM(data) => data,
-            ByteR::Mem(addr) => mem.load_8(self.read_16(addr)),
+            ByteR::Mem(addr) => {
+                let addr = self.read_16(addr);
+                mem.read_cycle(addr)
+            }
         }
     }
 
@@ -269,7 +378,10 @@ impl Cpu {
             ByteR::L => self.l = data,
             ByteR::F => self.f = data & 0xF0,
             ByteR::IMM(_) => panic!("You cannot write to a immediate value"),
-            ByteR::Mem(addr) => mem.write_8(self.read_16(addr), data),
+            ByteR::Mem(addr) => {
+                let addr = self.read_16(addr);
+                mem.write_cycle(addr, data)
+            }
         }
     }
 
@@ -293,8 +405,8 @@ impl Cpu {
                 let data = ((high as u16) << 8) | low as u16;
                 // Post read operation for increment and decrement
                 match reg {
-                    WordR::HLI => self.write_16(reg, data + 1),
-                    WordR::HLD => self.write_16(reg, data - 1),
+                    WordR::HLI => self.write_16(reg, data.wrapping_add(1)),
+                    WordR::HLD => self.write_16(reg, data.wrapping_sub(1)),
                     _ => {}
                 }
                 data
@@ -358,6 +470,9 @@ impl Cpu {
     }
 
     fn ret(&mut self, fl: decode::OptFlag, mem: &mut mem::Mem) -> usize {
+        if fl.0.is_some() {
+            mem.tick(); // Checking the condition takes a cycle before the pop.
+        }
         if self.flag_condition(fl) {
             self.pc = self.pop(mem);
             12 // If we return, we take 12 more cycles.
@@ -376,14 +491,20 @@ impl Cpu {
 
     fn push(&mut self, reg: WordR, mem: &mut mem::Mem) {
         let data = self.read_16(reg);
-        self.sp = self.sp.wrapping_sub(2);
-        mem.write_16(self.sp, data);
+        // An idle cycle, then the high byte goes first.
+        mem.tick();
+        self.sp = self.sp.wrapping_sub(1);
+        mem.write_cycle(self.sp, (data >> 8) as u8);
+        self.sp = self.sp.wrapping_sub(1);
+        mem.write_cycle(self.sp, data as u8);
     }
 
     fn pop(&mut self, mem: &mut mem::Mem) -> u16 {
-        let data = mem.load_16(self.sp);
-        self.sp = self.sp.wrapping_add(2);
-        data
+        let low = mem.read_cycle(self.sp) as u16;
+        self.sp = self.sp.wrapping_add(1);
+        let high = mem.read_cycle(self.sp) as u16;
+        self.sp = self.sp.wrapping_add(1);
+        (high << 8) | low
     }
 
     fn and(&mut self, reg: ByteR, mem: &mut mem::Mem) {
@@ -433,9 +554,10 @@ impl Cpu {
             false => self.a.overflowing_add(reg_c),
         };
 
+        // A borrow into the low nibble, or a carry out of it.
         let h = match sub {
-            true => (data & 0x0F) + (reg & 0x0F) + cin > 0x0F,
-            false => (data & 0x0F).wrapping_sub((reg & 0x0F) + cin) > 0x0F,
+            true => (self.a & 0x0F) < (reg & 0x0F) + cin,
+            false => (self.a & 0x0F) + (reg & 0x0F) + cin > 0x0F,
         };
         let zero = data == 0;
         self.set_flag(Flag::Z, zero);
@@ -632,3 +754,196 @@ impl Cpu {
         }
     }
 }
+
+impl State for Cpu {
+    fn state(&mut self, snapshot: &mut Snapshot) {
+        for register in [&mut self.a, &mut self.b, &mut self.c, &mut self.d, &mut self.e,
+                         &mut self.h, &mut self.l, &mut self.f].iter_mut() {
+            snapshot.u8(register);
+        }
+        snapshot.u16(&mut self.sp);
+        snapshot.u16(&mut self.pc);
+        let (mut tag, mut pc, mut opcode) = match self.state {
+            CPUState::Running => (0, 0, 0),
+            CPUState::Halt => (1, 0, 0),
+            CPUState::Stop => (2, 0, 0),
+            CPUState::Locked(pc, opcode) => (3, pc, opcode),
+        };
+        snapshot.u8(&mut tag);
+        snapshot.u16(&mut pc);
+        snapshot.u8(&mut opcode);
+        self.state = match tag {
+            0 => CPUState::Running,
+            1 => CPUState::Halt,
+            2 => CPUState::Stop,
+            _ => CPUState::Locked(pc, opcode),
+        };
+        snapshot.u8(&mut self.ime_delay);
+        snapshot.bool(&mut self.halt_bug);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+    use std::path::PathBuf;
+    use serde_json::{self, Value};
+
+    use gb::cpu::Cpu;
+    use gb::harness;
+    use gb::mem::{Access, Mem};
+
+    /// Single step tests in the format of https://github.com/SingleStepTests/sm83
+    /// One file per opcode, each a list of cases with an initial and final state.
+    const VECTORS: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/sm83");
+    /// The public tests themselves, when fetched with tests/sm83/fetch.sh.
+    const PUBLIC: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/sm83/public");
+
+    fn json_files(dir: &str) -> Vec<PathBuf> {
+        match fs::read_dir(dir) {
+            Ok(dir) => dir.filter_map(|e| e.ok())
+                .map(|e| e.path())
+                .filter(|p| p.extension().is_some_and(|ext| ext == "json"))
+                .collect(),
+            Err(_) => vec![],
+        }
+    }
+
+    fn field(state: &Value, name: &str) -> u16 {
+        state[name].as_u64().unwrap_or(0) as u16
+    }
+
+    fn flags(f: u8) -> String {
+        [(0x80, 'Z'), (0x40, 'N'), (0x20, 'H'), (0x10, 'C')].iter()
+            .map(|(mask, name)| if f & mask > 0 { *name } else { '-' })
+            .collect()
+    }
+
+    fn setup(state: &Value) -> (Cpu, Mem) {
+        let mut cpu = Cpu::new();
+        let mut mem = Mem::new_flat();
+        cpu.a = field(state, "a") as u8;
+        cpu.b = field(state, "b") as u8;
+        cpu.c = field(state, "c") as u8;
+        cpu.d = field(state, "d") as u8;
+        cpu.e = field(state, "e") as u8;
+        cpu.f = field(state, "f") as u8;
+        cpu.h = field(state, "h") as u8;
+        cpu.l = field(state, "l") as u8;
+        cpu.sp = field(state, "sp");
+        cpu.pc = field(state, "pc");
+        mem.set_ime(field(state, "ime") > 0);
+        mem.record_bus();
+        if let Some(ram) = state["ram"].as_array() {
+            for entry in ram {
+                mem.write_8(entry[0].as_u64().unwrap() as u16, entry[1].as_u64().unwrap() as u8);
+            }
+        }
+        (cpu, mem)
+    }
+
+    /// Every difference between the cpu and the expected state.
+    fn differences(cpu: &Cpu, mem: &Mem, state: &Value) -> Vec<String> {
+        let mut diffs = vec![];
+        let registers = [("a", cpu.a as u16), ("b", cpu.b as u16), ("c", cpu.c as u16),
+                         ("d", cpu.d as u16), ("e", cpu.e as u16), ("h", cpu.h as u16),
+                         ("l", cpu.l as u16), ("sp", cpu.sp), ("pc", cpu.pc)];
+        for (name, value) in registers.iter() {
+            let expected = field(state, name);
+            if expected != *value {
+                diffs.push(format!("{}: expected {:02X} got {:02X}", name, expected, value));
+            }
+        }
+        let f = field(state, "f") as u8;
+        if f != cpu.f {
+            diffs.push(format!("f: expected {} got {}", flags(f), flags(cpu.f)));
+        }
+        // The vectors set IME on EI, before it takes effect.
+        let ime = mem.ime() || cpu.ime_delay > 0;
+        if !state["ime"].is_null() && (field(state, "ime") > 0) != ime {
+            diffs.push(format!("ime: expected {} got {}", field(state, "ime"), ime));
+        }
+        if let Some(ram) = state["ram"].as_array() {
+            for entry in ram {
+                let addr = entry[0].as_u64().unwrap() as u16;
+                let expected = entry[1].as_u64().unwrap() as u8;
+                if mem.load_8(addr) != expected {
+                    diffs.push(format!("({:04X}): expected {:02X} got {:02X}",
+                                       addr, expected, mem.load_8(addr)));
+                }
+            }
+        }
+        diffs
+    }
+
+    /// The first M-cycle where the reads and writes differ from the expected `cycles`.  Idle
+    /// cycles only have to be idle, whatever the vectors say was on the address bus.
+    fn bus_difference(mem: &Mem, time: usize, cycles: &[Value]) -> Option<String> {
+        let mut actual = vec![None; time / 4];
+        for &(cycle, access) in mem.bus() {
+            if cycle < actual.len() {
+                actual[cycle] = Some(access);
+            }
+        }
+        let expected: Vec<_> = cycles.iter()
+            .map(|cycle| {
+                let addr = cycle[0].as_u64().unwrap_or(0) as u16;
+                let data = cycle[1].as_u64().unwrap_or(0) as u8;
+                match cycle[2].as_str().unwrap_or("") {
+                    "r-m" => Some(Access::Read(addr, data)),
+                    "-wm" => Some(Access::Write(addr, data)),
+                    _ => None,
+                }
+            })
+            .collect();
+        if actual.len() != expected.len() {
+            return Some(format!("cycles: expected {} got {}", expected.len(), actual.len()));
+        }
+        expected.iter().zip(actual.iter()).position(|(e, a)| e != a)
+            .map(|i| format!("cycle {}: expected {:X?} got {:X?}", i, expected[i], actual[i]))
+    }
+
+    #[test]
+    fn single_step_vectors() {
+        let mut files = json_files(VECTORS);
+        harness::require(&files, VECTORS);
+        files.extend(json_files(PUBLIC));
+        files.sort();
+
+        // Each opcode file gives its name, the number of failing cases and the first failure.
+        let results = harness::run_all(files.clone(), |path| {
+            let cases: Value = serde_json::from_reader(File::open(&path).unwrap())
+                .unwrap_or_else(|e| panic!("Could not parse {}: {}", path.display(), e));
+            let mut failed = 0;
+            let mut first = None;
+            for case in cases.as_array().unwrap() {
+                let (mut cpu, mut mem) = setup(&case["initial"]);
+                let time = cpu.cycle_running(&mut mem);
+                let mut diffs = differences(&cpu, &mem, &case["final"]);
+                if let Some(cycles) = case["cycles"].as_array() {
+                    diffs.extend(bus_difference(&mem, time, cycles));
+                }
+                if !diffs.is_empty() {
+                    if first.is_none() {
+                        first = Some(format!("{}: {}", case["name"], diffs.join(", ")));
+                    }
+                    failed += 1;
+                }
+            }
+            first.map(|first| format!("{:5} failed, first {}", failed, first))
+        });
+
+        let failures: Vec<_> = files.iter().zip(results)
+            .filter_map(|(path, result)| {
+                let name = path.strip_prefix(VECTORS).unwrap().with_extension("");
+                let name = name.to_string_lossy();
+                match result {
+                    Some(None) => None,
+                    Some(Some(failed)) => Some(format!("{:>6}: {}", name, failed)),
+                    None => Some(format!("{:>6}: crashed", name)),
+                }
+            })
+            .collect();
+        harness::report(&failures, "opcodes do not match the test vectors");
+    }
+}
@@ -67,7 +67,7 @@ impl fmt::Display for WordR {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Flag {
     Z,
     N,
@@ -75,7 +75,7 @@ pub enum Flag {
     C,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct OptFlag(pub Option<(Flag, bool)>);
 
 const OF_Z: OptFlag = OptFlag(Some((Flag::Z, true)));
@@ -107,7 +107,7 @@ impl fmt::Display for Flag {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum OpCode {
     One(u8),
     Two(u8, u8),
@@ -125,11 +125,13 @@ impl fmt::Display for OpCode {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Op {
     NOP,
     STOP,
     HALT,
+    /// Locks up the cpu.
+    Illegal(u8),
     DAA,
     CPL,
     SCF,
@@ -203,6 +205,7 @@ impl fmt::Display for Op {
             NOP => write!(f, "NOP"),
             STOP => write!(f, "STOP"),
             HALT => write!(f, "HALT"),
+            Illegal(op) => write!(f, "ILLEGAL {:02X}", op),
             DAA => write!(f, "DAA"),
             CPL => write!(f, "CPL"),
             SCF => write!(f, "SCF"),
@@ -299,23 +302,63 @@ pub fn decode(addr: u16, mem: &mut Mem) -> (OpCode, Op, u16, usize) {
     let op = mem.load_8(addr);
     // Figure out a way to avoid extra loads that will be pointless for
     // instructions that are not 3 bytes long.
-    let op2 = mem.load_8(addr + 1);
-    let op3 = mem.load_8(addr + 2);
+    let op2 = mem.load_8(addr.wrapping_add(1));
+    let op3 = mem.load_8(addr.wrapping_add(2));
     decode_internal(op, op2, op3)
 }
 
-pub fn disasemble(mem: Vec<u8>) {
-    let mut pc = 0;
-    while pc < mem.len() - 2 {
-        let (opcode, instruction, increment, _time) = decode_internal(mem[pc],
-                                                                     mem[pc+1],
-                                                                     mem[pc+2]);
-        println!("0x{:04X}: {}    {}", pc, opcode, instruction);
-        pc += increment as usize;
+pub type Decoded = (OpCode, Op, u16, usize);
+
+const CACHE_PAGE: usize = 0x100;
+
+/// Decoded instructions by rom offset, which covers both the bank and the address.
+/// Rom never changes, so nothing is invalidated: a bank switch just makes the
+/// cpu look at other offsets.  Code in ram is never cached.
+/// Pages are only allocated once code in them has run.
+pub struct DecodeCache {
+    pages: Vec<Option<Box<[Option<Decoded>]>>>,
+}
+
+impl DecodeCache {
+    pub fn new() -> Self {
+        DecodeCache { pages: Vec::new() }
+    }
+
+    /// Decode at addr, using the cache when `rom_offset` says where in rom it is.
+    pub fn decode(&mut self, addr: u16, rom_offset: Option<usize>, mem: &mut Mem) -> Decoded {
+        // Instructions that run into the next bank region are left alone.
+        let offset = match rom_offset {
+            Some(offset) if addr & 0x3FFF <= 0x3FFD => offset,
+            _ => return decode(addr, mem),
+        };
+        let (page, index) = (offset / CACHE_PAGE, offset % CACHE_PAGE);
+        if page >= self.pages.len() {
+            self.pages.resize_with(page + 1, || None);
+        }
+        let page = self.pages[page]
+            .get_or_insert_with(|| vec![None; CACHE_PAGE].into_boxed_slice());
+        if let Some(ref decoded) = page[index] {
+            return decoded.clone();
+        }
+        let decoded = decode(addr, mem);
+        page[index] = Some(decoded.clone());
+        decoded
     }
 }
 
-fn decode_internal(op: u8, op2: u8, op3: u8) -> (OpCode, Op, u16, usize) {
+impl fmt::Debug for DecodeCache {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let pages = self.pages.iter().filter(|p| p.is_some()).count();
+        write!(f, "DecodeCache {{ pages: {} }}", pages)
+    }
+}
+
+// Opcodes with no instruction behind them.
+pub fn is_illegal(op: u8) -> bool {
+    matches!(decode_internal(op, 0, 0).1, Op::Illegal(_))
+}
+
+pub fn decode_internal(op: u8, op2: u8, op3: u8) -> (OpCode, Op, u16, usize) {
     use self::Op::*;
     use self::ByteR::*;
     use self::WordR::*;
@@ -337,7 +380,7 @@ fn decode_internal(op: u8, op2: u8, op3: u8) -> (OpCode, Op, u16, usize) {
         0x0E => op!(op, op2, LD8(C, imm8!(op2)), 8),
         0x0F => op!(op, RRCA, 4),
 
-        0x10 => op!(op, STOP, 4),
+        0x10 => op!(op, op2, STOP, 4), // The second byte is skipped.
         0x11 => op!(op, op2, op3, LD16(DE, imm16!(op2, op3)), 12),
         0x12 => op!(op, LD8(Mem(DE), A), 8),
         0x13 => op!(op, INC16(DE), 8),
@@ -544,7 +587,7 @@ fn decode_internal(op: u8, op2: u8, op3: u8) -> (OpCode, Op, u16, usize) {
         0xD0 => op!(op, RET(OF_NC), 8),
         0xD1 => op!(op, POP(DE), 12),
         0xD2 => op!(op, op2, op3, JP(OF_NC, imm16!(op2, op3)), 12),
-        // 0xD3 => No instruction,
+        0xD3 => op!(op, Illegal(op), 4), // No instruction
         0xD4 => op!(op, op2, op3, CALL(OF_NC, imm16!(op2, op3)), 12),
         0xD5 => op!(op, PUSH(DE), 16),
         0xD6 => op!(op, op2, SUB(imm8!(op2)), 8),
@@ -552,26 +595,26 @@ fn decode_internal(op: u8, op2: u8, op3: u8) -> (OpCode, Op, u16, usize) {
         0xD8 => op!(op, RET(OF_C), 8),
         0xD9 => op!(op, RETI, 16),
         0xDA => op!(op, op2, op3, JP(OF_C, imm16!(op2, op3)), 12),
-        // 0xDB => No instruction,
+        0xDB => op!(op, Illegal(op), 4), // No instruction
         0xDC => op!(op, op2, op3, CALL(OF_C, imm16!(op2, op3)), 12),
-        // 0xDD => No instruction,
+        0xDD => op!(op, Illegal(op), 4), // No instruction
         0xDE => op!(op, op2, SBC(imm8!(op2)), 8),
         0xDF => op!(op, RST(0x18), 16),
 
         0xE0 => op!(op, op2, LD8(Mem(High(op2)), A), 12),
         0xE1 => op!(op, POP(HL), 12),
         0xE2 => op!(op, LD8(Mem(HighC), A), 8),
-        // 0xE3 => No instruction,
-        // 0xE4 => No instruction,
+        0xE3 => op!(op, Illegal(op), 4), // No instruction
+        0xE4 => op!(op, Illegal(op), 4), // No instruction
         0xE5 => op!(op, PUSH(HL), 16),
         0xE6 => op!(op, op2, AND(imm8!(op2)), 8),
         0xE7 => op!(op, RST(0x20), 16),
         0xE8 => op!(op, op2, SPADD(op2 as i8), 16),
         0xE9 => op!(op, JP(OF_NONE, HL), 0),
         0xEA => op!(op, op2, op3, LD8(Mem(imm16!(op2, op3)), A), 16),
-        // 0xEB => No instruction,
-        // 0xEC => No instruction,
-        // 0xED => No instruction,
+        0xEB => op!(op, Illegal(op), 4), // No instruction
+        0xEC => op!(op, Illegal(op), 4), // No instruction
+        0xED => op!(op, Illegal(op), 4), // No instruction
         0xEE => op!(op, op2, XOR(imm8!(op2)), 8),
         0xEF => op!(op, RST(0x28), 16),
 
@@ -579,7 +622,7 @@ fn decode_internal(op: u8, op2: u8, op3: u8) -> (OpCode, Op, u16, usize) {
         0xF1 => op!(op, POP(AF), 12),
         0xF2 => op!(op, LD8(A, Mem(HighC)), 8),
         0xF3 => op!(op, DI, 4),
-        // 0xF4 => No instruction,
+        0xF4 => op!(op, Illegal(op), 4), // No instruction
         0xF5 => op!(op, PUSH(AF), 16),
         0xF6 => op!(op, op2, OR(imm8!(op2)), 8),
         0xF7 => op!(op, RST(0x30), 16),
@@ -587,14 +630,10 @@ fn decode_internal(op: u8, op2: u8, op3: u8) -> (OpCode, Op, u16, usize) {
         0xF9 => op!(op, LD16(SP, HL), 8),
         0xFA => op!(op, op2, op3, LD8(A, Mem(imm16!(op2, op3))), 16),
         0xFB => op!(op, EI, 4),
-        // 0xFC => No instruction,
-        // 0xFD => No instruction,
+        0xFC => op!(op, Illegal(op), 4), // No instruction
+        0xFD => op!(op, Illegal(op), 4), // No instruction
         0xFE => op!(op, op2, CP(imm8!(op2)), 8),
         0xFF => op!(op, RST(0x38), 16),
-        _ => {
-            eprintln!("Instruction {:02X} cannot be decoded.", op);
-            (OpCode::One(op), NOP, 1, 0)
-        }
     }
 }
 
@@ -0,0 +1,420 @@
+//! Recursive descent disassembler.
+//!
+//! Starts at the cartrage entry point, the RST vectors and the interupt vectors and follows
+//! the flow of the program across banks.  Anything never reached is written out as data.
+//! The output is meant to be assembled again with RGBDS.
+
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::fmt::Write;
+
+use super::decode;
+use super::decode::{ByteR, Flag, Op, OptFlag, WordR};
+
+const BANK_SIZE: usize = 0x4000;
+const ENTRY_POINTS: [u16; 14] = [
+    0x0100,
+    0x0000, 0x0008, 0x0010, 0x0018, 0x0020, 0x0028, 0x0030, 0x0038,
+    0x0040, 0x0048, 0x0050, 0x0058, 0x0060,
+];
+
+pub struct Disassembly<'a> {
+    rom: &'a [u8],
+    /// Bytes that are part of an instruction.
+    code: Vec<bool>,
+    /// Bytes that start an instruction.
+    starts: Vec<bool>,
+    /// Rom offsets that are the target of a jump or call.
+    labels: BTreeSet<usize>,
+    /// Rom offset each branching instruction was resolved to.
+    targets: HashMap<usize, usize>,
+}
+
+/// Where the flow of a instruction can go next.
+enum Flow {
+    Continue,
+    Stop,
+    Branch(u16),
+    Jump(u16),
+}
+
+impl<'a> Disassembly<'a> {
+    pub fn new(rom: &'a [u8]) -> Self {
+        let mut dis = Disassembly {
+            rom,
+            code: vec![false; rom.len()],
+            starts: vec![false; rom.len()],
+            labels: BTreeSet::new(),
+            targets: HashMap::new(),
+        };
+        let mut queue: VecDeque<usize> = ENTRY_POINTS.iter()
+            .map(|addr| *addr as usize)
+            .filter(|offset| *offset < rom.len())
+            .collect();
+        while let Some(offset) = queue.pop_front() {
+            dis.trace(offset, &mut queue);
+        }
+        dis
+    }
+
+    fn bank_count(&self) -> usize {
+        self.rom.len().div_ceil(BANK_SIZE)
+    }
+
+    fn bank_of(offset: usize) -> usize {
+        offset / BANK_SIZE
+    }
+
+    /// The address the cpu sees for a rom offset.
+    fn address_of(offset: usize) -> u16 {
+        match Self::bank_of(offset) {
+            0 => offset as u16,
+            _ => (BANK_SIZE + offset % BANK_SIZE) as u16,
+        }
+    }
+
+    /// The rom offset for an address as seen from the given bank.
+    fn offset_of(&self, addr: u16, bank: usize) -> Option<usize> {
+        let offset = match addr {
+            0x0000..=0x3FFF => addr as usize,
+            0x4000..=0x7FFF => bank.max(1) * BANK_SIZE + (addr as usize - BANK_SIZE),
+            _ => return None,
+        };
+        if offset < self.rom.len() { Some(offset) } else { None }
+    }
+
+    /// Decode the instruction at a rom offset, if it fits inside its bank.
+    fn decode_at(&self, offset: usize) -> Option<(Op, usize)> {
+        let bank_end = ((Self::bank_of(offset) + 1) * BANK_SIZE).min(self.rom.len());
+        let byte = |i: usize| if offset + i < bank_end { self.rom[offset + i] } else { 0 };
+        if decode::is_illegal(byte(0)) {
+            return None;
+        }
+        let (_, op, size, _) = decode::decode_internal(byte(0), byte(1), byte(2));
+        let size = size as usize;
+        if offset + size > bank_end { None } else { Some((op, size)) }
+    }
+
+    /// Follow one path of execution until it leaves, returning or hits known code.
+    fn trace(&mut self, mut offset: usize, queue: &mut VecDeque<usize>) {
+        let mut bank = Self::bank_of(offset);
+        // Bank that code in bank 0 has most likely switched in, from `LD A, n; LD (2000), A`.
+        let mut switched = 1;
+        let mut last_a = None;
+        loop {
+            if self.starts[offset] {
+                return;
+            }
+            let (op, size) = match self.decode_at(offset) {
+                Some(decoded) => decoded,
+                None => return,
+            };
+            if self.code[offset..offset + size].iter().any(|c| *c) {
+                // Overlaps an instruction that was already decoded.
+                return;
+            }
+            self.starts[offset] = true;
+            self.code[offset..offset + size].iter_mut().for_each(|c| *c = true);
+
+            match op {
+                Op::LD8(ByteR::A, ByteR::IMM(data)) => last_a = Some(data),
+                Op::LD8(ByteR::Mem(WordR::IMM(0x2000..=0x3FFF)), ByteR::A) => {
+                    if let Some(data) = last_a {
+                        switched = (data as usize).max(1);
+                    }
+                }
+                Op::LD8(ByteR::A, _) => last_a = None,
+                _ => {}
+            }
+
+            let next = Self::address_of(offset).wrapping_add(size as u16);
+            let target_bank = if bank == 0 { switched } else { bank };
+            match Self::flow(&op, next) {
+                Flow::Continue => {}
+                Flow::Stop => return,
+                Flow::Branch(addr) => {
+                    if let Some(target) = self.offset_of(addr, target_bank) {
+                        self.targets.insert(offset, target);
+                        self.labels.insert(target);
+                        queue.push_back(target);
+                    }
+                }
+                Flow::Jump(addr) => {
+                    match self.offset_of(addr, target_bank) {
+                        Some(target) => {
+                            self.targets.insert(offset, target);
+                            self.labels.insert(target);
+                            offset = target;
+                            bank = Self::bank_of(offset);
+                            continue;
+                        }
+                        None => return,
+                    }
+                }
+            }
+            offset += size;
+            if offset >= self.rom.len() || Self::bank_of(offset) != bank {
+                return;
+            }
+        }
+    }
+
+    fn flow(op: &Op, next: u16) -> Flow {
+        match op {
+            Op::RET(OptFlag(None)) | Op::RETI => Flow::Stop,
+            Op::JP(_, WordR::HL) => Flow::Stop,
+            Op::JP(OptFlag(None), WordR::IMM(addr)) => Flow::Jump(*addr),
+            Op::JP(_, WordR::IMM(addr)) => Flow::Branch(*addr),
+            Op::JR(OptFlag(None), o) => Flow::Jump(next.wrapping_add(*o as u16)),
+            Op::JR(_, o) => Flow::Branch(next.wrapping_add(*o as u16)),
+            Op::CALL(_, WordR::IMM(addr)) => Flow::Branch(*addr),
+            Op::RST(addr) => Flow::Branch(*addr),
+            _ => Flow::Continue,
+        }
+    }
+
+    fn label(offset: usize) -> String {
+        format!("L{:02X}_{:04X}", Self::bank_of(offset), Self::address_of(offset))
+    }
+
+    /// Label for the target of the branch at a rom offset, if it points at the start of
+    /// an instruction.  Otherwise the plain address.
+    fn target(&self, offset: usize, addr: u16) -> String {
+        match self.targets.get(&offset) {
+            Some(target) if self.starts[*target] => Self::label(*target),
+            _ => format!("${:04X}", addr),
+        }
+    }
+
+    pub fn to_rgbds(&self) -> String {
+        let mut out = String::new();
+        for bank in 0..self.bank_count() {
+            match bank {
+                0 => writeln!(out, "SECTION \"ROM Bank $000\", ROM0[$0000]").unwrap(),
+                _ => writeln!(out, "\nSECTION \"ROM Bank ${:03X}\", ROMX[$4000], BANK[${:X}]",
+                              bank, bank).unwrap(),
+            }
+            let end = ((bank + 1) * BANK_SIZE).min(self.rom.len());
+            let mut offset = bank * BANK_SIZE;
+            while offset < end {
+                if self.starts[offset] {
+                    let (op, size) = self.decode_at(offset).unwrap();
+                    if self.labels.contains(&offset) {
+                        writeln!(out, "\n{}:", Self::label(offset)).unwrap();
+                    }
+                    let bytes = &self.rom[offset..offset + size];
+                    let line = match self.instruction(&op, offset, size) {
+                        Some(line) => line,
+                        None => format!("{} ; {}", Self::data(bytes), op),
+                    };
+                    writeln!(out, "    {:<32}; ${:04X}", line, Self::address_of(offset)).unwrap();
+                    offset += size;
+                }
+                else {
+                    let start = offset;
+                    while offset < end && !self.code[offset] && offset - start < 8 {
+                        offset += 1;
+                    }
+                    writeln!(out, "    {:<32}; ${:04X}",
+                             Self::data(&self.rom[start..offset]),
+                             Self::address_of(start)).unwrap();
+                }
+            }
+        }
+        out
+    }
+
+    fn data(bytes: &[u8]) -> String {
+        let bytes: Vec<String> = bytes.iter().map(|b| format!("${:02X}", b)).collect();
+        format!("db {}", bytes.join(", "))
+    }
+
+    /// RGBDS syntax for an instruction.  None when RGBDS would not assemble it back to
+    /// the same bytes.
+    fn instruction(&self, op: &Op, offset: usize, size: usize) -> Option<String> {
+        use self::Op::*;
+        let next = Self::address_of(offset).wrapping_add(size as u16);
+        Some(match op {
+            NOP => "nop".to_string(),
+            // rgbasm pads stop with a zero.
+            STOP if self.rom.get(offset + 1) == Some(&0) => "stop".to_string(),
+            STOP => return None,
+            HALT => "halt".to_string(),
+            DAA => "daa".to_string(),
+            CPL => "cpl".to_string(),
+            SCF => "scf".to_string(),
+            CCF => "ccf".to_string(),
+
+            RST(addr) => format!("rst ${:02X}", addr),
+
+            RET(fl) => format!("ret{}", cond(fl)),
+            RETI => "reti".to_string(),
+            CALL(fl, WordR::IMM(addr)) => format!("call {}{}", cond_first(fl),
+                                                  self.target(offset, *addr)),
+
+            PUSH(o) => format!("push {}", word(o)),
+            POP(o) => format!("pop {}", word(o)),
+
+            // RGBDS may shorten these to LDH, so keep the long encoding.
+            LD8(ByteR::Mem(WordR::IMM(0xFF00..=0xFFFF)), _) |
+            LD8(_, ByteR::Mem(WordR::IMM(0xFF00..=0xFFFF))) => return None,
+            LD8(o1 @ ByteR::Mem(WordR::High(_)), o2) |
+            LD8(o1 @ ByteR::Mem(WordR::HighC), o2) |
+            LD8(o1, o2 @ ByteR::Mem(WordR::High(_))) |
+            LD8(o1, o2 @ ByteR::Mem(WordR::HighC)) => format!("ldh {}, {}", byte(o1), byte(o2)),
+            LD8(o1, o2) => format!("ld {}, {}", byte(o1), byte(o2)),
+            LD16(o1, o2) => format!("ld {}, {}", word(o1), word(o2)),
+            LDMem(o1, o2) => format!("ld [{}], {}", word(o1), word(o2)),
+            SPLD(o) => format!("ld hl, sp{:+}", o),
+
+            AND(o) => format!("and a, {}", byte(o)),
+            OR(o) => format!("or a, {}", byte(o)),
+            XOR(o) => format!("xor a, {}", byte(o)),
+            CP(o) => format!("cp a, {}", byte(o)),
+
+            JR(fl, o) => format!("jr {}{}", cond_first(fl),
+                                 self.target(offset, next.wrapping_add(*o as u16))),
+            JP(_, WordR::HL) => "jp hl".to_string(),
+            JP(fl, WordR::IMM(addr)) => format!("jp {}{}", cond_first(fl),
+                                                self.target(offset, *addr)),
+
+            INC8(o) => format!("inc {}", byte(o)),
+            INC16(o) => format!("inc {}", word(o)),
+            DEC8(o) => format!("dec {}", byte(o)),
+            DEC16(o) => format!("dec {}", word(o)),
+
+            ADD8(o) => format!("add a, {}", byte(o)),
+            ADD16(o1, o2) => format!("add {}, {}", word(o1), word(o2)),
+            SPADD(o) => format!("add sp, {}", o),
+            ADC(o) => format!("adc a, {}", byte(o)),
+            SUB(o) => format!("sub a, {}", byte(o)),
+            SBC(o) => format!("sbc a, {}", byte(o)),
+
+            RL(o) => format!("rl {}", byte(o)),
+            RLC(o) => format!("rlc {}", byte(o)),
+            RLA => "rla".to_string(),
+            RLCA => "rlca".to_string(),
+            RR(o) => format!("rr {}", byte(o)),
+            RRC(o) => format!("rrc {}", byte(o)),
+            RRA => "rra".to_string(),
+            RRCA => "rrca".to_string(),
+            SLA(o) => format!("sla {}", byte(o)),
+            SRA(o) => format!("sra {}", byte(o)),
+            SWAP(o) => format!("swap {}", byte(o)),
+            SRL(o) => format!("srl {}", byte(o)),
+
+            BIT(n, o) => format!("bit {}, {}", n, byte(o)),
+            RES(n, o) => format!("res {}, {}", n, byte(o)),
+            SET(n, o) => format!("set {}, {}", n, byte(o)),
+
+            DI => "di".to_string(),
+            EI => "ei".to_string(),
+
+            _ => return None,
+        })
+    }
+}
+
+fn flag(fl: &OptFlag) -> &'static str {
+    match fl {
+        OptFlag(None) => "",
+        OptFlag(Some((Flag::Z, true))) => "z",
+        OptFlag(Some((Flag::Z, false))) => "nz",
+        OptFlag(Some((Flag::C, true))) => "c",
+        OptFlag(Some((Flag::C, false))) => "nc",
+        OptFlag(Some(_)) => unreachable!("Only Z and C are used as conditions"),
+    }
+}
+
+/// Condition as the only operand.
+fn cond(fl: &OptFlag) -> String {
+    match fl {
+        OptFlag(None) => String::new(),
+        _ => format!(" {}", flag(fl)),
+    }
+}
+
+/// Condition followed by another operand.
+fn cond_first(fl: &OptFlag) -> String {
+    match fl {
+        OptFlag(None) => String::new(),
+        _ => format!("{}, ", flag(fl)),
+    }
+}
+
+fn word(reg: &WordR) -> String {
+    match reg {
+        WordR::AF => "af".to_string(),
+        WordR::BC => "bc".to_string(),
+        WordR::DE => "de".to_string(),
+        WordR::HL => "hl".to_string(),
+        WordR::SP => "sp".to_string(),
+        WordR::PC => "pc".to_string(),
+        WordR::HLI => "hl+".to_string(),
+        WordR::HLD => "hl-".to_string(),
+        WordR::IMM(data) => format!("${:04X}", data),
+        WordR::HighC => "c".to_string(),
+        WordR::High(data) => format!("$FF{:02X}", data),
+    }
+}
+
+fn byte(reg: &ByteR) -> String {
+    match reg {
+        ByteR::A => "a".to_string(),
+        ByteR::B => "b".to_string(),
+        ByteR::C => "c".to_string(),
+        ByteR::D => "d".to_string(),
+        ByteR::E => "e".to_string(),
+        ByteR::H => "h".to_string(),
+        ByteR::L => "l".to_string(),
+        ByteR::F => "f".to_string(),
+        ByteR::Mem(addr) => format!("[{}]", word(addr)),
+        ByteR::IMM(data) => format!("${:02X}", data),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use gb::disasm::Disassembly;
+
+    fn rom() -> Vec<u8> {
+        let mut rom = vec![0xFF; 0x8000];
+        // Entry: NOP; JP 0150
+        rom[0x100..0x104].copy_from_slice(&[0x00, 0xC3, 0x50, 0x01]);
+        // 0150: LD A, 02; LD (2000), A; CALL 4000; JR -2 (to the call)
+        rom[0x150..0x15A].copy_from_slice(&[0x3E, 0x02, 0xEA, 0x00, 0x20,
+                                            0xCD, 0x00, 0x40, 0x18, 0xFB]);
+        // Bank 1: should not be reached since bank 2 is switched in
+        rom[0x4000] = 0xC9;
+        rom[0x4001..0x4004].copy_from_slice(&[0x12, 0x34, 0x56]);
+        rom
+    }
+
+    #[test]
+    fn separates_code_and_data() {
+        let mut rom = rom();
+        rom.extend(vec![0xC9; 0x4000]);
+        let rom = rom;
+        let dis = Disassembly::new(&rom);
+        assert!(dis.starts[0x150]);
+        assert!(!dis.code[0x104]);
+        // Only the bank switched in at the call is followed.
+        assert!(dis.starts[0x8000]);
+        assert!(!dis.code[0x4000]);
+
+        let asm = dis.to_rgbds();
+        assert!(asm.contains("L00_0150:"));
+        assert!(asm.contains("jp L00_0150"));
+        assert!(asm.contains("call L02_4000"));
+        assert!(asm.contains("jr L00_0155"));
+        assert!(asm.contains("SECTION \"ROM Bank $002\", ROMX[$4000], BANK[$2]"));
+        assert!(asm.contains("db $C9, $12, $34, $56"));
+    }
+
+    #[test]
+    fn missing_bank_is_not_followed() {
+        let rom = rom();
+        let dis = Disassembly::new(&rom);
+        assert!(!dis.code[0x4000]);
+        assert!(dis.to_rgbds().contains("call $4000"));
+    }
+}
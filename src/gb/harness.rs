@@ -0,0 +1,75 @@
+//! What the tests driven by files under tests/ share: building roms from source, running
+//! cases a few at a time and reporting what failed.
+
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Mutex;
+use std::thread;
+
+use gb::asm;
+
+/// Where roms assembled from source go.
+const ASSEMBLED: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/target/test-roms");
+
+/// Fails a test with nothing to run, which would otherwise pass without checking anything.
+pub fn require<T>(cases: &[T], dir: &str) {
+    assert!(!cases.is_empty(), "Nothing to test in {}, see the README there", dir);
+}
+
+/// Runs every case on as many threads as there are cpus, returning the results in the
+/// same order, with None for a case that panicked.
+pub fn run_all<T, R, F>(cases: Vec<T>, run: F) -> Vec<Option<R>>
+    where T: Send, R: Send, F: Fn(T) -> R + Sync {
+    let count = cases.len();
+    let queue = Mutex::new(cases.into_iter().enumerate());
+    let results = Mutex::new((0..count).map(|_| None).collect::<Vec<_>>());
+    let threads = thread::available_parallelism().map_or(4, |n| n.get()).min(count);
+    thread::scope(|scope| {
+        for _ in 0..threads {
+            scope.spawn(|| loop {
+                let next = queue.lock().unwrap().next();
+                let (i, case) = match next {
+                    Some(next) => next,
+                    None => break,
+                };
+                let result = panic::catch_unwind(AssertUnwindSafe(|| run(case))).ok();
+                results.lock().unwrap()[i] = result;
+            });
+        }
+    });
+    results.into_inner().unwrap()
+}
+
+/// Prints every failure, then fails if there were any.
+pub fn report(failures: &[String], what: &str) {
+    for failure in failures {
+        eprintln!("{}", failure);
+    }
+    assert!(failures.is_empty(), "{} {}", failures.len(), what);
+}
+
+/// Assembles a source file into a 32KB rom only cartrage, returning where it is.
+pub fn assemble(source: &str) -> Result<String, String> {
+    let text = fs::read_to_string(source).map_err(|e| format!("{}: {}", source, e))?;
+    let mut bytes = asm::assemble(&text, 0).map_err(|e| format!("{}: {}", source, e))?;
+    if bytes.len() > 0x8000 {
+        return Err(format!("{}: {:X} bytes is too big for a 32KB rom", source, bytes.len()));
+    }
+    bytes.resize(0x8000, 0);
+    let name = source.trim_start_matches(env!("CARGO_MANIFEST_DIR"))
+        .trim_start_matches("/tests/")
+        .replace('/', "-")
+        .replace(".asm", ".gb");
+    let path = format!("{}/{}", ASSEMBLED, name);
+    fs::create_dir_all(ASSEMBLED).map_err(|e| format!("{}: {}", ASSEMBLED, e))?;
+    fs::write(&path, bytes).map_err(|e| format!("{}: {}", path, e))?;
+    Ok(path)
+}
+
+/// A rom to run, assembled first if it is source.
+pub fn rom_path(path: String) -> Result<String, String> {
+    match path.ends_with(".asm") {
+        true => assemble(&path),
+        false => Ok(path),
+    }
+}
@@ -0,0 +1,222 @@
+//! Registers only a Game Boy Color has.
+//!
+//! FF4D KEY1  Speed switch, bit 7 the current speed and bit 0 a switch waiting for STOP
+//! FF4F VBK   Vram bank
+//! FF51-FF55  HDMA1-5, copying to vram all at once or 16 bytes each hblank
+//! FF68-FF6B  BCPS, BCPD, OCPS and OCPD, the index and data of the colour pallets
+//! FF70 SVBK  Work ram bank at D000, 1 to 7
+
+use gb::state::{Snapshot, State};
+
+/// Eight pallets of four 15 bit colours, written a byte at a time through an index.
+pub struct ColourPallets {
+    index: u8,
+    auto_increment: bool,
+    data: [u8; 64],
+}
+
+impl ColourPallets {
+    fn new() -> Self {
+        // The boot rom leaves every colour white.
+        ColourPallets { index: 0, auto_increment: false, data: [0xFF; 64] }
+    }
+
+    fn read_index(&self) -> u8 {
+        self.index | 0x40 | if self.auto_increment { 0x80 } else { 0 }
+    }
+
+    fn write_index(&mut self, data: u8) {
+        self.index = data & 0x3F;
+        self.auto_increment = data & 0x80 > 0;
+    }
+
+    fn read_data(&self) -> u8 {
+        self.data[self.index as usize]
+    }
+
+    fn write_data(&mut self, data: u8) {
+        self.data[self.index as usize] = data;
+        if self.auto_increment {
+            self.index = (self.index + 1) & 0x3F;
+        }
+    }
+
+    /// A colour as 8 bit RGB.
+    pub fn colour(&self, pallet: u8, colour: u8) -> [u8; 3] {
+        let at = pallet as usize * 8 + colour as usize * 2;
+        let value = self.data[at] as u16 | (self.data[at + 1] as u16) << 8;
+        let scale = |c: u16| ((c << 3) | (c >> 2)) as u8;
+        [scale(value & 0x1F), scale((value >> 5) & 0x1F), scale((value >> 10) & 0x1F)]
+    }
+}
+
+pub struct Cgb {
+    pub double_speed: bool,
+    prepare_speed: bool,
+    pub vram_bank: usize,
+    pub wram_bank: usize,
+    pub bg_pallets: ColourPallets,
+    pub obj_pallets: ColourPallets,
+    pub hdma_source: u16,
+    pub hdma_dest: u16,
+    /// Blocks of 16 bytes left to copy in hblank, counting down to 0.
+    pub hdma: Option<u8>,
+    hdma_left: u8, // What FF55 shows once no copy is running.
+}
+
+/// What a write to FF55 asks for.
+pub enum Transfer {
+    /// Copy this many blocks now.
+    General(u8),
+    HBlank,
+    Cancel,
+}
+
+impl Cgb {
+    pub fn new() -> Self {
+        Cgb {
+            double_speed: false,
+            prepare_speed: false,
+            vram_bank: 0,
+            wram_bank: 1,
+            bg_pallets: ColourPallets::new(),
+            obj_pallets: ColourPallets::new(),
+            hdma_source: 0,
+            hdma_dest: 0,
+            hdma: None,
+            hdma_left: 0x7F,
+        }
+    }
+
+    pub fn read(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0xFF4D => Some(0x7E | (self.double_speed as u8) << 7 | self.prepare_speed as u8),
+            0xFF4F => Some(0xFE | self.vram_bank as u8),
+            0xFF51..=0xFF54 => Some(0xFF), // Write only.
+            0xFF55 => Some(match self.hdma {
+                Some(left) => left,
+                None => 0x80 | self.hdma_left,
+            }),
+            0xFF68 => Some(self.bg_pallets.read_index()),
+            0xFF69 => Some(self.bg_pallets.read_data()),
+            0xFF6A => Some(self.obj_pallets.read_index()),
+            0xFF6B => Some(self.obj_pallets.read_data()),
+            0xFF70 => Some(0xF8 | self.wram_bank as u8),
+            _ => None,
+        }
+    }
+
+    /// Everything but FF55, which `transfer` handles.
+    pub fn write(&mut self, addr: u16, data: u8) -> bool {
+        match addr {
+            0xFF4D => self.prepare_speed = data & 0x01 > 0,
+            0xFF4F => self.vram_bank = (data & 0x01) as usize,
+            0xFF51 => self.hdma_source = (data as u16) << 8 | self.hdma_source & 0x00FF,
+            0xFF52 => self.hdma_source = self.hdma_source & 0xFF00 | (data & 0xF0) as u16,
+            0xFF53 => self.hdma_dest = ((data & 0x1F) as u16) << 8 | self.hdma_dest & 0x00FF,
+            0xFF54 => self.hdma_dest = self.hdma_dest & 0xFF00 | (data & 0xF0) as u16,
+            0xFF68 => self.bg_pallets.write_index(data),
+            0xFF69 => self.bg_pallets.write_data(data),
+            0xFF6A => self.obj_pallets.write_index(data),
+            0xFF6B => self.obj_pallets.write_data(data),
+            0xFF70 => self.wram_bank = ((data & 0x07) as usize).max(1),
+            _ => return false,
+        }
+        true
+    }
+
+    /// Start or stop a copy to vram from a write to FF55.
+    pub fn transfer(&mut self, data: u8) -> Transfer {
+        let blocks = data & 0x7F;
+        match (data & 0x80 > 0, self.hdma) {
+            (false, Some(left)) => {
+                self.hdma = None;
+                self.hdma_left = left;
+                Transfer::Cancel
+            }
+            (false, None) => {
+                self.hdma_left = 0x7F;
+                Transfer::General(blocks + 1)
+            }
+            (true, _) => {
+                self.hdma = Some(blocks);
+                Transfer::HBlank
+            }
+        }
+    }
+
+    /// Count off a block copied in hblank.
+    pub fn hdma_done(&mut self) {
+        self.hdma = match self.hdma {
+            Some(0) | None => {
+                self.hdma_left = 0x7F;
+                None
+            }
+            Some(left) => Some(left - 1),
+        };
+    }
+
+    /// STOP switches speed if a switch was asked for.
+    pub fn switch_speed(&mut self) -> bool {
+        if self.prepare_speed {
+            self.prepare_speed = false;
+            self.double_speed = !self.double_speed;
+            true
+        }
+        else { false }
+    }
+}
+
+impl State for ColourPallets {
+    fn state(&mut self, snapshot: &mut Snapshot) {
+        snapshot.u8(&mut self.index);
+        snapshot.bool(&mut self.auto_increment);
+        snapshot.bytes(&mut self.data);
+    }
+}
+
+impl State for Cgb {
+    fn state(&mut self, snapshot: &mut Snapshot) {
+        snapshot.bool(&mut self.double_speed);
+        snapshot.bool(&mut self.prepare_speed);
+        snapshot.usize(&mut self.vram_bank);
+        snapshot.usize(&mut self.wram_bank);
+        self.bg_pallets.state(snapshot);
+        self.obj_pallets.state(snapshot);
+        snapshot.u16(&mut self.hdma_source);
+        snapshot.u16(&mut self.hdma_dest);
+        snapshot.option(&mut self.hdma, || 0, |snapshot, blocks| snapshot.u8(blocks));
+        snapshot.u8(&mut self.hdma_left);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use gb::mem::cgb::{Cgb, Transfer};
+
+    #[test]
+    fn pallet_data_auto_increments() {
+        let mut cgb = Cgb::new();
+        cgb.write(0xFF68, 0x80 | 0x08); // Pallet 1, colour 0.
+        for byte in &[0x1F, 0x00, 0xE0, 0x03, 0x00, 0x7C] {
+            cgb.write(0xFF69, *byte);
+        }
+        assert_eq!(cgb.read(0xFF68), Some(0xC0 | 0x0E));
+        assert_eq!(cgb.bg_pallets.colour(1, 0), [0xFF, 0, 0]);
+        assert_eq!(cgb.bg_pallets.colour(1, 1), [0, 0xFF, 0]);
+        assert_eq!(cgb.bg_pallets.colour(1, 2), [0, 0, 0xFF]);
+        assert_eq!(cgb.bg_pallets.colour(0, 0), [0xFF, 0xFF, 0xFF]);
+    }
+
+    #[test]
+    fn hblank_transfer_counts_down_and_cancels() {
+        let mut cgb = Cgb::new();
+        match cgb.transfer(0x82) { Transfer::HBlank => {}, _ => panic!("Expected hblank") }
+        cgb.hdma_done();
+        assert_eq!(cgb.read(0xFF55), Some(0x01));
+        match cgb.transfer(0x00) { Transfer::Cancel => {}, _ => panic!("Expected a cancel") }
+        assert_eq!(cgb.read(0xFF55), Some(0x81));
+        match cgb.transfer(0x03) { Transfer::General(4) => {}, _ => panic!("Expected 4 blocks") }
+        assert_eq!(cgb.read(0xFF55), Some(0xFF));
+    }
+}
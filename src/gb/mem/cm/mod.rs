@@ -1,5 +1,8 @@
-use std::fs::File;
-use std::io::Read;
+use std::fs;
+use std::path::Path;
+
+use gb::GbKind;
+use gb::state::{Snapshot, State};
 
 #[derive(Debug)]
 enum CMtype {
@@ -17,87 +20,166 @@ pub struct CartrageMapper {
     mbc_mode: usize,
     rom: Vec<u8>,
     ram: Vec<u8>,
+    save: Option<String>, // Where battery backed ram is kept.
 }
 impl CartrageMapper {
-    fn ram_size(r: &u8) -> usize {
+    fn ram_size(r: &u8) -> Result<usize, String> {
         match r {
-            0 => 0,
-            1 => 0x0800,
-            2 => 0x2000,
-            3 => 0x8000,
-            _ => panic!("Unknown ram size")
+            0 => Ok(0),
+            1 => Ok(0x0800),
+            2 => Ok(0x2000),
+            3 => Ok(0x8000),
+            _ => Err(format!("Unknown ram size {:02X}", r)),
         }
     }
-    fn cm_type(t: &u8, r: &u8) -> (CMtype, Option<usize>) {
+    fn cm_type(t: &u8, r: &u8) -> Result<(CMtype, Option<usize>), String> {
         match t {
-            0x00 => (CMtype::ROM, None),
-            0x01 => (CMtype::MBC1, None),
-            0x02 => (CMtype::MBC1, Some(Self::ram_size(r))),
-            0x03 => (CMtype::MBC1, Some(Self::ram_size(r))),
-            0x05...0x06 => (CMtype::MBC2, None),
-            0x08...0x09 => (CMtype::ROM, None),
-            0x10...0x13 => (CMtype::MBC3, None),
-            _ => panic!("Unknown Cartrage Mapper"),
+            0x00 => Ok((CMtype::ROM, None)),
+            0x01 => Ok((CMtype::MBC1, None)),
+            0x02 => Ok((CMtype::MBC1, Some(Self::ram_size(r)?))),
+            0x03 => Ok((CMtype::MBC1, Some(Self::ram_size(r)?))),
+            0x05...0x06 => Ok((CMtype::MBC2, None)),
+            0x08...0x09 => Ok((CMtype::ROM, None)),
+            0x10...0x13 => Ok((CMtype::MBC3, None)),
+            _ => Err(format!("Unknown Cartrage Mapper {:02X}", t)),
         }
     }
 
-    pub fn new(cartrage: String) -> CartrageMapper {
-        use std::str;
-        match File::open(cartrage) {
-            Ok(mut file) => {
-                let mut rom = Vec::new();
-                let size = file.read_to_end(&mut rom).unwrap();
-
-                println!("Header Title: {}",
-                        str::from_utf8(&rom[0x134..0x13E]).unwrap());
-                println!("Header Manufacturer Code: {}",
-                        str::from_utf8(&rom[0x13F..0x142]).unwrap());
-
-                let (cm_chip, ram_type) = Self::cm_type(&rom[0x147], &rom[0x148]);
-                println!("Chip type: {:?}", cm_chip);
-
-                let mut ram = match ram_type {
-                    Some(size) => { println!("Ram size: {}", size); vec![0;size] },
-                    None => { println!("No RAM"); vec![] },
-                };
-
-                CartrageMapper {
-                    cm_chip,
-                    rom,
-                    ram,
-                    rom_page: 1,
-                    ram_page: 0,
-                    mbc_mode: 0,
-                    ram_enable: false,
-                }
+    fn has_battery(t: &u8) -> bool {
+        matches!(t, 0x03 | 0x06 | 0x09 | 0x0F | 0x10 | 0x13)
+    }
+
+    /// Load a cartrage, failing if it is missing, too short to hold a header and the first
+    /// two banks, or has a mapper or ram size that is not known.
+    pub fn new(cartrage: String) -> Result<CartrageMapper, String> {
+        let error = |e: String| format!("{}: {}", cartrage, e);
+        let rom = fs::read(&cartrage).map_err(|e| error(e.to_string()))?;
+        if rom.len() < 0x8000 {
+            return Err(error(format!("Only {:X} bytes long, too short for a cartrage", rom.len())));
+        }
+
+        println!("Header Title: {}", String::from_utf8_lossy(&rom[0x134..0x13E]));
+        println!("Header Manufacturer Code: {}", String::from_utf8_lossy(&rom[0x13F..0x142]));
+
+        let (cm_chip, ram_type) = Self::cm_type(&rom[0x147], &rom[0x148]).map_err(error)?;
+        println!("Chip type: {:?}", cm_chip);
+
+        let mut ram = match ram_type {
+            Some(size) => { println!("Ram size: {}", size); vec![0;size] },
+            None => { println!("No RAM"); vec![] },
+        };
+
+        let save = match Self::has_battery(&rom[0x147]) && !ram.is_empty() {
+            true => Some(Path::new(&cartrage).with_extension("sav")
+                         .to_string_lossy().into_owned()),
+            false => None,
+        };
+        if let Some(ref save) = save {
+            if let Ok(data) = fs::read(save) {
+                println!("Save loaded from {}", save);
+                let size = data.len().min(ram.len());
+                ram[..size].copy_from_slice(&data[..size]);
             }
-            Err(_) => panic!("File dose not exist"),
         }
+
+        Ok(CartrageMapper {
+            cm_chip,
+            rom,
+            ram,
+            rom_page: 1,
+            ram_page: 0,
+            mbc_mode: 0,
+            ram_enable: false,
+            save,
+        })
+    }
+    /// A cartrage with nothing in it, to stand in while the real one is moved.
+    pub fn empty() -> CartrageMapper {
+        CartrageMapper {
+            cm_chip: CMtype::ROM,
+            rom: vec![],
+            ram: vec![],
+            rom_page: 1,
+            ram_page: 0,
+            mbc_mode: 0,
+            ram_enable: false,
+            save: None,
+        }
+    }
+
+    /// Banks back to how they are at power on, ram is kept.
+    pub fn reset(&mut self) {
+        self.rom_page = 1;
+        self.ram_page = 0;
+        self.mbc_mode = 0;
+        self.ram_enable = false;
+    }
+
+    /// Which machine the cartrage was made for, from the colour flag in the header.
+    pub fn kind(&self) -> GbKind {
+        match self.rom.get(0x143) {
+            Some(flag) if flag & 0x80 > 0 => GbKind::GBC,
+            _ => GbKind::GB,
+        }
+    }
+
+    /// The header says the game has Super Game Boy features, which only counts with the new
+    /// licensee code.
+    pub fn supports_sgb(&self) -> bool {
+        self.rom.len() > 0x14B && self.rom[0x146] == 0x03 && self.rom[0x14B] == 0x33
     }
+
+    pub fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    pub fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    /// Copy in as much of a previous ram as fits.
+    pub fn restore_ram(&mut self, data: &[u8]) {
+        let size = data.len().min(self.ram.len());
+        self.ram[..size].copy_from_slice(&data[..size]);
+    }
+
+    /// Write battery backed ram out to the save file.
+    pub fn flush(&self) {
+        if let Some(ref save) = self.save {
+            match fs::write(save, &self.ram) {
+                Ok(()) => println!("Saved to {}", save),
+                Err(e) => eprintln!("Could not save to {}: {}", save, e),
+            }
+        }
+    }
+
+    /// Offset into rom of an address below 0x8000, given the current bank.
+    pub fn rom_offset(&self, addr: u16) -> usize {
+        if addr < 0x4000 { addr as usize }
+        else { (addr as usize) - 0x4000 + (0x4000 * self.rom_page as usize) }
+    }
+
+    pub fn read_offset(&self, offset: usize) -> u8 {
+        self.rom[offset]
+    }
+
     pub fn read(&self, addr: u16) -> Option<u8> {
         if addr < 0x4000 {
             Some(self.rom[addr as usize])
         }
         else if addr < 0x8000 {
-            let addr = (addr as usize) - 0x4000 + (0x4000 * self.rom_page as usize);
-            println!("Effective address {:04X} from page {:02X}", addr, self.rom_page);
-            Some(self.rom[addr])
+            Some(self.rom[self.rom_offset(addr)])
         }
         else { None }
     }
     pub fn write(&mut self, addr: u16, data: u8) -> bool {
-        println!("Write to ROM at {:04X}, with data {:02X}", addr, data);
         match self.cm_chip {
-            CMtype::ROM => println!("Unexpected write to ROM."),
+            CMtype::ROM => {} // Nothing to switch, games write here anyway.
             CMtype::MBC1 => match addr {
                     0x0000..=0x1FFF => self.ram_enable = data & 0x0F == 0x0A,
                     0x2000..=0x3FFF => {
                         self.rom_page = (self.rom_page & 0xE0) | (data & 0x1F);
                         if self.rom_page & 0x1F == 0 { self.rom_page += 1; }
-                        println!("ROM bank {:X} loaded using data {:02X} and addr {:04X}", 
-                                 self.rom_page,
-                                 data,
-                                 addr);
                     }
                     0x4000..=0x5FFF => match self.mbc_mode {
                         0 => {
@@ -138,3 +220,14 @@ impl CartrageMapper {
         else { false }
     }
 }
+
+/// Which banks are switched in and the ram, the chip and the rom come from the file.
+impl State for CartrageMapper {
+    fn state(&mut self, snapshot: &mut Snapshot) {
+        snapshot.u8(&mut self.rom_page);
+        snapshot.u8(&mut self.ram_page);
+        snapshot.bool(&mut self.ram_enable);
+        snapshot.usize(&mut self.mbc_mode);
+        snapshot.bytes(&mut self.ram);
+    }
+}
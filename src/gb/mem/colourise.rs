@@ -0,0 +1,353 @@
+//! Colours for monochrome games the way a Game Boy Color picks them, without its boot rom.
+//!
+//! The boot rom sums the title in the header of games Nintendo published and looks the sum
+//! up in a table, using the fourth letter of the title where two games share a sum.  Each
+//! game it knows gets one of 51 combinations of palettes, and games it does not know get the
+//! dark green one.  Holding a direction, with or without A or B, at power on picks one of
+//! twelve of them instead.
+//!
+//! The tables are the boot rom's, as worked out for SameBoy's free boot rom.
+
+use super::Buttons;
+
+/// Background, OBP0 and OBP1, four colours each, lightest first.
+pub type Pallets = [[[u8; 3]; 4]; 3];
+
+/// The boot rom's palettes, in the Game Boy Color's five bits each of blue, green and red.
+const PALETTES: [[u16; 4]; 30] = [
+    [0x7FFF, 0x32BF, 0x00D0, 0x0000],
+    [0x639F, 0x4279, 0x15B0, 0x04CB],
+    [0x7FFF, 0x6E31, 0x454A, 0x0000],
+    [0x7FFF, 0x1BEF, 0x0200, 0x0000],
+    [0x7FFF, 0x421F, 0x1CF2, 0x0000],
+    [0x7FFF, 0x5294, 0x294A, 0x0000],
+    [0x7FFF, 0x03FF, 0x012F, 0x0000],
+    [0x7FFF, 0x03EF, 0x01D6, 0x0000],
+    [0x7FFF, 0x42B5, 0x3DC8, 0x0000],
+    [0x7E74, 0x03FF, 0x0180, 0x0000],
+    [0x67FF, 0x77AC, 0x1A13, 0x2D6B],
+    [0x7ED6, 0x4BFF, 0x2175, 0x0000],
+    [0x53FF, 0x4A5F, 0x7E52, 0x0000],
+    [0x4FFF, 0x7ED2, 0x3A4C, 0x1CE0],
+    [0x03ED, 0x7FFF, 0x255F, 0x0000],
+    [0x036A, 0x021F, 0x03FF, 0x7FFF],
+    [0x7FFF, 0x01DF, 0x0112, 0x0000],
+    [0x231F, 0x035F, 0x00F2, 0x0009],
+    [0x7FFF, 0x03EA, 0x011F, 0x0000],
+    [0x299F, 0x001A, 0x000C, 0x0000],
+    [0x7FFF, 0x027F, 0x001F, 0x0000],
+    [0x7FFF, 0x03E0, 0x0206, 0x0120],
+    [0x7FFF, 0x7EEB, 0x001F, 0x7C00],
+    [0x7FFF, 0x3FFF, 0x7E00, 0x001F],
+    [0x7FFF, 0x03FF, 0x001F, 0x0000],
+    [0x03FF, 0x001F, 0x000C, 0x0000],
+    [0x7FFF, 0x033F, 0x0193, 0x0000],
+    [0x0000, 0x4200, 0x037F, 0x7FFF],
+    [0x7FFF, 0x7E8C, 0x7C00, 0x0000],
+    [0x7FFF, 0x1BEF, 0x6180, 0x0000],
+];
+
+/// OBP0, OBP1 and background as where they start in `PALETTES`, counted in colours.  A few
+/// start part way through a palette and run on into the next, as the boot rom has them.
+const COMBINATIONS: [(usize, usize, usize); 51] = [
+    (4 * 4, 4 * 4, 29 * 4),
+    (18 * 4, 18 * 4, 18 * 4),
+    (20 * 4, 20 * 4, 20 * 4),
+    (24 * 4, 24 * 4, 24 * 4),
+    (9 * 4, 9 * 4, 9 * 4),
+    (0, 0, 0),
+    (27 * 4, 27 * 4, 27 * 4),
+    (5 * 4, 5 * 4, 5 * 4),
+    (12 * 4, 12 * 4, 12 * 4),
+    (26 * 4, 26 * 4, 26 * 4),
+    (16 * 4, 8 * 4, 8 * 4),
+    (4 * 4, 28 * 4, 28 * 4),
+    (4 * 4, 2 * 4, 2 * 4),
+    (3 * 4, 4 * 4, 4 * 4),
+    (4 * 4, 29 * 4, 29 * 4),
+    (28 * 4, 4 * 4, 28 * 4),
+    (2 * 4, 17 * 4, 2 * 4),
+    (16 * 4, 16 * 4, 8 * 4),
+    (4 * 4, 4 * 4, 7 * 4),
+    (4 * 4, 4 * 4, 18 * 4),
+    (4 * 4, 4 * 4, 20 * 4),
+    (19 * 4, 19 * 4, 9 * 4),
+    (4 * 4 - 1, 4 * 4 - 1, 11 * 4),
+    (17 * 4, 17 * 4, 2 * 4),
+    (4 * 4, 4 * 4, 2 * 4),
+    (4 * 4, 4 * 4, 3 * 4),
+    (28 * 4, 28 * 4, 0),
+    (3 * 4, 3 * 4, 0),
+    (0, 0, 4),
+    (18 * 4, 22 * 4, 18 * 4),
+    (20 * 4, 22 * 4, 20 * 4),
+    (24 * 4, 22 * 4, 24 * 4),
+    (16 * 4, 22 * 4, 8 * 4),
+    (17 * 4, 4 * 4, 13 * 4),
+    (28 * 4 - 1, 0, 14 * 4),
+    (28 * 4 - 1, 4 * 4, 15 * 4),
+    (19 * 4, 22 * 4, 9 * 4),
+    (16 * 4, 28 * 4, 10 * 4),
+    (4 * 4, 23 * 4, 28 * 4),
+    (17 * 4, 22 * 4, 2 * 4),
+    (4 * 4, 0, 2 * 4),
+    (4 * 4, 28 * 4, 3 * 4),
+    (28 * 4, 3 * 4, 0),
+    (3 * 4, 28 * 4, 4 * 4),
+    (21 * 4, 28 * 4, 4 * 4),
+    (3 * 4, 28 * 4, 0),
+    (25 * 4, 3 * 4, 28 * 4),
+    (0, 28 * 4, 8 * 4),
+    (4 * 4, 3 * 4, 28 * 4),
+    (28 * 4, 3 * 4, 6 * 4),
+    (4 * 4, 28 * 4, 29 * 4),
+];
+
+/// The twelve combinations the buttons pick, with the direction and button that pick them.
+const BUTTON_COMBINATIONS: [(&str, u8, u8, usize); 12] = [
+    ("brown", UP, 0, 5),
+    ("red", UP, A, 43),
+    ("dark brown", UP, B, 28),
+    ("blue", LEFT, 0, 48),
+    ("dark blue", LEFT, A, 40),
+    ("grey", LEFT, B, 7),
+    ("pale yellow", DOWN, 0, 8),
+    ("orange", DOWN, A, 3),
+    ("yellow", DOWN, B, 49),
+    ("green", RIGHT, 0, 1),
+    ("dark green", RIGHT, A, 0),
+    ("inverted", RIGHT, B, 6),
+];
+
+/// Dark green, for games the boot rom does not know.
+const DEFAULT: usize = 0;
+
+// Buttons as `Buttons::bits` has them.
+const RIGHT: u8 = 0x10;
+const LEFT: u8 = 0x20;
+const UP: u8 = 0x40;
+const DOWN: u8 = 0x80;
+const A: u8 = 0x01;
+const B: u8 = 0x02;
+
+/// Title sum and combination, for games the boot rom knows by their sum alone.
+const TITLES: [(u8, usize); 65] = [
+    (0x00, 0), // Default
+    (0x88, 4), // ALLEY WAY
+    (0x16, 5), // YAKUMAN
+    (0x36, 35), // BASEBALL, GAME&WATCH 2
+    (0xD1, 34), // TENNIS
+    (0xDB, 3), // TETRIS
+    (0xF2, 31), // QIX
+    (0x3C, 15), // DR.MARIO
+    (0x8C, 10), // RADARMISSION
+    (0x92, 5), // F1RACE
+    (0x3D, 19), // YOSSY NO TAMAGO
+    (0x5C, 36),
+    (0x58, 7), // X
+    (0xC9, 37), // MARIOLAND2
+    (0x3E, 30), // YOSSY NO COOKIE
+    (0x70, 44), // ZELDA
+    (0x1D, 21),
+    (0x59, 32),
+    (0x69, 31), // TETRIS FLASH
+    (0x19, 20), // DONKEY KONG
+    (0x35, 5), // MARIO'S PICROSS
+    (0xA8, 33),
+    (0x14, 13), // POKEMON RED, GAMEBOYCAMERA G
+    (0xAA, 14), // POKEMON GREEN
+    (0x75, 5), // PICROSS 2
+    (0x95, 29), // YOSSY NO PANEPON
+    (0x99, 5), // KIRAKIRA KIDS
+    (0x34, 18), // GAMEBOY GALLERY
+    (0x6F, 9), // POCKETCAMERA
+    (0x15, 3),
+    (0xFF, 2), // BALLOON KID
+    (0x97, 26), // KINGOFTHEZOO
+    (0x4B, 25), // DMG FOOTBALL
+    (0x90, 25), // WORLD CUP
+    (0x17, 41), // OTHELLO
+    (0x10, 42), // SUPER RC PRO-AM
+    (0x39, 26), // DYNABLASTER
+    (0xF7, 45), // BOY AND BLOB GB2
+    (0xF6, 42), // MEGAMAN
+    (0xA2, 45), // STAR WARS-NOA
+    (0x49, 36),
+    (0x4E, 38), // WAVERACE
+    (0x43, 26),
+    (0x68, 42), // LOLO2
+    (0xE0, 30), // YOSHI'S COOKIE
+    (0x8B, 41), // MYSTIC QUEST
+    (0xF0, 34),
+    (0xCE, 34), // TOPRANKINGTENNIS
+    (0x0C, 5), // MANSELL
+    (0x29, 42), // MEGAMAN3
+    (0xE8, 6), // SPACE INVADERS
+    (0xB7, 5), // GAME&WATCH
+    (0x86, 33), // DONKEYKONGLAND95
+    (0x9A, 25), // ASTEROIDS/MISCMD
+    (0x52, 42), // STREET FIGHTER 2
+    (0x01, 42), // DEFENDER/JOUST
+    (0x9D, 40), // KILLERINSTINCT95
+    (0x71, 2), // TETRIS BLAST
+    (0x9C, 16), // PINOCCHIO
+    (0xBD, 25),
+    (0x5D, 42), // BA.TOSHINDEN
+    (0x6D, 42), // NETTOU KOF 95
+    (0x67, 5),
+    (0x3F, 0), // TETRIS PLUS
+    (0x6B, 39), // DONKEYKONGLAND 3
+];
+
+/// Title sum, fourth letter of the title and combination, for sums more than one game has.
+const DUPLICATES: [(u8, u8, usize); 29] = [
+    (0xB3, b'B', 36),
+    (0x46, b'E', 22), // SUPER MARIOLAND
+    (0x28, b'F', 25), // GOLF
+    (0xA5, b'A', 6), // SOLARSTRIKER
+    (0xC6, b'A', 32), // GBWARS
+    (0xD3, b'R', 12), // KAERUNOTAMENI
+    (0x27, b'B', 36),
+    (0x61, b'E', 11), // POKEMON BLUE
+    (0x18, b'K', 39), // DONKEYKONGLAND
+    (0x66, b'E', 18), // GAMEBOY GALLERY2
+    (0x6A, b'K', 39), // DONKEYKONGLAND 2
+    (0xBF, b' ', 24), // KID ICARUS
+    (0x0D, b'R', 31), // TETRIS2
+    (0xF4, b'-', 50),
+    (0xB3, b'U', 17), // MOGURANYA
+    (0x46, b'R', 46),
+    (0x28, b'A', 6), // GALAGA&GALAXIAN
+    (0xA5, b'R', 27), // BT2RAGNAROKWORLD
+    (0xC6, b' ', 0), // KEN GRIFFEY JR
+    (0xD3, b'I', 47),
+    (0x27, b'N', 41), // MAGNETIC SOCCER
+    (0x61, b'A', 41), // VEGAS STAKES
+    (0x18, b'I', 0),
+    (0x66, b'L', 0), // MILLI/CENTI/PEDE
+    (0x6A, b'I', 19), // MARIO & YOSHI
+    (0xBF, b'C', 34), // SOCCER
+    (0x0D, b'E', 23), // POKEBOM
+    (0xF4, b' ', 18), // G&W GALLERY
+    (0xB3, b'R', 29), // TETRIS ATTACK
+];
+
+/// Four colours starting `start` colours into the palettes, as eight bits each of red,
+/// green and blue.
+fn colours(start: usize) -> [[u8; 3]; 4] {
+    let mut colours = [[0; 3]; 4];
+    for (i, colour) in colours.iter_mut().enumerate() {
+        let bgr = PALETTES[(start + i) / 4][(start + i) % 4];
+        for (c, channel) in colour.iter_mut().enumerate() {
+            let five = (bgr >> (c * 5)) as u32 & 0x1F;
+            *channel = ((five * 255 + 15) / 31) as u8;
+        }
+    }
+    colours
+}
+
+fn combination(index: usize) -> Pallets {
+    let (obp0, obp1, background) = COMBINATIONS[index];
+    [colours(background), colours(obp0), colours(obp1)]
+}
+
+/// The sum of the title, only for games Nintendo published.
+fn title_checksum(rom: &[u8]) -> Option<u8> {
+    if rom.len() < 0x150 {
+        return None;
+    }
+    let nintendo = rom[0x14B] == 0x01 || (rom[0x14B] == 0x33 && &rom[0x144..0x146] == b"01");
+    match nintendo {
+        true => Some(rom[0x134..0x144].iter().fold(0u8, |sum, b| sum.wrapping_add(*b))),
+        false => None,
+    }
+}
+
+/// Which combination the boot rom would pick for a cartrage.
+fn combination_for(rom: &[u8]) -> usize {
+    let sum = match title_checksum(rom) {
+        Some(sum) => sum,
+        None => return DEFAULT,
+    };
+    let unique = TITLES.iter().find(|(s, _)| *s == sum).map(|title| title.1);
+    let shared = || DUPLICATES.iter()
+        .find(|(s, letter, _)| *s == sum && *letter == rom[0x137])
+        .map(|title| title.2);
+    unique.or_else(shared).unwrap_or(DEFAULT)
+}
+
+/// What the boot rom would pick for a cartrage.
+pub fn for_cartrage(rom: &[u8]) -> Pallets {
+    combination(combination_for(rom))
+}
+
+/// The combination picked by the buttons held, if they pick one.
+pub fn for_buttons(held: &Buttons) -> Option<(&'static str, Pallets)> {
+    let bits = held.bits();
+    let direction = bits & 0xF0;
+    let button = bits & (A | B);
+    BUTTON_COMBINATIONS.iter()
+        .find(|(_, d, b, _)| *d == direction && *b == button)
+        .map(|(name, _, _, index)| (*name, combination(*index)))
+}
+
+#[cfg(test)]
+mod tests {
+    use gb::mem::Buttons;
+    use gb::mem::colourise::{combination, combination_for, for_buttons, for_cartrage, DEFAULT};
+
+    fn rom(title: &str, licensee: u8) -> Vec<u8> {
+        let mut rom = vec![0; 0x150];
+        rom[0x134..0x134 + title.len()].copy_from_slice(title.as_bytes());
+        rom[0x14B] = licensee;
+        rom
+    }
+
+    #[test]
+    fn titles_pick_palettes() {
+        assert_eq!(combination_for(&rom("TETRIS", 0x01)), 3);
+        assert_eq!(combination_for(&rom("ZELDA", 0x01)), 44);
+        assert_eq!(combination_for(&rom("POKEMON RED", 0x01)), 13);
+        assert_eq!(combination_for(&rom("POKEMON GREEN", 0x01)), 14);
+        // Same sum, told apart by the fourth letter.
+        assert_eq!(combination_for(&rom("POKEMON BLUE", 0x01)), 11);
+        assert_eq!(combination_for(&rom("VEGAS STAKES", 0x01)), 41);
+        assert_eq!(combination_for(&rom("SUPER MARIOLAND", 0x01)), 22);
+        assert_eq!(combination_for(&rom("KID ICARUS", 0x01)), 24);
+        assert_eq!(combination_for(&rom("POKFMON BLTE", 0x01)), DEFAULT);
+        // Only Nintendo's games are looked up, with either licensee code.
+        assert_eq!(combination_for(&rom("POKEMON RED", 0x08)), DEFAULT);
+        let mut new_licensee = rom("POKEMON RED", 0x33);
+        new_licensee[0x144..0x146].copy_from_slice(b"01");
+        assert_eq!(combination_for(&new_licensee), 13);
+    }
+
+    #[test]
+    fn combinations_have_the_boot_roms_colours() {
+        let white = [0xFF, 0xFF, 0xFF];
+        let black = [0, 0, 0];
+        // Tetris is the orange one that Down and A pick.
+        let orange = [white, [0xFF, 0xFF, 0x00], [0xFF, 0x00, 0x00], black];
+        assert_eq!(for_cartrage(&rom("TETRIS", 0x01)), [orange; 3]);
+        // Pokemon Red has a red background with green and red sprites.
+        let red = [white, [0xFF, 0x84, 0x84], [0x94, 0x3A, 0x3A], black];
+        let green = [white, [0x7B, 0xFF, 0x31], [0x00, 0x84, 0x00], black];
+        assert_eq!(for_cartrage(&rom("POKEMON RED", 0x01)), [red, green, red]);
+        // Super Mario Land's sprites start part way through a palette.
+        assert_eq!(combination(22)[1], [black, white, [0xFF, 0x84, 0x84], [0x94, 0x3A, 0x3A]]);
+        assert_eq!(for_cartrage(&rom("UNKNOWN", 0x01))[0],
+                   [white, [0x7B, 0xFF, 0x31], [0x00, 0x63, 0xC5], black]);
+    }
+
+    #[test]
+    fn buttons_pick_combinations() {
+        let held = Buttons { left: true, b: true, ..Buttons::default() };
+        assert_eq!(for_buttons(&held).unwrap().0, "grey");
+        let held = Buttons { right: true, ..Buttons::default() };
+        assert_eq!(for_buttons(&held).unwrap().0, "green");
+        let held = Buttons { right: true, a: true, ..Buttons::default() };
+        assert_eq!(for_buttons(&held).unwrap().1, combination(DEFAULT));
+        assert!(for_buttons(&Buttons { a: true, ..Buttons::default() }).is_none());
+        assert!(for_buttons(&Buttons::default()).is_none());
+    }
+}
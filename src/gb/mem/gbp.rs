@@ -1,13 +1,18 @@
-/// The gameboy black and white pallets
-/// located at memory FF47 to FF49
-/// Each contains four colours expressed as 2 bit values as follows:
-/// 0  White
-/// 1  Light gray
-/// 2  Dark gray
-/// 3  Black
-///
-/// Bits representation: 33221100
-/// Object pallets always contain transparent for colour 0.
+//! The gameboy black and white pallets
+//! located at memory FF47 to FF49
+//! Each contains four colours expressed as 2 bit values as follows:
+//! 0  White
+//! 1  Light gray
+//! 2  Dark gray
+//! 3  Black
+//!
+//! Bits representation: 33221100
+//! Object pallets always contain transparent for colour 0.
+//!
+//! Colourised, each pallet shows its four colours in colours of its own instead of greys.
+
+use super::colourise::Pallets;
+use gb::state::{Snapshot, State};
 
 macro_rules! copy3 {
     ($b:expr, $c:expr) => {
@@ -32,11 +37,13 @@ pub struct GBP {
     obp0: u8,
     /// GB second object pallet
     obp1: u8,
+    /// Colours for BGP, OBP0 and OBP1 when colourised.
+    pub colours: Option<Pallets>,
 }
 
 impl GBP {
     pub fn new() -> Self {
-        GBP {bgp: 0, obp0: 0, obp1: 0}
+        GBP {bgp: 0, obp0: 0, obp1: 0, colours: None}
     }
     fn mem_to_pallet(addr: u16) -> Option<Pallet> {
         match addr {
@@ -62,13 +69,27 @@ impl GBP {
         }
     }
 
-    pub fn apply(&self, p: Pallet, color: u8, buffer: &mut [u8]){
-        let p = match p { 
+    /// Which of the four shades a colour is through a pallet.
+    pub fn shade(&self, p: Pallet, color: u8) -> u8 {
+        let p = match p {
             Pallet::BGP => self.bgp,
             Pallet::OBP0 => self.obp0,
             Pallet::OBP1 => self.obp1,
         };
-        let c = (p >> (color * 2)) & 0x03;
+        (p >> (color * 2)) & 0x03
+    }
+
+    pub fn apply(&self, p: Pallet, color: u8, buffer: &mut [u8]){
+        let n = match p { 
+            Pallet::BGP => 0,
+            Pallet::OBP0 => 1,
+            Pallet::OBP1 => 2,
+        };
+        let c = self.shade(p, color);
+        if let Some(ref colours) = self.colours {
+            buffer.copy_from_slice(&colours[n][c as usize]);
+            return;
+        }
         match c {
             0 => copy3!(buffer, 255),
             1 => copy3!(buffer, 170),
@@ -79,6 +100,19 @@ impl GBP {
     }
 }
 
+impl State for GBP {
+    fn state(&mut self, snapshot: &mut Snapshot) {
+        snapshot.u8(&mut self.bgp);
+        snapshot.u8(&mut self.obp0);
+        snapshot.u8(&mut self.obp1);
+        snapshot.option(&mut self.colours, || [[[0; 3]; 4]; 3], |snapshot, pallets| {
+            for colour in pallets.iter_mut().flat_map(|pallet| pallet.iter_mut()) {
+                snapshot.bytes(colour);
+            }
+        });
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use gb::mem::gbp::Pallet;
@@ -86,7 +120,7 @@ mod tests {
 
     #[test]
     fn apply() {
-        let pallet = GBP {bgp: 0, obp0: 0b00011011, obp1: 0b00000000};
+        let pallet = GBP {bgp: 0, obp0: 0b00011011, obp1: 0b00000000, colours: None};
         let mut buffer = vec![0,0,0];
         pallet.apply(Pallet::OBP0, 2, &mut buffer[..]);
         assert_eq!(buffer[0], 170);
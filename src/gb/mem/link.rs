@@ -0,0 +1,365 @@
+//! The link cable, so two Game Boys can trade and battle.
+//!
+//! The end that writes 0x81 to SC clocks the transfer, the other end only takes part if it
+//! has written 0x80 to SC and is waiting for a clock.  Each emulator runs at its own pace,
+//! so both ends say every so often how many cycles they have run, and the one ahead waits
+//! once it gets too far ahead.  A transfer is then answered by the other end at the same
+//! point in its own time, give or take the window.
+
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Cycles a byte takes at the normal 8192Hz clock.
+pub const TRANSFER_CYCLES: u64 = 8 * 512;
+/// How often each end says how far it has got.
+const SYNC_CYCLES: u64 = 4096;
+/// How far one end can get ahead of the other before it waits.
+const WINDOW: u64 = 2 * SYNC_CYCLES;
+/// How long to wait for the other end before going on anyway, it may be paused.
+const PATIENCE: Duration = Duration::from_millis(500);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Message {
+    /// The sender has run this many cycles.
+    Sync(u64),
+    /// The sender clocked a byte out, starting at this cycle.
+    Transfer(u64, u8),
+    /// The byte that came back for the transfer started at this cycle.
+    Reply(u64, u8),
+}
+
+impl Message {
+    fn encode(&self) -> [u8; 10] {
+        let (tag, cycles, byte) = match *self {
+            Message::Sync(cycles) => (0, cycles, 0),
+            Message::Transfer(cycles, byte) => (1, cycles, byte),
+            Message::Reply(cycles, byte) => (2, cycles, byte),
+        };
+        let mut bytes = [0; 10];
+        bytes[0] = tag;
+        for i in 0..8 {
+            bytes[1 + i] = (cycles >> (i * 8)) as u8;
+        }
+        bytes[9] = byte;
+        bytes
+    }
+
+    fn decode(bytes: &[u8; 10]) -> Option<Message> {
+        let cycles = (0..8).fold(0, |cycles, i| cycles | (bytes[1 + i] as u64) << (i * 8));
+        match bytes[0] {
+            0 => Some(Message::Sync(cycles)),
+            1 => Some(Message::Transfer(cycles, bytes[9])),
+            2 => Some(Message::Reply(cycles, bytes[9])),
+            _ => None,
+        }
+    }
+}
+
+/// Carries messages to and from the other end.
+pub trait Link: Send {
+    fn send(&mut self, message: Message) -> Result<(), String>;
+    /// The next message, waiting up to `wait` for one to come.
+    fn receive(&mut self, wait: Duration) -> Result<Option<Message>, String>;
+}
+
+/// Messages as they come in, read on their own thread so receiving never blocks for
+/// longer than asked.
+fn receive_from(incoming: &mpsc::Receiver<Message>, wait: Duration)
+    -> Result<Option<Message>, String> {
+    match incoming.recv_timeout(wait) {
+        Ok(message) => Ok(Some(message)),
+        Err(mpsc::RecvTimeoutError::Timeout) => Ok(None),
+        Err(mpsc::RecvTimeoutError::Disconnected) => Err("the other end is gone".to_string()),
+    }
+}
+
+/// Another emulator over TCP.
+pub struct TcpLink {
+    stream: TcpStream,
+    incoming: mpsc::Receiver<Message>,
+}
+
+impl TcpLink {
+    /// Connect to the other end at `address`, or if it is not there yet, listen there and
+    /// wait for it to connect.  Both ends can then be started the same way.
+    pub fn connect(address: &str) -> Result<TcpLink, String> {
+        let error = |e: ::std::io::Error| format!("Link cable {}: {}", address, e);
+        let stream = match TcpStream::connect(address) {
+            Ok(stream) => stream,
+            Err(_) => match TcpListener::bind(address) {
+                Ok(listener) => {
+                    println!("Waiting for the other end of the link cable on {}", address);
+                    listener.accept().map_err(error)?.0
+                }
+                // Both started at once and the other end got to listen first.
+                Err(_) => TcpStream::connect(address).map_err(error)?,
+            },
+        };
+        let link = TcpLink::from_stream(stream).map_err(error)?;
+        println!("Link cable connected to {}", address);
+        Ok(link)
+    }
+
+    /// Start reading what comes in over a connected stream.
+    fn from_stream(stream: TcpStream) -> Result<TcpLink, ::std::io::Error> {
+        stream.set_nodelay(true)?;
+        let mut reader = stream.try_clone()?;
+        let (to_link, incoming) = mpsc::channel();
+        thread::Builder::new().name("Link".to_string()).spawn(move || {
+            let mut bytes = [0; 10];
+            while reader.read_exact(&mut bytes).is_ok() {
+                match Message::decode(&bytes) {
+                    Some(message) if to_link.send(message).is_ok() => {}
+                    _ => break,
+                }
+            }
+        })?;
+        Ok(TcpLink { stream, incoming })
+    }
+}
+
+impl Link for TcpLink {
+    fn send(&mut self, message: Message) -> Result<(), String> {
+        self.stream.write_all(&message.encode()).map_err(|e| e.to_string())
+    }
+
+    fn receive(&mut self, wait: Duration) -> Result<Option<Message>, String> {
+        receive_from(&self.incoming, wait)
+    }
+}
+
+impl Drop for TcpLink {
+    /// Lets the reading thread finish, and the other end know.
+    fn drop(&mut self) {
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+}
+
+/// Both ends in the same process, for testing.
+#[cfg(test)]
+pub struct Wire {
+    to: mpsc::Sender<Message>,
+    incoming: mpsc::Receiver<Message>,
+}
+
+#[cfg(test)]
+impl Wire {
+    pub fn pair() -> (Wire, Wire) {
+        let (to_a, from_b) = mpsc::channel();
+        let (to_b, from_a) = mpsc::channel();
+        (Wire { to: to_b, incoming: from_b }, Wire { to: to_a, incoming: from_a })
+    }
+}
+
+#[cfg(test)]
+impl Link for Wire {
+    fn send(&mut self, message: Message) -> Result<(), String> {
+        self.to.send(message).map_err(|_| "the other end is gone".to_string())
+    }
+
+    fn receive(&mut self, wait: Duration) -> Result<Option<Message>, String> {
+        receive_from(&self.incoming, wait)
+    }
+}
+
+/// One end of the cable as the serial port sees it, keeping time with the other end.
+/// It goes with the link over resets and reloads, so the two ends stay in step.
+pub struct Cable {
+    link: Option<Box<dyn Link>>, // None once the other end is gone.
+    cycles: u64, // Cycles run at this end.
+    other: u64, // Cycles the other end last said it had run.
+    synced: u64, // When this end last said.
+    clocked: VecDeque<(u64, u8)>, // Transfers the other end clocked, not yet answered.
+    reply: Option<(u64, u8)>,
+    // Off once the other end has kept this one waiting too long, so a paused emulator
+    // does not hold this one up again on every byte, until it says something.
+    patient: bool,
+}
+
+impl Cable {
+    pub fn new(link: Box<dyn Link>) -> Cable {
+        Cable {
+            link: Some(link),
+            cycles: 0,
+            other: 0,
+            synced: 0,
+            clocked: VecDeque::new(),
+            reply: None,
+            patient: true,
+        }
+    }
+
+    pub fn connected(&self) -> bool {
+        self.link.is_some()
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    fn send(&mut self, message: Message) {
+        let sent = match self.link {
+            Some(ref mut link) => link.send(message),
+            None => return,
+        };
+        if let Err(e) = sent {
+            self.disconnect(e);
+        }
+    }
+
+    /// Take in what the other end has sent, waiting up to `wait` for the first message.
+    fn receive(&mut self, wait: Duration) {
+        let mut wait = wait;
+        loop {
+            let received = match self.link {
+                Some(ref mut link) => link.receive(wait),
+                None => return,
+            };
+            if let Ok(Some(_)) = received {
+                self.patient = true;
+            }
+            match received {
+                Ok(Some(Message::Sync(cycles))) => self.other = cycles,
+                Ok(Some(Message::Transfer(start, byte))) => self.clocked.push_back((start, byte)),
+                Ok(Some(Message::Reply(start, byte))) => self.reply = Some((start, byte)),
+                Ok(None) => return,
+                Err(e) => return self.disconnect(e),
+            }
+            wait = Duration::from_secs(0);
+        }
+    }
+
+    fn disconnect(&mut self, reason: String) {
+        eprintln!("Link cable disconnected: {}", reason);
+        self.link = None;
+    }
+
+    fn say_how_far(&mut self) {
+        self.synced = self.cycles;
+        let cycles = self.cycles;
+        self.send(Message::Sync(cycles));
+    }
+
+    /// Let `time` cycles pass, waiting for the other end if this one is too far ahead.
+    /// Messages are only looked at when `poll` is set or it is time to sync, to keep it
+    /// cheap when nothing is going on.
+    pub fn run(&mut self, time: u64, poll: bool) {
+        self.cycles += time;
+        let syncing = self.cycles >= self.synced + SYNC_CYCLES;
+        if syncing {
+            self.say_how_far();
+            let since = Instant::now();
+            while self.connected() && self.cycles > self.other + WINDOW {
+                match self.patience(since) {
+                    Some(wait) => self.receive(wait),
+                    None => break,
+                }
+            }
+        }
+        if poll || syncing {
+            self.receive(Duration::from_secs(0));
+        }
+    }
+
+    /// How much longer to wait for the other end, having waited since `since`.
+    fn patience(&mut self, since: Instant) -> Option<Duration> {
+        let wait = match self.patient {
+            true => PATIENCE.checked_sub(since.elapsed()),
+            false => None,
+        };
+        self.patient = wait.is_some();
+        wait
+    }
+
+    /// Clock `byte` out to the other end, returning when the transfer started.
+    pub fn start(&mut self, byte: u8) -> u64 {
+        let start = self.cycles;
+        self.send(Message::Transfer(start, byte));
+        start
+    }
+
+    /// A transfer the other end clocked that has now finished at this end.
+    pub fn due(&mut self) -> Option<(u64, u8)> {
+        match self.clocked.front() {
+            Some(&(start, _)) if start + TRANSFER_CYCLES <= self.cycles => self.clocked.pop_front(),
+            _ => None,
+        }
+    }
+
+    /// Send back the byte shifted out for a transfer the other end clocked.
+    pub fn answer(&mut self, start: u64, byte: u8) {
+        self.send(Message::Reply(start, byte));
+    }
+
+    /// Wait for the byte that came back for the transfer started at `start`, 0xFF if it
+    /// never comes.  This end is clocking, so it is not ready for transfers the other end
+    /// clocks meanwhile, those get `sb` back as it is.
+    pub fn reply(&mut self, start: u64, sb: u8) -> u8 {
+        self.say_how_far();
+        let since = Instant::now();
+        let mut waiting = true;
+        loop {
+            while let Some((other_start, _)) = self.clocked.pop_front() {
+                self.answer(other_start, sb);
+            }
+            match self.reply {
+                Some((replied, byte)) if replied == start => return byte,
+                _ if !waiting => return 0xFF,
+                _ => {}
+            }
+            // Out of patience there is still one last look at what has come.
+            let wait = self.patience(since);
+            waiting = wait.is_some() && self.connected();
+            self.receive(wait.unwrap_or_default());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use gb::mem::link::{Cable, Link, Message, TcpLink, Wire, PATIENCE};
+
+    #[test]
+    fn messages_cross_tcp() {
+        // Whatever port is free, so tests running at once do not fight over one.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let other = thread::spawn(move || {
+            let mut link = TcpLink::connect(&address).unwrap();
+            link.send(Message::Transfer(0x1_2345_6789, 0xA5)).unwrap();
+            link.receive(Duration::from_secs(5)).unwrap()
+        });
+        let mut link = TcpLink::from_stream(listener.accept().unwrap().0).unwrap();
+        assert_eq!(link.receive(Duration::from_secs(5)).unwrap(),
+                   Some(Message::Transfer(0x1_2345_6789, 0xA5)));
+        link.send(Message::Reply(7, 0x3C)).unwrap();
+        assert_eq!(other.join().unwrap(), Some(Message::Reply(7, 0x3C)));
+        // The other end dropped its link when its thread finished.
+        assert!(link.receive(Duration::from_secs(5)).is_err());
+    }
+
+    #[test]
+    fn a_quiet_end_is_only_waited_for_once() {
+        let (wire, mut other) = Wire::pair();
+        let mut cable = Cable::new(Box::new(wire));
+        let since = Instant::now();
+        assert_eq!(cable.reply(0, 0), 0xFF);
+        assert!(since.elapsed() >= PATIENCE);
+
+        let since = Instant::now();
+        assert_eq!(cable.reply(1, 0), 0xFF);
+        assert!(since.elapsed() < PATIENCE / 2, "Waited again for an end that is not there");
+
+        // Once it says something it is waited for again.
+        other.send(Message::Reply(2, 0x42)).unwrap();
+        assert_eq!(cable.reply(2, 0), 0x42);
+        assert!(cable.patient);
+    }
+}
@@ -1,19 +1,30 @@
-use std::fs::File;
-use std::io::Read;
+use std::fs;
 use std::cmp::min;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU8, Ordering};
 
 use ::{GAMEBOY_WIDTH, GAMEBOY_HEIGHT};
+use gb::GbKind;
+use gb::state::{Snapshot, State};
 
 mod ppu;
 mod gbp;
 mod timer;
 mod cm;
+mod cgb;
+mod colourise;
+pub mod sgb;
+pub mod link;
 use self::cm::CartrageMapper;
+use self::link::Cable;
 
+const KB_4: usize = 0x1000;
 const KB_8: usize = 0x2000;
 const KB_8_MASK: usize = 0x1FFF;
 const GAMEBOY_SCREEN_BUFFER_SIZE: u32 = GAMEBOY_WIDTH * GAMEBOY_HEIGHT * 3;
+const CYCLES_PER_FRAME: usize = 456 * 154;
 
+#[derive(Clone, Copy, Default)]
 pub struct Buttons {
     pub a: bool,
     pub b: bool,
@@ -43,28 +54,80 @@ impl Buttons {
         if self.right { res |= 0x1; }
         (!res & 0xF)
     }
+
+    /// Pressed buttons as bits, the d-pad high and the others low.
+    fn bits(&self) -> u8 {
+        (!self.dpad() & 0xF) << 4 | (!self.buttons() & 0xF)
+    }
+
+    fn from_bits(bits: u8) -> Buttons {
+        let bit = |n: u8| bits & (1 << n) > 0;
+        Buttons {
+            down: bit(7), up: bit(6), left: bit(5), right: bit(4),
+            start: bit(3), select: bit(2), b: bit(1), a: bit(0),
+        }
+    }
+}
+
+/// The buttons held right now.  Main sets them as they change and the gameboy looks at
+/// them every M-cycle, so a game sees a press at the time it happens, not the next frame.
+#[derive(Clone, Default)]
+pub struct SharedButtons(Arc<AtomicU8>);
+
+impl SharedButtons {
+    pub fn set(&self, buttons: Buttons) {
+        self.0.store(buttons.bits(), Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> Buttons {
+        Buttons::from_bits(self.0.load(Ordering::Relaxed))
+    }
 }
 
-use std::iter;
+/// The 8 colours of a row of a tile, read straight from a vram bank.
 macro_rules! get_sprite {
-    ($map:expr, $sprite_index:expr, $row:expr) => {
-        (0..8).rev()
-            .zip(iter::repeat($map.read(0x8000 + $sprite_index * 16 + $row * 2).unwrap()))
-            .zip(iter::repeat($map.read(0x8000 + $sprite_index * 16 + $row * 2 + 1).unwrap()))
-            .map(|((val, low), high)| {
+    ($vram:expr, $bank:expr, $sprite_index:expr, $row:expr) => {{
+        let addr = $bank * KB_8 + $sprite_index as usize * 16 + $row as usize * 2;
+        let (low, high) = ($vram[addr], $vram[addr + 1]);
+        (0..8).rev().map(move |val| {
             let (low, high) = (((low >> val) & 0x01) > 0, ((high >> val) & 0x01) > 0);
             (high as u8) * 2 + (low as u8)
         })
-    }
+    }}
 }
 
 pub trait MemMapper {
     fn read(&self, addr: u16) -> Option<u8>;
+    fn rom_offset(&self, addr: u16) -> Option<usize>;
     fn write(&mut self, addr: u16, data: u8) -> bool;
     fn time_passes(&mut self, time: usize) -> Option<Vec<u8>>;
-    fn update_input(&mut self, buttons: Buttons);
-    fn check_interupt(&mut self, ime: bool) -> Option<u16>;
+    fn dma_active(&self) -> bool;
+    fn kind(&self) -> GbKind;
+    /// STOP asked for a speed switch, which happens instead of stopping.
+    fn switch_speed(&mut self) -> bool;
+    /// Show a monochrome game in colour, picked by the buttons held or the cartrage.
+    fn colourise(&mut self, held: &Buttons);
+    /// The Super Game Boy border, 256 by 224, to draw the screen in the middle of.
+    fn border(&self) -> Option<&[u8]>;
+    fn connect_input(&mut self, input: SharedButtons);
+    fn connect_link(&mut self, cable: Cable);
+    fn take_link(&mut self) -> Option<Cable>;
+    fn reset(&mut self);
+    fn flush_save(&self);
+    fn ram_snapshot(&self) -> (Vec<u8>, Vec<u8>);
+    /// The cartrage rom, which a save state has to be loaded with.
+    fn rom(&self) -> &[u8];
+    fn restore_ram(&mut self, wram: &[u8], cartrage_ram: &[u8]);
+    /// Look at the joypad lines, which still happens while everything else is stopped.
+    fn update_joypad(&mut self);
+    fn take_joypad_edge(&mut self) -> bool;
+    /// Save or load everything but the roms.
+    fn state(&mut self, snapshot: &mut Snapshot);
+    fn pending_interupts(&self) -> u8;
+    fn clear_interupt(&mut self, mask: u8);
     fn render(&self, row: u8, buffer: &mut [u8]);
+    #[cfg(test)]
+    fn serial_log(&self) -> &[u8];
     fn print_background_map(&self);
     fn print_sprite_table(&self);
 }
@@ -152,11 +215,14 @@ impl Oam {
         self.data[e].write(n, data)
     }
 
+    /// Colour, pallet and priority of the sprite on top at each pixel of a line.
+    /// The pallet is OBP0 or OBP1 as 0 or 1, or a colour pallet in colour.
     fn sprite_line(&self,
-                   map: &MemMapper,
+                   vram: &[u8],
                    scanline: u8,
-                   is_8_by_16: bool) -> Vec<Option<(u8, bool, bool)>> {
-        let mut line = vec![None; GAMEBOY_WIDTH as usize];
+                   is_8_by_16: bool,
+                   colour: bool) -> [Option<(u8, u8, bool)>; GAMEBOY_WIDTH as usize] {
+        let mut line = [None; GAMEBOY_WIDTH as usize];
         let scanline = scanline + 9; // First 8 lines are not used
 
         let mut sprites: Vec<_> = self.data.iter()
@@ -167,14 +233,16 @@ impl Oam {
                     } && obj.y < scanline + 8
             )
             .collect();
-        sprites.sort_by(|obj, obj2| obj.x.cmp(&obj2.x));
+        // In colour the first in oam is on top, otherwise the leftmost.
+        if !colour {
+            sprites.sort_by(|obj, obj2| obj.x.cmp(&obj2.x));
+        }
         sprites.iter().take(10)
             .for_each(|obj| {
                 let depth = match is_8_by_16 {
                     false => obj.y.wrapping_sub(scanline),
                     true => obj.y.wrapping_add(8).wrapping_sub(scanline),
                 };
-                println!("D: {}", depth);
                 assert!(depth < 16);
                 let yline = match obj.read_artibute(OamAtribute::YFlip) {
                     false => {
@@ -192,8 +260,15 @@ impl Oam {
                             true => obj.t & 0xFE,
                         } as u16;
 
-                let sprite = get_sprite!(map, sprite_index, yline);
-                let pallet = obj.read_artibute(OamAtribute::Pallet);
+                let bank = match colour {
+                    true => (obj.a >> 3 & 0x01) as usize,
+                    false => 0,
+                };
+                let sprite = get_sprite!(vram, bank, sprite_index, yline);
+                let pallet = match colour {
+                    true => obj.a & 0x07,
+                    false => obj.read_artibute(OamAtribute::Pallet) as u8,
+                };
                 let priority = obj.read_artibute(OamAtribute::Priority);
 
                 for (offset, color) in (0..8).zip(sprite) {
@@ -213,6 +288,16 @@ impl Oam {
     }
 }
 
+impl State for Oam {
+    fn state(&mut self, snapshot: &mut Snapshot) {
+        for entry in self.data.iter_mut() {
+            let mut bytes = [entry.y, entry.x, entry.t, entry.a];
+            snapshot.bytes(&mut bytes);
+            *entry = OamEntry { y: bytes[0], x: bytes[1], t: bytes[2], a: bytes[3] };
+        }
+    }
+}
+
 /*
 DUMMY
 */
@@ -222,6 +307,12 @@ struct Serial {
     transfer_tick: usize,
     buffered_interupt: bool,
     out: u8,
+    /// Every byte sent so far.
+    log: Vec<u8>,
+    log_to_file: bool,
+    /// The other Game Boy, if one is plugged in.
+    cable: Option<Cable>,
+    started: Option<u64>, // When a transfer this end is clocking over the cable started.
 }
 
 use std::fs::OpenOptions;
@@ -230,15 +321,16 @@ const SERIAL_FILE: &str = "serial.log";
 impl Serial {
 
     pub fn new() -> Self {
-        let mut f = OpenOptions::new().create(true).append(true).open(SERIAL_FILE).unwrap();
-        write!(f,"Serial Log:");
-
         Serial {
             sb: 0x00,
             sc: 0x00,
             transfer_tick: 0,
             buffered_interupt: false,
             out: 0xFF,
+            log: vec![],
+            log_to_file: true,
+            cable: None,
+            started: None,
         }
     }
 
@@ -259,6 +351,12 @@ impl Serial {
                 if self.sc & 0x80 > 0 {
                     self.transfer_tick = 8;
                     self.out = 0xFF;
+                    match self.cable {
+                        Some(ref mut cable) if self.sc & 0x01 > 0 => {
+                            self.started = Some(cable.start(self.sb));
+                        }
+                        _ => {}
+                    }
                 }
                 true
             },
@@ -267,6 +365,15 @@ impl Serial {
     }
 
     pub fn tick(&mut self, time: usize) {
+        if let Some(mut cable) = self.cable.take() {
+            self.tick_linked(&mut cable, time);
+            match cable.connected() {
+                true => self.cable = Some(cable),
+                // Carries on as if the cable was never there.
+                false => self.started = None,
+            }
+            return;
+        }
         if self.sc & 0x80 > 0 {
             if self.sc & 0x01 > 0 {
                 let to_shift = min(self.transfer_tick, time);
@@ -290,12 +397,47 @@ impl Serial {
                 */
             }
             if self.transfer_tick == 0 {
-                self.sc &= 0x7F; // clear transfer bit.
-                let mut f = OpenOptions::new().append(true).open(SERIAL_FILE).unwrap();
-                write!(f,"{}", self.out as char);
-                self.buffered_interupt = true;
+                let (out, to_file) = (self.out, self.log_to_file);
+                self.finish(out, to_file);
+            }
+        }
+    }
+
+    /// Transfers over the cable take as long as they would on the hardware, the other end
+    /// answers one this end clocks once it gets to the same point.
+    fn tick_linked(&mut self, cable: &mut Cable, time: usize) {
+        cable.run(time as u64, self.sc & 0x80 > 0);
+        while let Some((start, byte)) = cable.due() {
+            let out = self.sb;
+            cable.answer(start, out);
+            // Only taken in when waiting for the other end's clock.
+            if self.sc & 0x81 == 0x80 {
+                self.sb = byte;
+                self.finish(out, false);
+            }
+        }
+        if let Some(start) = self.started {
+            if cable.cycles() >= start + link::TRANSFER_CYCLES {
+                self.started = None;
+                let out = self.sb;
+                self.sb = cable.reply(start, out);
+                self.finish(out, false);
+            }
+        }
+    }
+
+    /// A byte has gone out.  Bytes that went over the cable are not written to the file.
+    fn finish(&mut self, out: u8, to_file: bool) {
+        self.sc &= 0x7F; // clear transfer bit.
+        self.log.push(out);
+        if to_file {
+            let mut f = OpenOptions::new().create(true).append(true).open(SERIAL_FILE).unwrap();
+            if self.log.len() == 1 {
+                write!(f,"Serial Log:");
             }
+            write!(f,"{}", out as char);
         }
+        self.buffered_interupt = true;
     }
 
     pub fn check_interupt(&mut self) -> u8 {
@@ -308,49 +450,119 @@ impl Serial {
 
 }
 
+/// Only the port itself, the log is what was sent and the cable belongs to the session.
+impl State for Serial {
+    fn state(&mut self, snapshot: &mut Snapshot) {
+        snapshot.u8(&mut self.sb);
+        snapshot.u8(&mut self.sc);
+        snapshot.usize(&mut self.transfer_tick);
+        snapshot.bool(&mut self.buffered_interupt);
+        snapshot.u8(&mut self.out);
+    }
+}
+
 pub struct Mem {
-    map_holder: Box<MemMapper>,
-    screen: Box<[u8; GAMEBOY_SCREEN_BUFFER_SIZE as usize]>,
+    map_holder: Box<MemMapper + Send>, // Built before the gameboy thread starts.
+    screen: Box<[u8]>,
     ime: bool,
+    cycles: usize,
+    behind: usize, // Cycles the cpu has run that the rest has not caught up with.
+    frame_ready: bool,
+    stopped: Option<usize>, // Cycles into the current blank frame.
+    skip_render: bool,
+    #[cfg(test)]
+    bus: Option<Vec<(usize, Access)>>, // Cpu accesses and the M-cycle they were in.
+}
+
+/// A cpu read or write on the bus.
+#[cfg(test)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Access {
+    Read(u16, u8),
+    Write(u16, u8),
+}
+
+/// What a 256 byte page of the address space reads from.
+#[derive(Clone, Copy)]
+enum Page {
+    Rom(usize), // Offset into the cartrage rom.
+    Vram(usize),
+    Wram(usize),
+    Slow, // Boot rom, cartrage ram, oam and io go through the full memory map.
 }
 
 pub struct GbMapper {
     cartrage: CartrageMapper,
     boot_rom: BootRom,
-    vram: [u8; KB_8],
-    wram: [u8; KB_8],
+    vram: [u8; KB_8 * 2], // Bank 1 is only used in colour.
+    wram: [u8; KB_4 * 8], // So are banks 2 to 7.
     boot: bool,
     oam: Oam,
     serial: Serial,
-    buttons: Buttons,
-    joypad: u8,
+    input: SharedButtons,
+    joypad: u8, // Which lines are selected, in the bits 0xFF00 has them.
+    lines_seen: u8, // The joypad lines as last seen, to catch them falling.
+    joypad_edge: bool,
     timer: timer::Timer,
     hram: [u8; 127],
     interupt_enable: u8,
     interupt_flag: u8,
     ppu: ppu::PPU,
     gbp: gbp::GBP,
+    dma: Option<(u16, u16)>, // Source and bytes copied so far.
+    pages: [Page; 256],
+    cgb: Option<cgb::Cgb>, // Only running in colour.
+    sgb: Option<sgb::Sgb>, // Only running on a Super Game Boy.
 }
 
 impl GbMapper {
-    pub fn new(cartrage: String) -> Self {
+    pub fn new(cartrage: String) -> Result<Self, String> {
+        Ok(GbMapper::with_parts(CartrageMapper::new(cartrage)?, BootRom::new(vec![])))
+    }
+
+    pub fn new_with_boot_rom(boot_rom: String, cartrage: String) -> Result<Self, String> {
+        let buffer = fs::read(&boot_rom).map_err(|e| format!("{}: {}", boot_rom, e))?;
+        println!("Boot rom loaded: {:X} bytes long", buffer.len());
+
+        Ok(GbMapper::with_parts(CartrageMapper::new(cartrage)?, BootRom::new(buffer)))
+    }
+
+    /// Everything starts fresh around the cartrage.  Without a boot rom the io registers
+    /// are left how the boot rom would have left them.
+    /// Cartrages made for the Game Boy Color run in colour.
+    fn with_parts(cartrage: CartrageMapper, boot_rom: BootRom) -> Self {
+        let boot = boot_rom.rom.is_empty();
+        let cgb = match cartrage.kind() {
+            GbKind::GBC => Some(cgb::Cgb::new()),
+            _ => None,
+        };
         let mut mapper = GbMapper {
-            cartrage: CartrageMapper::new(cartrage),
-            boot_rom: BootRom::new(vec![]),
-            vram: [0; KB_8],
-            wram: [0; KB_8],
-            boot: true,
+            cartrage,
+            boot_rom,
+            vram: [0; KB_8 * 2],
+            wram: [0; KB_4 * 8],
+            boot,
             oam: Oam::new(),
             serial: Serial::new(),
-            buttons: Buttons {a:false,b:false,start:false,select:false,up:false,down:false,left:false,right:false},
+            input: SharedButtons::default(),
             joypad: 0,
+            lines_seen: 0x0F,
+            joypad_edge: false,
             timer: timer::Timer::new(),
             hram: [0; 127],
             interupt_enable: 0,
             interupt_flag: 0,
             ppu: ppu::PPU::new(),
             gbp: gbp::GBP::new(),
+            dma: None,
+            pages: [Page::Slow; 256],
+            cgb,
+            sgb: None,
         };
+        mapper.map_pages();
+        if !boot {
+            return mapper;
+        }
         mapper.write(0xFF10, 0x80);
         mapper.write(0xFF11, 0xBF);
         mapper.write(0xFF12, 0xF3);
@@ -379,164 +591,376 @@ impl GbMapper {
         mapper
     }
 
-    pub fn new_with_boot_rom(boot_rom: String, cartrage: String) -> Self {
-        let mut buffer = Vec::new();
-        let boot_size = File::open(boot_rom)
-            .unwrap()
-            .read_to_end(&mut buffer)
-            .unwrap();
-        println!("Boot rom loaded: {:X} bytes long", boot_size);
-
-        GbMapper {
-            cartrage: CartrageMapper::new(cartrage),
-            boot_rom: BootRom::new(buffer),
-            vram: [0; KB_8],
-            wram: [0; KB_8],
-            boot: false,
-            buttons: Buttons {a:false,b:false,start:false,select:false,up:false,down:false,left:false,right:false},
-            joypad: 0,
-            timer: timer::Timer::new(),
-            oam: Oam::new(),
-            serial: Serial::new(),
-            hram: [0; 127],
-            interupt_enable: 0,
-            interupt_flag: 0,
-            ppu: ppu::PPU::new(),
-            gbp: gbp::GBP::new(),
+
+    /// Run games that support it as on a Super Game Boy.
+    pub fn with_sgb(mut self) -> Self {
+        self.enable_sgb();
+        self
+    }
+
+    fn enable_sgb(&mut self) {
+        if self.cgb.is_none() && self.cartrage.supports_sgb() {
+            self.sgb = Some(sgb::Sgb::new());
         }
     }
 
-    fn dma(&mut self, data: u8) -> bool{
-        let start = (data as u16) << 8;
-        for offset in 0..0x9F {
-            let data = self.read(start + offset).unwrap();
-            self.write(0xFE00 + offset, data);
+    /// Keep serial output in memory only, instead of also appending it to serial.log.
+    #[cfg(test)]
+    pub fn without_serial_file(mut self) -> Self {
+        self.serial.log_to_file = false;
+        self
+    }
+
+    /// The colour boot rom is also mapped from 0200 to 08FF, after the cartrage header.
+    fn boot_rom_mapped(&self, addr: u16) -> bool {
+        !self.boot && (addr < 0x0100 || ((0x0200..0x0900).contains(&addr) &&
+                                         self.boot_rom.rom.len() > 0x0100))
+    }
+
+    /// Where in vram an address is, given the bank in use.
+    fn vram_offset(&self, addr: u16) -> usize {
+        let bank = self.cgb.as_ref().map_or(0, |cgb| cgb.vram_bank);
+        bank * KB_8 + (addr as usize & KB_8_MASK)
+    }
+
+    /// Where in work ram an address is.  C000 is always bank 0, D000 is bank 1 or in colour
+    /// whichever is picked.
+    fn wram_offset(&self, addr: u16) -> usize {
+        let addr = addr as usize & KB_8_MASK;
+        match addr < KB_4 {
+            true => addr,
+            false => self.cgb.as_ref().map_or(1, |cgb| cgb.wram_bank) * KB_4 + addr - KB_4,
+        }
+    }
+
+    /// Point the pages at what is mapped now.  Needed after a bank switch or the boot rom
+    /// being turned off.
+    fn map_pages(&mut self) {
+        for page in 0..0x100 {
+            let addr = (page << 8) as u16;
+            self.pages[page] = match addr {
+                0x0000..=0x7FFF if self.boot_rom_mapped(addr) => Page::Slow,
+                0x0000..=0x7FFF => Page::Rom(self.cartrage.rom_offset(addr)),
+                0x8000..=0x9FFF => Page::Vram(self.vram_offset(addr)),
+                0xC000..=0xFDFF => Page::Wram(self.wram_offset(addr)),
+                _ => Page::Slow,
+            }
+        }
+    }
+
+    /// The selected button lines as they are now, low when pressed.
+    fn joypad_lines(&self) -> u8 {
+        // The other controllers of a Super Game Boy have nothing plugged in, and with
+        // none selected the lines say which one is being read.
+        if let Some(ref sgb) = self.sgb {
+            match (sgb.multiplayer(), self.joypad & 0x30) {
+                (true, 0x30) => return 0x0F - sgb.player(),
+                _ if sgb.player() > 0 => return 0x0F,
+                _ => {}
+            }
+        }
+        let buttons = self.input.get();
+        let mut lines = 0x0F;
+        if self.joypad & 0x10 == 0 {
+            lines &= buttons.dpad();
+        }
+        if self.joypad & 0x20 == 0 {
+            lines &= buttons.buttons();
+        }
+        lines
+    }
+
+    fn select_joypad(&mut self, data: u8) {
+        if let Some(ref mut sgb) = self.sgb {
+            sgb.write_joypad(data);
         }
+        self.joypad = data & 0x30; // Only control bits.
+        self.update_joypad();
+    }
+
+    fn dma(&mut self, data: u8) -> bool{
+        self.dma = Some(((data as u16) << 8, 0));
         true
     }
 
+    /// Copy one byte of a running dma, one M-cycle's worth.
+    fn dma_tick(&mut self) {
+        if let Some((start, offset)) = self.dma {
+            let data = self.read(start + offset).unwrap_or(0xFF);
+            self.oam.write(0xFE00 + offset, data);
+            self.dma = if offset < 0x9F { Some((start, offset + 1)) } else { None };
+        }
+    }
+
+    /// Copy 16 bytes of a colour hdma into vram, moving the source and destination on.
+    fn hdma_block(&mut self) {
+        let (source, dest) = match self.cgb {
+            Some(ref cgb) => (cgb.hdma_source, cgb.hdma_dest),
+            None => return,
+        };
+        for i in 0..16 {
+            let data = self.read(source.wrapping_add(i)).unwrap_or(0xFF);
+            let offset = self.vram_offset(0x8000 | (dest + i));
+            self.vram[offset] = data;
+        }
+        if let Some(ref mut cgb) = self.cgb {
+            cgb.hdma_source = source.wrapping_add(16);
+            cgb.hdma_dest = (dest + 16) & 0x1FF0;
+        }
+    }
+
+    /// The 4K a Super Game Boy transfer sends, the first 256 tiles of the background as
+    /// shown, left to right and top to bottom.
+    fn sgb_transfer(&mut self) {
+        if self.sgb.as_ref().is_none_or(|sgb| sgb.transfer.is_none()) {
+            return;
+        }
+        let map_offset = match self.ppu.lcdc_get(3) {
+            true => 0x1C00,
+            false => 0x1800,
+        };
+        let mut data = vec![0; 4096];
+        for (tile, bytes) in data.chunks_mut(16).enumerate() {
+            let map_data = self.vram[map_offset + (tile / 20) * 32 + tile % 20];
+            let index = match self.ppu.lcdc_get(4) {
+                true => map_data as usize,
+                false => (256 + ((map_data as i8) as isize)) as usize,
+            };
+            bytes.copy_from_slice(&self.vram[index * 16..index * 16 + 16]);
+        }
+        self.sgb.as_mut().unwrap().transfer_done(&data);
+    }
+
+    /// A running hblank hdma copies a block as each visible row finishes.
+    fn hdma_hblank(&mut self, rows: &[u8]) {
+        for _ in rows.iter().filter(|row| **row < GAMEBOY_HEIGHT as u8) {
+            if self.cgb.as_ref().is_some_and(|cgb| cgb.hdma.is_some()) {
+                self.hdma_block();
+                self.cgb.as_mut().unwrap().hdma_done();
+            }
+        }
+    }
+
 }
 
 impl MemMapper for GbMapper {
     fn read(&self, addr: u16) -> Option<u8> {
-        // Main table
-        match addr {
-            0x0000...0x00FF => if !self.boot { self.boot_rom.read(addr) } else { self.cartrage.read(addr) }
-            0x0000...0x7FFF => self.cartrage.read(addr),
-            0x8000...0x9FFF => Some(self.vram[addr as usize & KB_8_MASK]),
-            0xA000...0xBFFF => self.cartrage.read_ram(addr),
-            0xC000...0xDFFF => Some(self.wram[addr as usize & KB_8_MASK]),
-            0xE000...0xFDFF => Some(self.wram[addr as usize & KB_8_MASK]),
-            0xFE00...0xFE9F => self.oam.read(addr),
-            // 0xFEA0...0xFEFF Not Used by anything.
-            0xFF00 => Some(self.joypad), // Joypad
-            0xFF01...0xFF02 => self.serial.read(addr),
-            0xFF04...0xFF07 => self.timer.read(addr),
-            0xFF0F => Some(self.interupt_flag),
-            0xFF10...0xFF3F => Some(0xFF), // Audio device not implemented.
-            0xFF40...0xFF45 => self.ppu.read(addr), // PPU state
-            0xFF47...0xFF49 => self.gbp.read(addr), // Pallet for GB
-            0xFF50 => Some(match self.boot{true => 0xFE, false => 0xFF}),
-            0xFF80...0xFFFE => Some(self.hram[addr as usize & 0x007F]),
-            0xFFFF => Some(self.interupt_enable),
+        let low = addr as usize & 0xFF;
+        match self.pages[addr as usize >> 8] {
+            Page::Rom(offset) => Some(self.cartrage.read_offset(offset + low)),
+            Page::Vram(offset) => Some(self.vram[offset + low]),
+            Page::Wram(offset) => Some(self.wram[offset + low]),
+            Page::Slow => self.read_slow(addr),
+        }
+    }
+    fn rom_offset(&self, addr: u16) -> Option<usize> {
+        match self.pages[addr as usize >> 8] {
+            Page::Rom(offset) => Some(offset + (addr as usize & 0xFF)),
             _ => None,
         }
     }
     fn write(&mut self, addr: u16, data: u8) -> bool {
-        match addr {
-            // Main table
-            0x0000...0x7FFF => self.cartrage.write(addr, data),
-            0x8000...0x9FFF => {self.vram[addr as usize & KB_8_MASK] = data; true}
-            0xA000...0xBFFF => self.cartrage.write_ram(addr, data),
-            0xC000...0xDFFF => {self.wram[addr as usize & KB_8_MASK] = data; true}
-            0xE000...0xFDFF => {self.wram[addr as usize & KB_8_MASK] = data; true}
-            0xFE00...0xFE9F => self.oam.write(addr, data),
-            // 0xFEA0...0xFEFF Not Usable.  Tetris write here.
-            0xFF00 => { // Joypad
-                self.joypad = data & 0x30; // Only control bits.
-                if self.joypad & 0x10 == 0 {
-                    self.joypad |= self.buttons.dpad();
-                }
-                if self.joypad & 0x20 == 0 {
-                    self.joypad |= self.buttons.buttons();
-                }
-                if self.joypad & 0x0F < 0x0F {
-                    self.interupt_flag |= 0x10;
-                }
-                true
-            },
-            0xFF01...0xFF02 => self.serial.write(addr, data),
-            0xFF04...0xFF07 => self.timer.write(addr, data),
-            0xFF01...0xFF02 => true, // Not implemented serial
-            0xFF0F => {self.interupt_flag = data; println!("IF set to {:02X}", data); true}
-            0xFF10...0xFF3F => true, // Audio device not implemented.
-            0xFF40...0xFF45 => self.ppu.write(addr, data), // PPU state
-            0xFF46 => self.dma(data),
-            0xFF47...0xFF49 => self.gbp.write(addr, data), // Pallet for GB
-            0xFF50 => {self.boot = self.boot || (data & 0x01) > 0; true},
-            0xFF80...0xFFFE => {self.hram[addr as usize & 0x007F] = data; true}
-            0xFFFF => {self.interupt_enable = data; println!("IE set to {:02X}", data); true},
-            _ => false,
+        let low = addr as usize & 0xFF;
+        match self.pages[addr as usize >> 8] {
+            Page::Vram(offset) => {self.vram[offset + low] = data; true}
+            Page::Wram(offset) => {self.wram[offset + low] = data; true}
+            _ => self.write_slow(addr, data),
         }
     }
     fn time_passes(&mut self, time: usize) -> Option<Vec<u8>>{
+        for _ in 0..time / 4 {
+            self.dma_tick();
+        }
+        self.update_joypad();
         self.timer.tick(time);
         self.serial.tick(time);
-        self.ppu.time_passes(time)
-    }
-    fn update_input(&mut self, buttons: Buttons) {
-        self.buttons = buttons;
-        let data = self.read(0xFF00).unwrap();
-        self.write(0xFF00, data);
-    }
-
-    fn check_interupt(&mut self, ime: bool) -> Option<u16> {
+        // In double speed the cpu runs twice as fast as the lcd.
+        let rows = match self.cgb {
+            Some(ref cgb) if cgb.double_speed => self.ppu.time_passes(time / 2),
+            _ => self.ppu.time_passes(time),
+        };
+        if let Some(ref rows) = rows {
+            self.hdma_hblank(rows);
+            if rows.contains(&(GAMEBOY_HEIGHT as u8)) {
+                self.sgb_transfer();
+            }
+        }
         self.interupt_flag |= self.ppu.interupt_update();
         self.interupt_flag |= self.timer.check_interupt();
         self.interupt_flag |= self.serial.check_interupt();
-        let interupt_triggers = self.interupt_flag & self.interupt_enable;
-        if !ime || interupt_triggers == 0 { None }
-        else if interupt_triggers & 0x01 > 0 { self.interupt_flag = self.interupt_flag & !0x01; Some(0x40) } // v blank
-        else if interupt_triggers & 0x02 > 0 { self.interupt_flag = self.interupt_flag & !0x02; Some(0x48) } // Stat
-        else if interupt_triggers & 0x04 > 0 { self.interupt_flag = self.interupt_flag & !0x04; Some(0x50) } // Timer
-        else if interupt_triggers & 0x08 > 0 { self.interupt_flag = self.interupt_flag & !0x08; Some(0x58) } // Serial
-        else if interupt_triggers & 0x10 > 0 { self.interupt_flag = self.interupt_flag & !0x10; Some(0x60) } // Timer
-        else { None }
+        rows
+    }
+    fn dma_active(&self) -> bool {
+        self.dma.is_some()
+    }
+    fn kind(&self) -> GbKind {
+        match (&self.cgb, &self.sgb) {
+            (Some(_), _) => GbKind::GBC,
+            (None, Some(_)) => GbKind::SGB,
+            (None, None) => GbKind::GB,
+        }
+    }
+    fn border(&self) -> Option<&[u8]> {
+        self.sgb.as_ref().map(|sgb| sgb.border())
+    }
+    fn switch_speed(&mut self) -> bool {
+        self.cgb.as_mut().is_some_and(|cgb| cgb.switch_speed())
+    }
+    fn colourise(&mut self, held: &Buttons) {
+        if self.cgb.is_some() || self.sgb.is_some() {
+            return;
+        }
+        self.gbp.colours = Some(match colourise::for_buttons(held) {
+            Some((name, pallets)) => {
+                println!("Colours: {}", name);
+                pallets
+            }
+            None => colourise::for_cartrage(self.cartrage.rom()),
+        });
+    }
+    fn reset(&mut self) {
+        let mut cartrage = std::mem::replace(&mut self.cartrage, CartrageMapper::empty());
+        cartrage.reset();
+        let boot_rom = std::mem::replace(&mut self.boot_rom, BootRom::new(vec![]));
+        let log_to_file = self.serial.log_to_file;
+        let input = self.input.clone();
+        let sgb = self.sgb.is_some();
+        let cable = self.serial.cable.take();
+        *self = GbMapper::with_parts(cartrage, boot_rom);
+        if sgb {
+            self.enable_sgb();
+        }
+        self.serial.log_to_file = log_to_file;
+        self.serial.cable = cable;
+        self.connect_input(input);
+    }
+    fn flush_save(&self) {
+        self.cartrage.flush();
+    }
+    fn ram_snapshot(&self) -> (Vec<u8>, Vec<u8>) {
+        (self.wram.to_vec(), self.cartrage.ram().to_vec())
+    }
+    fn rom(&self) -> &[u8] {
+        self.cartrage.rom()
+    }
+    fn restore_ram(&mut self, wram: &[u8], cartrage_ram: &[u8]) {
+        let size = wram.len().min(self.wram.len());
+        self.wram[..size].copy_from_slice(&wram[..size]);
+        self.cartrage.restore_ram(cartrage_ram);
+    }
+    fn connect_input(&mut self, input: SharedButtons) {
+        self.input = input;
+        self.lines_seen = self.joypad_lines();
+    }
+    fn connect_link(&mut self, cable: Cable) {
+        self.serial.cable = Some(cable);
+    }
+    fn take_link(&mut self) -> Option<Cable> {
+        self.serial.started = None;
+        self.serial.cable.take()
+    }
+    /// Look at the lines after a button or the selection changes.
+    /// Only a line going from high to low raises the joypad interupt.
+    fn update_joypad(&mut self) {
+        let lines = self.joypad_lines();
+        if self.lines_seen & !lines > 0 {
+            self.interupt_flag |= 0x10;
+            self.joypad_edge = true;
+        }
+        self.lines_seen = lines;
+    }
+    fn take_joypad_edge(&mut self) -> bool {
+        std::mem::replace(&mut self.joypad_edge, false)
+    }
+    fn state(&mut self, snapshot: &mut Snapshot) {
+        self.cartrage.state(snapshot);
+        snapshot.bool(&mut self.boot);
+        if !self.boot && self.boot_rom.rom.is_empty() {
+            snapshot.reject("saved while the boot rom was running, and there is none now");
+        }
+        snapshot.bytes(&mut self.vram);
+        snapshot.bytes(&mut self.wram);
+        self.oam.state(snapshot);
+        self.serial.state(snapshot);
+        snapshot.u8(&mut self.joypad);
+        snapshot.u8(&mut self.lines_seen);
+        snapshot.bool(&mut self.joypad_edge);
+        self.timer.state(snapshot);
+        snapshot.bytes(&mut self.hram);
+        snapshot.u8(&mut self.interupt_enable);
+        snapshot.u8(&mut self.interupt_flag);
+        self.ppu.state(snapshot);
+        self.gbp.state(snapshot);
+        snapshot.option(&mut self.dma, || (0, 0), |snapshot, dma| {
+            snapshot.u16(&mut dma.0);
+            snapshot.u16(&mut dma.1);
+        });
+        snapshot.option(&mut self.cgb, cgb::Cgb::new, |snapshot, cgb| cgb.state(snapshot));
+        snapshot.option(&mut self.sgb, sgb::Sgb::new, |snapshot, sgb| sgb.state(snapshot));
+        if snapshot.is_loading() {
+            self.map_pages();
+        }
+    }
+
+    fn pending_interupts(&self) -> u8 {
+        self.interupt_flag & self.interupt_enable & 0x1F
+    }
+
+    fn clear_interupt(&mut self, mask: u8) {
+        self.interupt_flag &= !mask;
     }
 
     fn render(&self, row: u8, buffer: &mut [u8]) {
-        let x_offset = self.ppu.scx;
-        let y_offset = self.ppu.scy.wrapping_add(row);
+        if let Some(ref sgb) = self.sgb {
+            match sgb.mask {
+                sgb::Mask::None => {}
+                sgb::Mask::Freeze => return, // The last frame is left as it is.
+                sgb::Mask::Black => return buffer.iter_mut().for_each(|b| *b = 0),
+                sgb::Mask::Colour0 => return buffer.chunks_mut(3)
+                    .for_each(|pixel| pixel.copy_from_slice(&sgb.backdrop())),
+            }
+        }
         let sprite_size = self.ppu.lcdc_get(2);
-        let sprites = self.oam.sprite_line(self, row, sprite_size);
+        let sprites = self.oam.sprite_line(&self.vram, row, sprite_size, self.cgb.is_some());
         let background = self.background_line(row);
         for i in 0..GAMEBOY_WIDTH as usize {
-            let buff_offset = i * 3;
-            if let Some((color, pallet, priority)) = sprites[i] {
-                if !priority || background[i] == 0 {
-                    self.gbp.apply(
-                        match pallet { 
-                            false => gbp::Pallet::OBP0, 
-                            true => gbp::Pallet::OBP1 
-                        },
-                        color,
-                        &mut buffer[buff_offset..buff_offset + 3],
-                    );
+            let pixel = &mut buffer[i * 3..i * 3 + 3];
+            let (bg, bg_pallet, bg_priority) = background[i];
+            match self.cgb {
+                Some(ref cgb) => {
+                    // With LCDC bit 0 clear sprites are always on top.
+                    let bg_on_top = |priority| {
+                        self.ppu.lcdc_get(0) && bg > 0 && (bg_priority || priority)
+                    };
+                    match sprites[i] {
+                        Some((color, pallet, priority)) if !bg_on_top(priority) =>
+                            pixel.copy_from_slice(&cgb.obj_pallets.colour(pallet, color)),
+                        _ => pixel.copy_from_slice(&cgb.bg_pallets.colour(bg_pallet, bg)),
+                    }
                 }
-                else {
-                self.gbp.apply(gbp::Pallet::BGP,
-                               background[i],
-                               &mut buffer[buff_offset..buff_offset + 3]);
+                None => {
+                    let (pallet, color) = match sprites[i] {
+                        Some((color, 0, priority)) if !priority || bg == 0 =>
+                            (gbp::Pallet::OBP0, color),
+                        Some((color, _, priority)) if !priority || bg == 0 =>
+                            (gbp::Pallet::OBP1, color),
+                        _ => (gbp::Pallet::BGP, bg),
+                    };
+                    match self.sgb {
+                        Some(ref sgb) => pixel.copy_from_slice(
+                            &sgb.colour(i, row as usize, self.gbp.shade(pallet, color))),
+                        None => self.gbp.apply(pallet, color, pixel),
+                    }
                 }
             }
-            else {
-                self.gbp.apply(gbp::Pallet::BGP,
-                               background[i],
-                               &mut buffer[buff_offset..buff_offset + 3]);
-            }
         }
     }
 
+    #[cfg(test)]
+    fn serial_log(&self) -> &[u8] {
+        &self.serial.log
+    }
+
     fn print_background_map(&self) {
         let map_offset = match self.ppu.lcdc_get(3) {
             true => 0x9C00,
@@ -573,42 +997,229 @@ impl MemMapper for GbMapper {
 }
 
 impl GbMapper {
-    fn background_line(&self, scanline: u8) -> Vec<u8> {
+    /// The full memory map, for pages that need more than a plain array.
+    fn read_slow(&self, addr: u16) -> Option<u8> {
+        // Main table
+        match addr {
+            0x0000...0x08FF if self.boot_rom_mapped(addr) => self.boot_rom.read(addr),
+            0x0000...0x7FFF => self.cartrage.read(addr),
+            0x8000...0x9FFF => Some(self.vram[self.vram_offset(addr)]),
+            0xA000...0xBFFF => self.cartrage.read_ram(addr),
+            0xC000...0xFDFF => Some(self.wram[self.wram_offset(addr)]),
+            0xFE00...0xFE9F => self.oam.read(addr),
+            // 0xFEA0...0xFEFF Not Used by anything.
+            0xFF00 => Some(self.joypad | self.joypad_lines()), // Joypad
+            0xFF01...0xFF02 => self.serial.read(addr),
+            0xFF04...0xFF07 => self.timer.read(addr),
+            0xFF0F => Some(self.interupt_flag),
+            0xFF10...0xFF3F => Some(0xFF), // Audio device not implemented.
+            0xFF40...0xFF45 => self.ppu.read(addr), // PPU state
+            0xFF47...0xFF49 => self.gbp.read(addr), // Pallet for GB
+            0xFF50 => Some(match self.boot{true => 0xFE, false => 0xFF}),
+            0xFF4D...0xFF70 => self.cgb.as_ref()?.read(addr),
+            0xFF80...0xFFFE => Some(self.hram[addr as usize & 0x007F]),
+            0xFFFF => Some(self.interupt_enable),
+            _ => None,
+        }
+    }
+
+    fn write_slow(&mut self, addr: u16, data: u8) -> bool {
+        match addr {
+            // Main table
+            0x0000...0x7FFF => {
+                let written = self.cartrage.write(addr, data);
+                self.map_pages(); // Likely a bank switch.
+                written
+            }
+            0x8000...0x9FFF => {self.vram[self.vram_offset(addr)] = data; true}
+            0xA000...0xBFFF => self.cartrage.write_ram(addr, data),
+            0xC000...0xFDFF => {self.wram[self.wram_offset(addr)] = data; true}
+            0xFE00...0xFE9F => self.oam.write(addr, data),
+            // 0xFEA0...0xFEFF Not Usable.  Tetris write here.
+            0xFF00 => {self.select_joypad(data); true}
+            0xFF01...0xFF02 => self.serial.write(addr, data),
+            0xFF04...0xFF07 => self.timer.write(addr, data),
+            0xFF01...0xFF02 => true, // Not implemented serial
+            0xFF0F => {self.interupt_flag = data; true}
+            0xFF10...0xFF3F => true, // Audio device not implemented.
+            0xFF40...0xFF45 => self.ppu.write(addr, data), // PPU state
+            0xFF46 => self.dma(data),
+            0xFF47...0xFF49 => self.gbp.write(addr, data), // Pallet for GB
+            0xFF50 => {
+                self.boot = self.boot || (data & 0x01) > 0;
+                self.map_pages();
+                true
+            }
+            0xFF55 if self.cgb.is_some() => {
+                let transfer = self.cgb.as_mut().unwrap().transfer(data);
+                if let cgb::Transfer::General(blocks) = transfer {
+                    for _ in 0..blocks {
+                        self.hdma_block();
+                    }
+                }
+                true
+            }
+            0xFF4D...0xFF70 => match self.cgb {
+                Some(ref mut cgb) => {
+                    let written = cgb.write(addr, data);
+                    self.map_pages(); // The vram or wram bank may have changed.
+                    written
+                }
+                None => false,
+            },
+            0xFF80...0xFFFE => {self.hram[addr as usize & 0x007F] = data; true}
+            0xFFFF => {self.interupt_enable = data; true},
+            _ => false,
+        }
+    }
+
+    /// Colour, pallet and priority of each background pixel on a line.  Only colour has
+    /// tile attributes, outside it the pallet is always 0 and the priority false.
+    fn background_line(&self, scanline: u8) -> [(u8, u8, bool); GAMEBOY_WIDTH as usize] {
         let x_offset = self.ppu.scx;
-        let y_offset = self.ppu.scy.wrapping_add(scanline) as u16;
-        let map_x_offset = (x_offset >> 3) as u16; // offset divided by 8
+        let y_offset = self.ppu.scy.wrapping_add(scanline) as usize;
+        let map_x_offset = (x_offset >> 3) as usize; // offset divided by 8
         let map_offset = match self.ppu.lcdc_get(3) {
-            true => 0x9C00,
-            false => 0x9800,
+            true => 0x1C00,
+            false => 0x1800,
         };
 
         let map_row = map_offset + ((y_offset / 8) * 32);
         let sprite_offset = y_offset % 8;
 
-        (0..32)
-            .map(|sprite_on_line| self.read(map_row + ((sprite_on_line + map_x_offset) % 32)).unwrap())
-            .map(|map_data|
-                 match self.ppu.lcdc_get(4) {
-                     true => map_data as u16,
-                     // This needs to be a signed offset
-                     false => (256 + ((map_data as i8) as i16)) as u16,
-                 })
-            .flat_map(|sprite_index| get_sprite!(self, sprite_index, sprite_offset))
-            .skip(x_offset as usize % 8)
-            .take(GAMEBOY_WIDTH as usize)
-            .collect()
+        // Scrolled part way into a tile, the first is cut short and a 21st shows.
+        let mut line = [(0, 0, false); GAMEBOY_WIDTH as usize];
+        let skip = x_offset as usize % 8;
+        for sprite_on_line in 0..21 {
+            let map_addr = map_row + ((sprite_on_line + map_x_offset) % 32);
+            let map_data = self.vram[map_addr];
+            // Attributes sit in bank 1 behind the map.
+            let attributes = match self.cgb {
+                Some(_) => self.vram[KB_8 + map_addr],
+                None => 0,
+            };
+            let sprite_index = match self.ppu.lcdc_get(4) {
+                true => map_data as u16,
+                // This needs to be a signed offset
+                false => (256 + ((map_data as i8) as i16)) as u16,
+            };
+            let row = match attributes & 0x40 > 0 {
+                true => 7 - sprite_offset,
+                false => sprite_offset,
+            };
+            let bank = (attributes >> 3 & 0x01) as usize;
+            let mut pixels = [0; 8];
+            for (pixel, colour) in pixels.iter_mut().zip(get_sprite!(self.vram, bank, sprite_index, row)) {
+                *pixel = colour;
+            }
+            if attributes & 0x20 > 0 {
+                pixels.reverse();
+            }
+            let (pallet, priority) = (attributes & 0x07, attributes & 0x80 > 0);
+            for (x, pixel) in pixels.iter().enumerate() {
+                if let Some(shown) = (sprite_on_line * 8 + x).checked_sub(skip).and_then(|x| line.get_mut(x)) {
+                    *shown = (*pixel, pallet, priority);
+                }
+            }
+        }
+        line
     }
 }
 
+/// 64K of plain ram with nothing mapped, for testing the cpu on its own.
+#[cfg(test)]
+pub struct FlatMapper {
+    ram: Vec<u8>,
+}
+
+#[cfg(test)]
+impl MemMapper for FlatMapper {
+    fn read(&self, addr: u16) -> Option<u8> { Some(self.ram[addr as usize]) }
+    fn rom_offset(&self, _addr: u16) -> Option<usize> { None }
+    fn write(&mut self, addr: u16, data: u8) -> bool { self.ram[addr as usize] = data; true }
+    fn time_passes(&mut self, _time: usize) -> Option<Vec<u8>> { None }
+    fn dma_active(&self) -> bool { false }
+    fn kind(&self) -> GbKind { GbKind::GB }
+    fn switch_speed(&mut self) -> bool { false }
+    fn colourise(&mut self, _held: &Buttons) {}
+    fn border(&self) -> Option<&[u8]> { None }
+    fn connect_input(&mut self, _input: SharedButtons) {}
+    fn connect_link(&mut self, _cable: Cable) {}
+    fn take_link(&mut self) -> Option<Cable> { None }
+    fn reset(&mut self) {}
+    fn flush_save(&self) {}
+    fn ram_snapshot(&self) -> (Vec<u8>, Vec<u8>) { (vec![], vec![]) }
+    fn rom(&self) -> &[u8] { &[] }
+    fn restore_ram(&mut self, _wram: &[u8], _cartrage_ram: &[u8]) {}
+    fn update_joypad(&mut self) {}
+    fn take_joypad_edge(&mut self) -> bool { false }
+    fn state(&mut self, snapshot: &mut Snapshot) { snapshot.bytes(&mut self.ram) }
+    fn pending_interupts(&self) -> u8 { 0 }
+    fn clear_interupt(&mut self, _mask: u8) {}
+    fn render(&self, _row: u8, _buffer: &mut [u8]) {}
+    #[cfg(test)]
+    fn serial_log(&self) -> &[u8] { &[] }
+    fn print_background_map(&self) {}
+    fn print_sprite_table(&self) {}
+}
+
 impl Mem {
     pub fn new_gb(mapper: GbMapper) -> Self {
         Mem {
             map_holder: Box::new(mapper),
-            screen: Box::new([0; GAMEBOY_SCREEN_BUFFER_SIZE as usize]),
+            screen: vec![0; GAMEBOY_SCREEN_BUFFER_SIZE as usize].into_boxed_slice(),
+            ime: false,
+            cycles: 0,
+            behind: 0,
+            frame_ready: false,
+            stopped: None,
+            skip_render: false,
+            #[cfg(test)]
+            bus: None,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn new_flat() -> Self {
+        Mem {
+            map_holder: Box::new(FlatMapper { ram: vec![0; 0x10000] }),
+            screen: vec![0; GAMEBOY_SCREEN_BUFFER_SIZE as usize].into_boxed_slice(),
             ime: false,
+            cycles: 0,
+            behind: 0,
+            frame_ready: false,
+            stopped: None,
+            skip_render: false,
+            bus: None,
         }
     }
 
+    /// Starts recording every cpu access, with the M-cycle since `take_cycles` it was in.
+    #[cfg(test)]
+    pub fn record_bus(&mut self) {
+        self.bus = Some(vec![]);
+    }
+
+    #[cfg(test)]
+    pub fn bus(&self) -> &[(usize, Access)] {
+        self.bus.as_ref().map_or(&[], |bus| &bus[..])
+    }
+
+    #[cfg(test)]
+    fn record(&mut self, access: impl FnOnce(&Self) -> Access) {
+        if self.bus.is_none() {
+            return;
+        }
+        let entry = (self.cycles / 4 - 1, access(self));
+        if let Some(ref mut bus) = self.bus {
+            bus.push(entry);
+        }
+    }
+
+    pub fn ime(&self) -> bool {
+        self.ime
+    }
+
     pub fn load_8(&self, addr: u16) -> u8 {
         // Look value up in memory map
         match self.map_holder.read(addr) {
@@ -621,8 +1232,164 @@ impl Mem {
         }
     }
 
-    pub fn time_passes(&mut self, time: usize) -> Option<Vec<u8>>{
-        self.map_holder.time_passes(time)
+    /// Where in the cartrage rom an address is currently mapped from, if it is rom.
+    pub fn rom_offset(&self, addr: u16) -> Option<usize> {
+        self.map_holder.rom_offset(addr)
+    }
+
+    /// One M-cycle of everything but the cpu, after any it is behind by, rendering any rows
+    /// the ppu finished.
+    pub fn tick(&mut self) {
+        self.idle_cycle();
+        self.catch_up();
+    }
+
+    /// An M-cycle nothing outside the cpu can tell apart from the next, fetching or working
+    /// inside.  The rest only runs once something could see it, which saves going through
+    /// every part of the system for each of them.
+    pub fn idle_cycle(&mut self) {
+        self.cycles += 4;
+        self.behind += 4;
+    }
+
+    /// Run everything but the cpu through the cycles it is behind by.  Has to come before
+    /// anything that looks at the rest of the system.
+    pub fn catch_up(&mut self) {
+        let time = std::mem::replace(&mut self.behind, 0);
+        if time == 0 {
+            return;
+        }
+        if let Some(ref mut clock) = self.stopped {
+            // Nothing runs but the joypad, so a button can wake it, and main still gets
+            // a white frame every frame's worth.
+            self.map_holder.update_joypad();
+            *clock += time;
+            if *clock >= CYCLES_PER_FRAME {
+                *clock = 0;
+                self.screen.iter_mut().for_each(|b| *b = 0xFF);
+                self.frame_ready = true;
+            }
+            return;
+        }
+        if let Some(rows) = self.map_holder.time_passes(time) {
+            for r in rows {
+                self.frame_ready |= match self.skip_render {
+                    true => r == 144,
+                    false => self.render(r as usize),
+                };
+            }
+        }
+    }
+
+    /// Back to power on, keeping the cartrage and its ram.
+    pub fn reset(&mut self) {
+        self.map_holder.reset();
+        self.ime = false;
+        self.cycles = 0;
+        self.behind = 0;
+        self.frame_ready = false;
+        self.stopped = None;
+    }
+
+    pub fn flush_save(&self) {
+        self.map_holder.flush_save();
+    }
+
+    /// Work ram and cartrage ram, to carry over a reload.
+    pub fn ram_snapshot(&self) -> (Vec<u8>, Vec<u8>) {
+        self.map_holder.ram_snapshot()
+    }
+
+    pub fn rom(&self) -> &[u8] {
+        self.map_holder.rom()
+    }
+
+    pub fn restore_ram(&mut self, wram: &[u8], cartrage_ram: &[u8]) {
+        self.map_holder.restore_ram(wram, cartrage_ram)
+    }
+
+    /// Frames that will never be shown can skip drawing, everything else still runs.
+    pub fn skip_rendering(&mut self, skip: bool) {
+        self.skip_render = skip;
+    }
+
+    /// STOP halts the clock, resets DIV and blanks the lcd until a button is pressed.
+    /// In colour it switches speed instead if that was asked for.
+    /// Returns true if the cpu stopped.
+    pub fn stop(&mut self) -> bool {
+        self.catch_up();
+        self.write_8(0xFF04, 0);
+        if self.map_holder.switch_speed() {
+            return false;
+        }
+        self.map_holder.take_joypad_edge();
+        self.stopped = Some(0);
+        true
+    }
+
+    pub fn kind(&self) -> GbKind {
+        self.map_holder.kind()
+    }
+
+    pub fn colourise(&mut self, held: &Buttons) {
+        self.map_holder.colourise(held)
+    }
+
+    pub fn border(&self) -> Option<&[u8]> {
+        self.map_holder.border()
+    }
+
+    /// Leave STOP if a selected button line has fallen since.
+    pub fn wake(&mut self) -> bool {
+        if self.map_holder.take_joypad_edge() {
+            self.stopped = None;
+            true
+        }
+        else { false }
+    }
+
+    /// A cpu read, taking an M-cycle.  While dma is running only io and hram answer.
+    pub fn read_cycle(&mut self, addr: u16) -> u8 {
+        self.tick();
+        let data = if addr < 0xFF00 && self.map_holder.dma_active() { 0xFF }
+        else { self.load_8(addr) };
+        #[cfg(test)]
+        self.record(|_| Access::Read(addr, data));
+        data
+    }
+
+    /// A cpu write, taking an M-cycle.  While dma is running only io and hram listen.
+    pub fn write_cycle(&mut self, addr: u16, data: u8) {
+        self.tick();
+        if addr >= 0xFF00 || !self.map_holder.dma_active() {
+            self.write_8(addr, data);
+        }
+        #[cfg(test)]
+        self.record(|_| Access::Write(addr, data));
+    }
+
+    /// The M-cycle fetching an instruction byte.  The cpu decodes ahead of time, so outside
+    /// of tests this only has to pass the time.
+    #[cfg_attr(not(test), allow(unused_variables))]
+    pub fn fetch_cycle(&mut self, addr: u16) {
+        self.idle_cycle();
+        #[cfg(test)]
+        self.record(|mem| Access::Read(addr, mem.load_8(addr)));
+    }
+
+    /// Cycles ticked since `take_cycles`.
+    pub fn cycles(&self) -> usize {
+        self.cycles
+    }
+
+    /// Cycles ticked since last taken, starting the count again.
+    pub fn take_cycles(&mut self) -> usize {
+        std::mem::replace(&mut self.cycles, 0)
+    }
+
+    /// True once for each frame finished.
+    pub fn take_frame(&mut self) -> bool {
+        std::mem::replace(&mut self.frame_ready, false)
     }
 
     pub fn set_ime(&mut self, value: bool) {
@@ -630,15 +1397,46 @@ impl Mem {
         self.ime = value;
     }
 
-    pub fn check_interupt(&mut self) -> Option<u16> {
-        self.map_holder.check_interupt(self.ime)
+    /// An enabled interupt is requested, whether or not IME is set.
+    pub fn interupt_pending(&self) -> bool {
+        self.map_holder.pending_interupts() != 0
     }
 
-    pub fn update_input(&mut self, buttons: Buttons) {
-        self.map_holder.update_input(buttons)
+    /// An interupt should be dispatched now.
+    pub fn check_interupt(&self) -> bool {
+        self.ime && self.interupt_pending()
     }
 
-    pub fn render(&mut self, row: usize) -> bool{
+    /// Take the highest priority interupt, clearing its flag, and return its vector.
+    /// None if nothing is requested any more.
+    pub fn acknowledge_interupt(&mut self) -> Option<u16> {
+        let pending = self.map_holder.pending_interupts();
+        if pending == 0 { return None }
+        let bit = pending.trailing_zeros() as u16;
+        self.map_holder.clear_interupt(1 << bit);
+        Some(0x40 + bit * 8)
+    }
+
+    /// Where to read the buttons from from now on.
+    pub fn connect_input(&mut self, input: SharedButtons) {
+        self.map_holder.connect_input(input)
+    }
+
+    /// Plug in the link cable, it is taken back out to move it to a reloaded game.
+    pub fn connect_link(&mut self, cable: Cable) {
+        self.map_holder.connect_link(cable)
+    }
+
+    pub fn take_link(&mut self) -> Option<Cable> {
+        self.map_holder.take_link()
+    }
+
+    #[cfg(test)]
+    pub fn serial_log(&self) -> &[u8] {
+        self.map_holder.serial_log()
+    }
+
+    fn render(&mut self, row: usize) -> bool{
         if row < 144 {
             // Actually render
             let start_row = GAMEBOY_WIDTH as usize * 3 * row;
@@ -652,9 +1450,18 @@ impl Mem {
         }
     }
 
-    pub fn screen_swap(&mut self, other: &mut Box<[u8; GAMEBOY_SCREEN_BUFFER_SIZE as usize]>) {
+    /// The frame being drawn, complete right after row 144 has been rendered.
+    pub fn screen(&self) -> &[u8] {
+        &self.screen[..]
+    }
+
+    pub fn screen_swap(&mut self, other: &mut Box<[u8]>) {
         use std::mem::swap;
 
+        // After a Super Game Boy the buffer coming back may be bordered.
+        if other.len() != self.screen.len() {
+            *other = self.screen.clone();
+        }
         swap(&mut self.screen, other);
         // Prints that should be done once a frame:
         // self.map_holder.print_background_map();
@@ -668,19 +1475,77 @@ impl Mem {
             println!("Memory write failed for address: {:04X}", addr)
         }
     }
+}
 
-    pub fn write_16(&mut self, addr: u16, data: u16) {
-        // Look value up in memory map
-        self.write_8(addr, data as u8);
-        self.write_8(addr.wrapping_add(1), (data >> 8) as u8);
+/// The screen is left out, it is drawn again by the next frame.
+impl State for Mem {
+    fn state(&mut self, snapshot: &mut Snapshot) {
+        self.map_holder.state(snapshot);
+        snapshot.bool(&mut self.ime);
+        snapshot.usize(&mut self.cycles);
+        snapshot.bool(&mut self.frame_ready);
+        snapshot.option(&mut self.stopped, || 0, |snapshot, clock| snapshot.usize(clock));
     }
+}
 
-    pub fn load_16(&self, addr: u16) -> u16 {
-        // Look value up in memory map
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use gb::mem::{BootRom, Buttons, GbMapper, MemMapper, Serial, SharedButtons};
+    use gb::mem::cm::CartrageMapper;
+    use gb::mem::link::{Cable, Wire};
+
+    #[test]
+    fn joypad_interupt_only_when_a_line_falls() {
+        let mut mapper = GbMapper::with_parts(CartrageMapper::empty(), BootRom::new(vec![]));
+        let input = SharedButtons::default();
+        mapper.connect_input(input.clone());
+        mapper.write(0xFF00, 0x20); // D-pad.
+
+        input.set(Buttons { a: true, ..Buttons::default() });
+        mapper.time_passes(4);
+        assert!(!mapper.take_joypad_edge());
+        assert_eq!(mapper.read(0xFF00), Some(0x2F));
+
+        input.set(Buttons { a: true, down: true, ..Buttons::default() });
+        assert_eq!(mapper.read(0xFF00), Some(0x27));
+        mapper.time_passes(4);
+        assert!(mapper.take_joypad_edge());
+        mapper.time_passes(4);
+        assert!(!mapper.take_joypad_edge());
+
+        mapper.write(0xFF00, 0x10); // Buttons, A is held so its line falls.
+        assert!(mapper.take_joypad_edge());
+        assert_eq!(mapper.read(0xFF00), Some(0x1E));
+
+        input.set(Buttons::default());
+        mapper.time_passes(4);
+        assert!(!mapper.take_joypad_edge());
+    }
 
-        let low: u16 = self.load_8(addr) as u16;
-        let high: u16 = self.load_8(addr + 1) as u16;
+    fn linked(wire: Wire, sb: u8, sc: u8) -> Serial {
+        let mut serial = Serial::new();
+        serial.log_to_file = false;
+        serial.cable = Some(Cable::new(Box::new(wire)));
+        serial.write(0xFF01, sb);
+        serial.write(0xFF02, sc);
+        for _ in 0..4000 {
+            serial.tick(4);
+        }
+        serial
+    }
 
-        (high << 8) + low
+    #[test]
+    fn link_cable_swaps_bytes() {
+        let (master, slave) = Wire::pair();
+        let slave = thread::spawn(move || {
+            let mut serial = linked(slave, 0x99, 0x80);
+            (serial.read(0xFF01), serial.check_interupt())
+        });
+        let mut serial = linked(master, 0x42, 0x81);
+        assert_eq!(serial.read(0xFF01), Some(0x99));
+        assert_eq!(serial.read(0xFF02), Some(0x7F));
+        assert_eq!(serial.check_interupt(), 0x08);
+        assert_eq!(slave.join().unwrap(), (Some(0x42), 0x08));
     }
 }
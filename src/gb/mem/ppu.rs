@@ -1,3 +1,4 @@
+use gb::state::{self, Snapshot};
 
 macro_rules! get_bit {
     ($data:expr, $bit:expr) => {
@@ -158,7 +159,10 @@ impl PPU {
                     else { self.lx_sent = false; }
                 }
                 self.set_state();
-                Some(ret)
+                match ret.is_empty() {
+                    true => None,
+                    false => Some(ret),
+                }
             }
             else {
                 self.set_state();
@@ -216,3 +220,16 @@ impl PPU {
         }
     }
 }
+
+impl state::State for PPU {
+    fn state(&mut self, snapshot: &mut Snapshot) {
+        for register in [&mut self.lcdc, &mut self.stat, &mut self.scy, &mut self.scx,
+                         &mut self.ly, &mut self.lyc, &mut self.wy, &mut self.wx].iter_mut() {
+            snapshot.u8(register);
+        }
+        snapshot.usize(&mut self.lx);
+        snapshot.bool(&mut self.lx_sent);
+        snapshot.bool(&mut self.vblank_interupt_buffered);
+        snapshot.bool(&mut self.stat_interupt_buffered);
+    }
+}
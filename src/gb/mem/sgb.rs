@@ -0,0 +1,502 @@
+//! What a Super Game Boy adds around a game.
+//!
+//! Games send it commands by pulsing P14 and P15 of FF00: both low starts a packet, then
+//! each bit is P14 low for a 0 or P15 low for a 1, with both high in between.  A packet is
+//! 16 bytes, least significant bit first, and the first byte of a command gives the command
+//! in its top five bits and how many packets it takes in the bottom three.
+//!
+//! The screen is split into 20 by 18 cells, each shown in one of four palettes.  Commands
+//! that move more data than fits in packets (the border, palettes and attribute files) send
+//! it as the next frame shown, 256 tiles read off the background.
+//!
+//! The border the Super Game Boy shows before a game sends its own is in its own rom, so
+//! until then the border is left the colour behind the screen.
+
+use gb::state::{Snapshot, State};
+
+pub const WIDTH: usize = 256;
+pub const HEIGHT: usize = 224;
+/// Where the gameboy screen is in the border.
+pub const LEFT: usize = 48;
+pub const TOP: usize = 40;
+
+const CELLS_WIDE: usize = 20;
+const CELLS_HIGH: usize = 18;
+
+// Commands handled, anything else (sound, the snes itself) is ignored.
+const PAL01: u8 = 0x00;
+const PAL23: u8 = 0x01;
+const PAL03: u8 = 0x02;
+const PAL12: u8 = 0x03;
+const ATTR_BLK: u8 = 0x04;
+const ATTR_LIN: u8 = 0x05;
+const ATTR_DIV: u8 = 0x06;
+const ATTR_CHR: u8 = 0x07;
+const PAL_SET: u8 = 0x0A;
+const PAL_TRN: u8 = 0x0B;
+const MLT_REQ: u8 = 0x11;
+const CHR_TRN: u8 = 0x13;
+const PCT_TRN: u8 = 0x14;
+const ATTR_TRN: u8 = 0x15;
+const ATTR_SET: u8 = 0x16;
+const MASK_EN: u8 = 0x17;
+
+/// What the next frame carries, for the commands that send a frame of data.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Transfer {
+    Tiles(usize), // The first tile it holds, 0 or 128.
+    Picture,
+    Palettes,
+    Attributes,
+}
+
+/// How the screen is hidden, MASK_EN.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mask {
+    None,
+    Freeze,
+    Black,
+    Colour0,
+}
+
+/// A 15 bit colour as 8 bit RGB.
+fn rgb(low: u8, high: u8) -> [u8; 3] {
+    let value = low as u16 | (high as u16) << 8;
+    let scale = |c: u16| ((c << 3) | (c >> 2)) as u8;
+    [scale(value & 0x1F), scale((value >> 5) & 0x1F), scale((value >> 10) & 0x1F)]
+}
+
+pub struct Sgb {
+    packet: [u8; 16],
+    bits: Option<usize>, // Bits of the packet so far, None until a reset pulse.
+    lines: u8, // P14 and P15 as last written.
+    command: Vec<u8>,
+    players: u8,
+    player: u8,
+    pub mask: Mask,
+    pub transfer: Option<Transfer>,
+    palettes: [[[u8; 3]; 4]; 4],
+    attributes: [u8; CELLS_WIDE * CELLS_HIGH],
+    system_palettes: Vec<u8>, // 512 palettes of 4 colours from PAL_TRN.
+    attribute_files: Vec<u8>, // 45 files of 90 bytes from ATTR_TRN.
+    tiles: Vec<u8>, // 256 4 bit tiles for the border.
+    picture: Vec<u8>, // The border map and its palettes.
+    border: Vec<u8>,
+}
+
+impl Sgb {
+    pub fn new() -> Self {
+        // The palette the Super Game Boy starts with.
+        let start = [[0xF8, 0xE8, 0xC8], [0xD8, 0x90, 0x48], [0xA8, 0x28, 0x20], [0x30, 0x18, 0x50]];
+        let mut sgb = Sgb {
+            packet: [0; 16],
+            bits: None,
+            lines: 0x30,
+            command: vec![],
+            players: 1,
+            player: 0,
+            mask: Mask::None,
+            transfer: None,
+            palettes: [start; 4],
+            attributes: [0; CELLS_WIDE * CELLS_HIGH],
+            system_palettes: vec![0; 4096],
+            attribute_files: vec![0; 4096],
+            tiles: vec![0; 8192],
+            picture: vec![0; 4096],
+            border: vec![0; WIDTH * HEIGHT * 3],
+        };
+        sgb.draw_border();
+        sgb
+    }
+
+    /// A write to FF00, which may be a bit of a packet.
+    pub fn write_joypad(&mut self, data: u8) {
+        let lines = data & 0x30;
+        match lines {
+            0x00 => {
+                self.bits = Some(0);
+                self.packet = [0; 16];
+            }
+            // Back high after P15 was low, the next controller is read from now on.
+            0x30 if self.lines == 0x10 && self.players > 1 && self.bits.is_none() => {
+                self.player = (self.player + 1) % self.players;
+            }
+            0x10 | 0x20 if self.lines == 0x30 => if let Some(bits) = self.bits {
+                self.bit(bits, lines == 0x10);
+            }
+            _ => {}
+        }
+        self.lines = lines;
+    }
+
+    fn bit(&mut self, bits: usize, one: bool) {
+        if bits == 128 {
+            // The stop bit after the 16 bytes.
+            self.bits = None;
+            let packet = self.packet;
+            self.packet_done(&packet);
+            return;
+        }
+        if one {
+            self.packet[bits / 8] |= 1 << (bits % 8);
+        }
+        self.bits = Some(bits + 1);
+    }
+
+    fn packet_done(&mut self, packet: &[u8; 16]) {
+        self.command.extend_from_slice(packet);
+        let length = (self.command[0] & 0x07).max(1) as usize;
+        if self.command.len() >= length * 16 {
+            let command = std::mem::take(&mut self.command);
+            self.run(&command);
+        }
+    }
+
+    /// Which controller FF00 reads, 0 to 3.  Only the first has buttons.
+    pub fn player(&self) -> u8 {
+        self.player
+    }
+
+    /// Whether more than one controller is being read.
+    pub fn multiplayer(&self) -> bool {
+        self.players > 1
+    }
+
+    fn run(&mut self, command: &[u8]) {
+        match command[0] >> 3 {
+            PAL01 => self.set_pair(0, 1, command),
+            PAL23 => self.set_pair(2, 3, command),
+            PAL03 => self.set_pair(0, 3, command),
+            PAL12 => self.set_pair(1, 2, command),
+            ATTR_BLK => self.attribute_blocks(command),
+            ATTR_LIN => self.attribute_lines(command),
+            ATTR_DIV => self.attribute_divide(command),
+            ATTR_CHR => self.attribute_cells(command),
+            PAL_SET => self.palette_set(command),
+            PAL_TRN => self.transfer = Some(Transfer::Palettes),
+            MLT_REQ => {
+                self.players = match command[1] & 0x03 {
+                    1 => 2,
+                    3 => 4,
+                    _ => 1,
+                };
+                self.player = 0;
+            }
+            CHR_TRN => self.transfer = Some(Transfer::Tiles((command[1] as usize & 0x01) * 128)),
+            PCT_TRN => self.transfer = Some(Transfer::Picture),
+            ATTR_TRN => self.transfer = Some(Transfer::Attributes),
+            ATTR_SET => self.attribute_file(command[1]),
+            MASK_EN => self.mask = match command[1] & 0x03 {
+                0 => Mask::None,
+                1 => Mask::Freeze,
+                2 => Mask::Black,
+                _ => Mask::Colour0,
+            },
+            _ => {}
+        }
+    }
+
+    /// Two palettes, and colour 0 which every palette shares.
+    fn set_pair(&mut self, first: usize, second: usize, command: &[u8]) {
+        let colour = |n: usize| rgb(command[1 + n * 2], command[2 + n * 2]);
+        for palette in self.palettes.iter_mut() {
+            palette[0] = colour(0);
+        }
+        for i in 1..4 {
+            self.palettes[first][i] = colour(i);
+            self.palettes[second][i] = colour(i + 3);
+        }
+        self.draw_border();
+    }
+
+    fn attribute_blocks(&mut self, command: &[u8]) {
+        let sets = (command[1] as usize).min((command.len() - 2) / 6);
+        for set in command[2..].chunks(6).take(sets) {
+            let (control, palettes) = (set[0] & 0x07, set[1]);
+            let (x1, y1, x2, y2) = (set[2] as usize, set[3] as usize,
+                                    set[4] as usize, set[5] as usize);
+            let inside = palettes & 0x03;
+            let outside = (palettes >> 4) & 0x03;
+            // With only the inside or the outside set, the edge goes with it.
+            let (edge, edge_set) = match control {
+                0x01 => (inside, true),
+                0x04 => (outside, true),
+                _ => ((palettes >> 2) & 0x03, control & 0x02 > 0),
+            };
+            for y in 0..CELLS_HIGH {
+                for x in 0..CELLS_WIDE {
+                    let within = x >= x1 && x <= x2 && y >= y1 && y <= y2;
+                    let on_edge = within && (x == x1 || x == x2 || y == y1 || y == y2);
+                    let palette = match (within, on_edge) {
+                        (true, true) if edge_set => Some(edge),
+                        (true, false) if control & 0x01 > 0 => Some(inside),
+                        (false, _) if control & 0x04 > 0 => Some(outside),
+                        _ => None,
+                    };
+                    if let Some(palette) = palette {
+                        self.attributes[y * CELLS_WIDE + x] = palette;
+                    }
+                }
+            }
+        }
+    }
+
+    fn attribute_lines(&mut self, command: &[u8]) {
+        let lines = (command[1] as usize).min(command.len() - 2);
+        for line in &command[2..2 + lines] {
+            let (at, palette) = ((line & 0x1F) as usize, (line >> 5) & 0x03);
+            match line & 0x80 > 0 {
+                true if at < CELLS_HIGH => for x in 0..CELLS_WIDE {
+                    self.attributes[at * CELLS_WIDE + x] = palette;
+                },
+                false if at < CELLS_WIDE => for y in 0..CELLS_HIGH {
+                    self.attributes[y * CELLS_WIDE + at] = palette;
+                },
+                _ => {}
+            }
+        }
+    }
+
+    fn attribute_divide(&mut self, command: &[u8]) {
+        let (after, before, on) = (command[1] & 0x03, (command[1] >> 2) & 0x03,
+                                   (command[1] >> 4) & 0x03);
+        let across = command[1] & 0x40 > 0;
+        let at = command[2] as usize;
+        for y in 0..CELLS_HIGH {
+            for x in 0..CELLS_WIDE {
+                let position = if across { y } else { x };
+                self.attributes[y * CELLS_WIDE + x] = match position {
+                    p if p < at => before,
+                    p if p == at => on,
+                    _ => after,
+                };
+            }
+        }
+    }
+
+    fn attribute_cells(&mut self, command: &[u8]) {
+        let (mut x, mut y) = (command[1] as usize, command[2] as usize);
+        let count = command[3] as usize | (command[4] as usize) << 8;
+        let down = command[5] & 0x01 > 0;
+        let palettes = command[6..].iter()
+            .flat_map(|byte| (0..4).rev().map(move |n| (byte >> (n * 2)) & 0x03));
+        for palette in palettes.take(count) {
+            if x >= CELLS_WIDE || y >= CELLS_HIGH {
+                break;
+            }
+            self.attributes[y * CELLS_WIDE + x] = palette;
+            match down {
+                false => { x += 1; if x == CELLS_WIDE { x = 0; y += 1; } }
+                true => { y += 1; if y == CELLS_HIGH { y = 0; x += 1; } }
+            }
+        }
+    }
+
+    fn palette_set(&mut self, command: &[u8]) {
+        for palette in 0..4 {
+            let number = (command[1 + palette * 2] as usize |
+                          (command[2 + palette * 2] as usize) << 8) & 0x1FF;
+            let data = &self.system_palettes[number * 8..number * 8 + 8];
+            for colour in 0..4 {
+                self.palettes[palette][colour] = rgb(data[colour * 2], data[colour * 2 + 1]);
+            }
+        }
+        // Colour 0 is shared, the first palette's wins.
+        let shared = self.palettes[0][0];
+        for palette in self.palettes.iter_mut() {
+            palette[0] = shared;
+        }
+        if command[9] & 0x80 > 0 {
+            self.attribute_file(command[9] & 0x3F);
+        }
+        if command[9] & 0x40 > 0 {
+            self.mask = Mask::None;
+        }
+        self.draw_border();
+    }
+
+    /// ATTR_SET, or an attribute file picked by PAL_SET.
+    fn attribute_file(&mut self, data: u8) {
+        let file = (data & 0x3F) as usize;
+        if file < 45 {
+            let cells = self.attribute_files[file * 90..file * 90 + 90].iter()
+                .flat_map(|byte| (0..4).rev().map(move |n| (byte >> (n * 2)) & 0x03));
+            for (attribute, palette) in self.attributes.iter_mut().zip(cells) {
+                *attribute = palette;
+            }
+        }
+        if data & 0x40 > 0 {
+            self.mask = Mask::None;
+        }
+    }
+
+    /// The 4K sent by the frame after a transfer command.
+    pub fn transfer_done(&mut self, data: &[u8]) {
+        match self.transfer.take() {
+            Some(Transfer::Tiles(first)) => {
+                self.tiles[first * 32..first * 32 + 4096].copy_from_slice(data);
+                self.draw_border();
+            }
+            Some(Transfer::Picture) => {
+                self.picture.copy_from_slice(data);
+                self.draw_border();
+            }
+            Some(Transfer::Palettes) => self.system_palettes.copy_from_slice(data),
+            Some(Transfer::Attributes) => self.attribute_files.copy_from_slice(data),
+            None => {}
+        }
+    }
+
+    /// The colour of a shade at a pixel of the gameboy screen.
+    pub fn colour(&self, x: usize, y: usize, shade: u8) -> [u8; 3] {
+        let palette = self.attributes[(y / 8) * CELLS_WIDE + x / 8] as usize;
+        self.palettes[palette][shade as usize]
+    }
+
+    /// What goes behind the screen and any clear part of the border.
+    pub fn backdrop(&self) -> [u8; 3] {
+        self.palettes[0][0]
+    }
+
+    /// The border as RGB, 256 by 224, with the screen to be drawn over the middle.
+    pub fn border(&self) -> &[u8] {
+        &self.border
+    }
+
+    /// Draw the border from its tiles, map and palettes.  Colour 0 of each tile is clear.
+    fn draw_border(&mut self) {
+        let backdrop = self.backdrop();
+        for (ty, tx) in (0..HEIGHT / 8).flat_map(|ty| (0..WIDTH / 8).map(move |tx| (ty, tx))) {
+            let entry = (ty * 32 + tx) * 2;
+            let (tile, flags) = (self.picture[entry] as usize, self.picture[entry + 1]);
+            let palette = ((flags >> 2) & 0x07) as usize;
+            let (x_flip, y_flip) = (flags & 0x40 > 0, flags & 0x80 > 0);
+            for row in 0..8 {
+                let source = if y_flip { 7 - row } else { row };
+                let planes = [
+                    self.tiles[tile * 32 + source * 2],
+                    self.tiles[tile * 32 + source * 2 + 1],
+                    self.tiles[tile * 32 + 16 + source * 2],
+                    self.tiles[tile * 32 + 16 + source * 2 + 1],
+                ];
+                for column in 0..8 {
+                    let bit = if x_flip { column } else { 7 - column };
+                    let index = planes.iter().enumerate()
+                        .fold(0, |index, (n, plane)| index | ((plane >> bit) & 0x01) << n) as usize;
+                    // The border uses palettes 4 to 7, sent after the map.
+                    let colour = match (index, palette) {
+                        (0, _) | (_, 0..=3) => backdrop,
+                        _ => {
+                            let at = 0x800 + ((palette - 4) * 16 + index) * 2;
+                            rgb(self.picture[at], self.picture[at + 1])
+                        }
+                    };
+                    let pixel = ((ty * 8 + row) * WIDTH + tx * 8 + column) * 3;
+                    self.border[pixel..pixel + 3].copy_from_slice(&colour);
+                }
+            }
+        }
+    }
+}
+
+
+impl State for Sgb {
+    fn state(&mut self, snapshot: &mut Snapshot) {
+        snapshot.bytes(&mut self.packet);
+        snapshot.option(&mut self.bits, || 0, |snapshot, bits| snapshot.usize(bits));
+        snapshot.u8(&mut self.lines);
+        snapshot.vec(&mut self.command);
+        snapshot.u8(&mut self.players);
+        snapshot.u8(&mut self.player);
+        let masks = [Mask::None, Mask::Freeze, Mask::Black, Mask::Colour0];
+        let mut mask = masks.iter().position(|m| *m == self.mask).unwrap() as u8;
+        snapshot.u8(&mut mask);
+        self.mask = masks[mask as usize % masks.len()];
+        let mut transfer = match self.transfer {
+            None => 0,
+            Some(Transfer::Tiles(first)) => 1 + (first > 0) as u8,
+            Some(Transfer::Picture) => 3,
+            Some(Transfer::Palettes) => 4,
+            Some(Transfer::Attributes) => 5,
+        };
+        snapshot.u8(&mut transfer);
+        self.transfer = match transfer {
+            1 => Some(Transfer::Tiles(0)),
+            2 => Some(Transfer::Tiles(128)),
+            3 => Some(Transfer::Picture),
+            4 => Some(Transfer::Palettes),
+            5 => Some(Transfer::Attributes),
+            _ => None,
+        };
+        for colour in self.palettes.iter_mut().flat_map(|palette| palette.iter_mut()) {
+            snapshot.bytes(colour);
+        }
+        snapshot.bytes(&mut self.attributes);
+        snapshot.bytes(&mut self.system_palettes);
+        snapshot.bytes(&mut self.attribute_files);
+        snapshot.bytes(&mut self.tiles);
+        snapshot.bytes(&mut self.picture);
+        // The border is drawn from the rest, rather than kept.
+        if snapshot.is_loading() {
+            self.draw_border();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use gb::mem::sgb::{Sgb, Mask};
+
+    /// Pulse a command in the way games do.
+    fn send(sgb: &mut Sgb, packet: &[u8; 16]) {
+        sgb.write_joypad(0x00);
+        sgb.write_joypad(0x30);
+        for bit in 0..129 {
+            let one = bit < 128 && packet[bit / 8] & (1 << (bit % 8)) > 0;
+            sgb.write_joypad(if one { 0x10 } else { 0x20 });
+            sgb.write_joypad(0x30);
+        }
+    }
+
+    #[test]
+    fn palettes_and_attributes() {
+        let mut sgb = Sgb::new();
+        // PAL01, colour 0 white and palette 1 colour 1 red.
+        let mut packet = [0; 16];
+        packet[0] = 1; // Command 00, one packet.
+        packet[1] = 0xFF;
+        packet[2] = 0x7F;
+        packet[9] = 0x1F;
+        send(&mut sgb, &packet);
+        // ATTR_DIV, palette 1 from row 9 down.
+        let mut packet = [0; 16];
+        packet[0] = 0x06 << 3 | 1;
+        packet[1] = 0x40 | 0x10 | 0x01;
+        packet[2] = 9;
+        send(&mut sgb, &packet);
+        assert_eq!(sgb.colour(0, 0, 0), [0xFF, 0xFF, 0xFF]);
+        assert_eq!(sgb.colour(0, 100, 1), [0xFF, 0, 0]);
+        assert_ne!(sgb.colour(0, 0, 1), [0xFF, 0, 0]);
+        // MASK_EN black.
+        let mut packet = [0; 16];
+        packet[0] = 0x17 << 3 | 1;
+        packet[1] = 2;
+        send(&mut sgb, &packet);
+        assert_eq!(sgb.mask, Mask::Black);
+    }
+
+    #[test]
+    fn multiplayer_moves_to_the_next_controller() {
+        let mut sgb = Sgb::new();
+        let mut packet = [0; 16];
+        packet[0] = 0x11 << 3 | 1;
+        packet[1] = 1;
+        send(&mut sgb, &packet);
+        assert!(sgb.multiplayer());
+        assert_eq!(sgb.player(), 0);
+        for &lines in &[0x20, 0x30, 0x10, 0x30] {
+            sgb.write_joypad(lines);
+        }
+        assert_eq!(sgb.player(), 1);
+    }
+}
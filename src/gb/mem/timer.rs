@@ -1,3 +1,5 @@
+use gb::state::{Snapshot, State};
+
 pub struct Timer {
     div: u8,
     tima: u8,
@@ -50,26 +52,32 @@ impl Timer {
             3 => 7,
             _ => unreachable!("value should only be two bytes"),
         };
-        let mut tac_key = (self.subdiv >> threshold) & 0x01 > 0;
-        let tac_ena = (self.tac & 0x04) > 0;
-
-        for _ in 0..time {
-            // DIV
-            self.subdiv += 1;
-            let new_tac_key = (self.subdiv >> threshold) & 0x01 > 0;
-
-            if  tac_ena && tac_key && !new_tac_key {
-                //TIMA Increment
+        // TIMA counts each time the chosen bit of the counter falls, which is each time the
+        // counter passes a multiple of twice that bit.
+        let before = self.subdiv;
+        self.subdiv += time;
+        if self.tac & 0x04 > 0 {
+            for _ in 0..(self.subdiv >> (threshold + 1)) - (before >> (threshold + 1)) {
                 let (new_tima, overflow) = self.tima.overflowing_add(1);
                 self.tima = new_tima;
                 if overflow {
                     self.tima = self.tma;
                     self.buffered_interupt = true;
                 }
-            };
-
-            tac_key = new_tac_key;
+            }
         }
         self.div = (self.subdiv >> 8 & 0xFF) as u8;
     }
 }
+
+impl State for Timer {
+    fn state(&mut self, snapshot: &mut Snapshot) {
+        snapshot.u8(&mut self.div);
+        snapshot.u8(&mut self.tima);
+        snapshot.u8(&mut self.tma);
+        snapshot.u8(&mut self.tac);
+        snapshot.bool(&mut self.buffered_interupt);
+        snapshot.usize(&mut self.subclock);
+        snapshot.usize(&mut self.subdiv);
+    }
+}
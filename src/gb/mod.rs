@@ -1,6 +1,9 @@
 use std::sync::mpsc;
 use std::thread;
 use std::sync::{Mutex, Arc};
+use std::fs;
+use std::time::SystemTime;
+use std::path::Path;
 use ::{GAMEBOY_WIDTH, GAMEBOY_HEIGHT};
 
 const GAMEBOY_SCREEN_BUFFER_SIZE: u32 = GAMEBOY_WIDTH * GAMEBOY_HEIGHT * 3;
@@ -8,123 +11,858 @@ const GAMEBOY_SCREEN_BUFFER_SIZE: u32 = GAMEBOY_WIDTH * GAMEBOY_HEIGHT * 3;
 mod cpu;
 pub mod mem;
 mod decode;
+mod disasm;
+mod asm;
+mod movie;
+mod state;
+mod video;
+use self::mem::sgb;
+use self::state::{Snapshot, State};
+#[cfg(test)]
+mod harness;
+#[cfg(test)]
+mod rom_tests;
+#[cfg(test)]
+mod screenshot_tests;
+#[cfg(test)]
+mod bench;
 
-enum GbKind {
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum GbKind {
     GB,
-    // SGB,
-    // GBC,
+    SGB,
+    GBC,
 }
 
 pub enum Input {
-    Buttons(mem::Buttons),
+    /// Main is ready for the next frame.
+    Next,
+    /// Frames to run for every frame sent to main, the others are not drawn.
+    Speed(usize),
+    /// Back to the boot rom, or just after it if there is none.
+    Reset,
+    /// Read the rom from disk again and start over, saving first.
+    Reload,
+    /// Start or stop recording video to a new file.
+    Video,
+    /// Start or stop recording a movie to a new file, from the state the game is in.
+    Movie,
+    /// Colours to record video in from now on.
+    Palette([[u8; 3]; 4]),
+    /// Finish any video and save, then stop.
+    Quit,
 }
 
 pub enum Output {
     Frame,
+    /// The cpu hit an illegal opcode and hung, the lcd keeps going.
+    Lockup { pc: u16, opcode: u8, cpu: String },
+    /// The movie being played has run out, input is live from now on.
+    MovieFinished { desynced: bool },
+    /// Frames from now on are in colour rather than greys for the palette.
+    Colour(bool),
+    /// Frames from now on are 256 by 224, the screen in the middle of a Super Game Boy
+    /// border.
+    Border(bool),
+}
+
+/// How the emulator should run, beyond which roms it runs.
+pub struct Settings {
+    /// Reload the rom when it changes on disk.
+    pub watch: bool,
+    /// Carry work ram and cartrage ram over reloads.
+    pub keep_ram: bool,
+    pub movie: Option<Movie>,
+    /// Record video from power on to this file.
+    pub video: Option<String>,
+    /// Colours for the four shades, lightest first, in anything the gameboy thread writes.
+    /// Not used for colour games.
+    pub palette: [[u8; 3]; 4],
+    /// Show monochrome games in colour the way a Game Boy Color would.
+    pub colourise: bool,
+    /// Run games that support it on a Super Game Boy.
+    pub sgb: bool,
+    /// Address of another emulator to connect a link cable to.
+    pub link: Option<String>,
+}
+
+/// Input movie files, see `movie` for what is in them.
+pub enum Movie {
+    Record(String),
+    Play(String),
 }
 
 pub struct GbConnect {
     pub to_gb: mpsc::Sender<Input>,
     pub from_gb: mpsc::Receiver<Output>,
-    pub canvas: Arc<Mutex<Box<[u8; GAMEBOY_SCREEN_BUFFER_SIZE as usize]>>>,
+    pub canvas: Arc<Mutex<Box<[u8]>>>,
+    /// Set whenever the buttons held change.
+    pub buttons: mem::SharedButtons,
+}
+
+impl GbConnect {
+    /// Have the gameboy finish up, waiting until it has.
+    pub fn stop(self) {
+        // It may be waiting on a frame, or running one and then waiting.
+        if self.to_gb.send(Input::Quit).is_ok() {
+            while self.from_gb.recv().is_ok() {}
+        }
+    }
 }
 
 struct Gb {
     cpu: cpu::Cpu,
     mem: mem::Mem,
+    rom: u64, // Hash of the cartrage rom, which save states go with.
+}
+
+/// Runs a `Gb` on its own thread, handing frames to main and taking input back.
+struct Runner {
+    gb: Gb,
+    roms: (String, Option<String>),
     to_main: mpsc::Sender<Output>,
     from_main: mpsc::Receiver<Input>,
-    front_buffer: Arc<Mutex<Box<[u8; GAMEBOY_SCREEN_BUFFER_SIZE as usize]>>>,
+    front_buffer: Arc<Mutex<Box<[u8]>>>,
+    locked: bool,
+    speed: usize,
+    skipped: usize, // Frames run since the last one sent.
+    settings: Settings,
+    watched: Option<SystemTime>, // When the rom last changed, as running now.
+    changed: Option<SystemTime>, // A change seen on disk, waiting to settle.
+    frames_sent: usize,
+    live: mem::SharedButtons, // Set by main.
+    // What the gameboy reads.  The same as live, except with a movie the buttons are only
+    // taken once a frame, so it plays back the same.
+    input: mem::SharedButtons,
+    recorder: Option<movie::Recorder>,
+    player: Option<movie::Player>,
+    video: Option<video::Video>,
+    bordered: Vec<u8>, // The screen in the Super Game Boy border.
 }
 
+/// Frames between checks on the rom when watching it.
+const WATCH_FRAMES: usize = 30;
+
 pub fn disasemble(rom: &str) {
-    use gb::decode;
     use std::fs::File;
     use std::io::Read;
 
     match File::open(rom) {
         Ok(mut file) => {
             let mut buffer = Vec::new();
-            let size = file.read_to_end(&mut buffer).unwrap();
-            decode::disasemble(buffer);
+            file.read_to_end(&mut buffer).unwrap();
+            print!("{}", disasm::Disassembly::new(&buffer).to_rgbds());
         }
         Err(_) => eprintln!("File dose not exist"),
     }
 }
 
-pub fn connect(roms: (String, Option<String>)) -> GbConnect {
+pub fn assemble(source: &str) -> Result<(), String> {
+    use std::fs;
+    use std::io::Write;
+
+    let text = fs::read_to_string(source).map_err(|e| format!("{}: {}", source, e))?;
+    let bytes = asm::assemble(&text, 0).map_err(|e| format!("{}: {}", source, e))?;
+    std::io::stdout().write_all(&bytes).map_err(|e| e.to_string())
+}
+
+pub fn connect(roms: (String, Option<String>), mut settings: Settings) -> Result<GbConnect, String> {
+    let boot_rom = roms.1.is_some();
+    let video = match settings.video {
+        Some(ref path) => Some(video::Video::create(path)?),
+        None => None,
+    };
+    let player = match settings.movie {
+        Some(Movie::Play(ref path)) => Some(movie::Player::open(path, boot_rom)?),
+        _ => None,
+    };
+    // A movie plays back on the machine it was recorded on.
+    if let Some(ref player) = player {
+        let start = player.start();
+        if (start.sgb, start.colourise) != (settings.sgb, settings.colourise) {
+            println!("Playing as recorded, with sgb {} and colourise {}", start.sgb, start.colourise);
+        }
+        settings.sgb = start.sgb;
+        settings.colourise = start.colourise;
+    }
+    let recorder = match settings.movie {
+        Some(Movie::Record(ref path)) => {
+            let start = movie::Start {
+                boot_rom,
+                sgb: settings.sgb,
+                colourise: settings.colourise,
+                state: None,
+            };
+            Some(movie::Recorder::create(path, &start)?)
+        }
+        _ => None,
+    };
+    let cable = match settings.link {
+        Some(ref address) => Some(mem::link::Cable::new(Box::new(mem::link::TcpLink::connect(address)?))),
+        None => None,
+    };
     let (to_gb, from_main) = mpsc::channel();
     let (to_main, from_gb) = mpsc::channel();
     let canvas = Arc::new(Mutex::new(
-            Box::new([0; GAMEBOY_SCREEN_BUFFER_SIZE as usize])));
+            vec![0; GAMEBOY_SCREEN_BUFFER_SIZE as usize].into_boxed_slice()));
+
+    let buttons = mem::SharedButtons::default();
+    let live = buttons.clone();
+    let input = match settings.movie {
+        Some(_) => mem::SharedButtons::default(),
+        None => live.clone(),
+    };
 
+    let mut gb = Gb::new(roms.clone(), settings.sgb)?;
+    let from_state = match player.as_ref().and_then(|p| p.start().state.as_ref()) {
+        Some(state) => {
+            gb.load_state(state).map_err(|e| format!("The movie's state will not load, {}", e))?;
+            true
+        }
+        None => false,
+    };
     let front_buffer = Arc::clone(&canvas);
     thread::Builder::new().name("GB".to_string()).spawn(move || {
-        Gb::new(GbKind::GB, roms, to_main, from_main, front_buffer).unwrap().cycle();
+        gb.mem.connect_input(input.clone());
+        if let Some(cable) = cable {
+            gb.mem.connect_link(cable);
+        }
+        let watched = modified(&roms.0);
+        let mut runner = Runner {
+            gb, roms, to_main, from_main, front_buffer,
+            locked: false,
+            speed: 1,
+            skipped: 0,
+            settings,
+            watched,
+            changed: None,
+            frames_sent: 0,
+            live,
+            input,
+            recorder,
+            player,
+            video,
+            bordered: vec![0; sgb::WIDTH * sgb::HEIGHT * 3],
+        };
+        runner.play_input();
+        if runner.recorder.is_some() {
+            runner.input.set(runner.live.get());
+        }
+        // A saved state already has its colours.
+        if !from_state {
+            runner.colourise();
+        }
+        runner.to_main.send(Output::Colour(runner.colour())).unwrap();
+        runner.to_main.send(Output::Border(runner.gb.mem.border().is_some())).unwrap();
+        runner.cycle();
     }).unwrap();
 
-    GbConnect { to_gb, from_gb, canvas }
+    Ok(GbConnect { to_gb, from_gb, canvas, buttons })
 }
 
 impl Gb {
-    fn new(kind: GbKind,
-           roms: (String, Option<String>),
-           to_main: mpsc::Sender<Output>,
-           from_main: mpsc::Receiver<Input>,
-           front_buffer: Arc<Mutex<Box<[u8; GAMEBOY_SCREEN_BUFFER_SIZE as usize]>>>)
-           -> Option<Gb> {
-        match kind {
-            GbKind::GB => {
-                match &roms.1 { 
-                    Some(bootrom) => Some(Gb {
-                            cpu: cpu::Cpu::new(),
-                            mem: mem::Mem::new_gb(
-                                mem::GbMapper::new_with_boot_rom(bootrom.to_string(), 
-                                                                 roms.0)),
-                            to_main,
-                            from_main,
-                            front_buffer,
-                        }),
-                    None => Some(Gb {
-                            cpu: cpu::Cpu::new_after_boot(),
-                            mem: mem::Mem::new_gb(mem::GbMapper::new(roms.0)),
-                            to_main,
-                            from_main,
-                            front_buffer,
-                        })
+    /// Which machine to be comes from the cartrage header, games for the Super Game Boy
+    /// only run on one when `sgb` is set.
+    fn new(roms: (String, Option<String>), sgb: bool) -> Result<Gb, String> {
+        let mapper = match &roms.1 {
+            Some(bootrom) => mem::GbMapper::new_with_boot_rom(bootrom.to_string(), roms.0)?,
+            None => mem::GbMapper::new(roms.0)?,
+        };
+        let mapper = match sgb {
+            true => mapper.with_sgb(),
+            false => mapper,
+        };
+        let mem = mem::Mem::new_gb(mapper);
+        let cpu = match roms.1 {
+            Some(_) => cpu::Cpu::new(),
+            None => cpu::Cpu::new_after_boot(mem.kind()),
+        };
+        let rom = movie::checksum(movie::CHECKSUM_START, mem.rom());
+        Ok(Gb { cpu, mem, rom })
+    }
+
+    /// Starts after the boot rom, keeping serial output in memory.
+    #[cfg(test)]
+    fn headless(rom: String) -> Gb {
+        let mem = mem::Mem::new_gb(mem::GbMapper::new(rom).unwrap().without_serial_file());
+        let rom = movie::checksum(movie::CHECKSUM_START, mem.rom());
+        Gb { cpu: cpu::Cpu::new_after_boot(mem.kind()), mem, rom }
+    }
+
+    /// Power cycle, keeping the cartrage as it is.
+    fn reset(&mut self, boot_rom: bool) {
+        self.cpu = match boot_rom {
+            true => cpu::Cpu::new(),
+            false => cpu::Cpu::new_after_boot(self.mem.kind()),
+        };
+        self.mem.reset();
+    }
+
+    /// Run one instruction, and an interupt if one is raised.
+    /// Returns true when a frame has been finished.
+    fn step(&mut self) -> bool {
+        self.cpu.cycle(&mut self.mem);
+        if self.mem.check_interupt() && self.cpu.lockup().is_none() {
+            self.cpu.handle_interupt(&mut self.mem);
+        }
+        self.mem.take_frame()
+    }
+
+    fn save_state(&mut self) -> Vec<u8> {
+        let mut snapshot = Snapshot::saving();
+        self.state(&mut snapshot);
+        snapshot.saved()
+    }
+
+    /// Carry on from a state saved with the same rom.  A state that does not load leaves
+    /// the machine in a mess, so it should be reset or thrown away after.
+    fn load_state(&mut self, bytes: &[u8]) -> Result<(), String> {
+        let mut snapshot = Snapshot::loading(bytes);
+        self.state(&mut snapshot);
+        snapshot.loaded()
+    }
+
+    /// The whole machine hashed together, so two runs can be compared frame by frame.
+    /// The screen is not in it, it is only drawn when it will be shown.
+    fn checksum(&mut self) -> u64 {
+        movie::checksum(movie::CHECKSUM_START, &self.save_state())
+    }
+}
+
+/// The rom goes first, as a hash, so a state is only loaded into the game it came from.
+impl State for Gb {
+    fn state(&mut self, snapshot: &mut Snapshot) {
+        let mut rom = self.rom;
+        snapshot.u64(&mut rom);
+        if rom != self.rom {
+            snapshot.reject("the state was saved with a different rom");
+        }
+        self.cpu.state(snapshot);
+        self.mem.state(snapshot);
+    }
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Which of the four shades the core drew, from the greys of `GBP::apply`.
+pub fn shade_index(grey: u8) -> usize {
+    match grey {
+        255 => 0,
+        170 => 1,
+        85 => 2,
+        _ => 3,
+    }
+}
+
+/// A file in the current directory named after the rom that is not there yet,
+/// like tetris-3.y4m.
+pub fn capture_path(rom: &str, extension: &str) -> String {
+    let stem = Path::new(rom).file_stem().map_or("fegabo".into(), |s| s.to_string_lossy());
+    (1..).map(|n| format!("{}-{}.{}", stem, n, extension))
+        .find(|path| !Path::new(path).exists())
+        .unwrap()
+}
+
+impl Runner {
+    /// Power cycle, saving first.
+    fn reset(&mut self) {
+        self.gb.mem.flush_save();
+        self.gb.reset(self.roms.1.is_some());
+        self.colourise();
+        self.locked = false;
+        self.record(movie::Event::Reset);
+        println!("Reset");
+    }
+
+    fn colour(&self) -> bool {
+        self.gb.mem.kind() != GbKind::GB || self.settings.colourise
+    }
+
+    /// Colours are picked at power on, from the buttons held then like a Game Boy Color.
+    /// They are the buttons the gameboy reads, so a movie picks the same ones.
+    fn colourise(&mut self) {
+        if self.settings.colourise {
+            self.gb.mem.colourise(&self.input.get());
+        }
+    }
+
+    /// Start over from the rom on disk, saving first.  If it will not load the current
+    /// game keeps running.
+    fn reload(&mut self) {
+        self.gb.mem.flush_save();
+        let gb = match Gb::new(self.roms.clone(), self.settings.sgb) {
+            Ok(gb) => gb,
+            Err(e) => {
+                eprintln!("Reload failed, {}", e);
+                self.watched = modified(&self.roms.0);
+                self.changed = None;
+                return;
+            }
+        };
+        let ram = match self.settings.keep_ram {
+            true => Some(self.gb.mem.ram_snapshot()),
+            false => None,
+        };
+        let (colour, border) = (self.colour(), self.gb.mem.border().is_some());
+        let cable = self.gb.mem.take_link();
+        self.gb = gb;
+        if let Some(cable) = cable {
+            self.gb.mem.connect_link(cable);
+        }
+        self.colourise();
+        if self.colour() != colour {
+            self.to_main.send(Output::Colour(self.colour())).unwrap();
+        }
+        if self.gb.mem.border().is_some() != border {
+            self.to_main.send(Output::Border(!border)).unwrap();
+            // A video cannot change size part way.
+            if let Some(video) = self.video.take() {
+                video.finish();
+            }
+        }
+        self.gb.mem.connect_input(self.input.clone());
+        if let Some((wram, cartrage_ram)) = ram {
+            self.gb.mem.restore_ram(&wram, &cartrage_ram);
+        }
+        self.skip_rendering();
+        self.locked = false;
+        self.watched = modified(&self.roms.0);
+        self.changed = None;
+        self.record(movie::Event::Reload);
+        println!("Reloaded {}", self.roms.0);
+    }
+
+    /// Reload once the rom has changed and then stayed the same between two checks,
+    /// so a build still writing it is not loaded half done.
+    fn check_rom(&mut self) {
+        let now = modified(&self.roms.0);
+        if now.is_none() || now == self.watched {
+            self.changed = None;
+        }
+        else if now == self.changed {
+            self.reload();
+        }
+        else {
+            self.changed = now;
+        }
+    }
+
+    fn record(&mut self, event: movie::Event) {
+        if let Some(ref mut recorder) = self.recorder {
+            recorder.record(&event);
+        }
+    }
+
+    /// Hold the buttons for the coming frame, which are held through any reset or reload
+    /// the movie did before it, then do those.  Once it runs out input comes from main again.
+    fn play_input(&mut self) {
+        let next = self.player.as_ref().map(|p| (p.buttons(), p.desynced()));
+        if let Some((Some(buttons), _)) = next {
+            self.input.set(buttons);
+        }
+        while let Some(event) = self.player.as_mut().and_then(|p| p.next_event()) {
+            match event {
+                movie::Event::Reset => self.reset(),
+                movie::Event::Reload => self.reload(),
+                movie::Event::Frame(_, _) => unreachable!("Frames are not events"),
+            }
+        }
+        if let Some((None, desynced)) = next {
+            self.player = None;
+            self.input = self.live.clone();
+            self.gb.mem.connect_input(self.input.clone());
+            self.to_main.send(Output::MovieFinished { desynced }).unwrap();
+            match desynced {
+                true => println!("Movie finished, it did not play back the same"),
+                false => println!("Movie finished"),
+            }
+        }
+    }
+
+    /// Start recording a movie from the state the game is in now, or stop the one being
+    /// recorded.  Input goes through the movie's buttons while it records.
+    fn toggle_movie(&mut self) {
+        if self.player.is_some() {
+            println!("A movie is playing, it cannot be recorded over");
+            return;
+        }
+        if self.recorder.take().is_some() {
+            self.input = self.live.clone();
+            self.gb.mem.connect_input(self.input.clone());
+            println!("Stopped recording the movie");
+            return;
+        }
+        let path = capture_path(&self.roms.0, "movie");
+        let start = movie::Start {
+            boot_rom: self.roms.1.is_some(),
+            sgb: self.settings.sgb,
+            colourise: self.settings.colourise,
+            state: Some(self.gb.save_state()),
+        };
+        match movie::Recorder::create(&path, &start) {
+            Ok(recorder) => {
+                self.recorder = Some(recorder);
+                self.input = mem::SharedButtons::default();
+                self.input.set(self.live.get());
+                self.gb.mem.connect_input(self.input.clone());
+                println!("Recording a movie to {}", path);
+            }
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+
+    /// Record or check the frame just finished, and pick the buttons for the next one.
+    fn movie_frame(&mut self) {
+        if self.recorder.is_none() && self.player.is_none() {
+            return;
+        }
+        let sum = self.gb.checksum();
+        let buttons = self.input.get();
+        self.record(movie::Event::Frame(buttons, sum));
+        if let Some(ref mut player) = self.player {
+            player.check(sum);
+        }
+        self.play_input();
+        if self.recorder.is_some() {
+            self.input.set(self.live.get());
+        }
+    }
+
+    /// Only the last frame of each group sent is drawn, unless they are all recorded.
+    fn skip_rendering(&mut self) {
+        let skip = self.skipped + 1 < self.speed && self.video.is_none();
+        self.gb.mem.skip_rendering(skip);
+    }
+
+    /// Put the screen in the middle of the Super Game Boy border, if there is one.
+    /// The screen is copied rather than swapped, so a frozen screen stays.
+    fn draw_border(&mut self) -> bool {
+        let border = match self.gb.mem.border() {
+            Some(border) => border,
+            None => return false,
+        };
+        self.bordered.copy_from_slice(border);
+        let row = GAMEBOY_WIDTH as usize * 3;
+        for (y, line) in self.gb.mem.screen().chunks(row).enumerate() {
+            let at = ((sgb::TOP + y) * sgb::WIDTH + sgb::LEFT) * 3;
+            self.bordered[at..at + row].copy_from_slice(line);
+        }
+        true
+    }
+
+    fn toggle_video(&mut self) {
+        match self.video.take() {
+            Some(video) => video.finish(),
+            None => match video::Video::create(&capture_path(&self.roms.0, "y4m")) {
+                Ok(video) => self.video = Some(video),
+                Err(e) => eprintln!("{}", e),
+            },
+        }
+        self.skip_rendering();
+    }
+
+    /// Send frame by swapping buffers and telling main to do something.
+    /// Main answers with `Next` once it wants the next one.
+    /// Returns false once main wants to stop.
+    fn send_frame(&mut self) -> bool {
+        {
+            let mut front = self.front_buffer.lock().unwrap();
+            match self.gb.mem.border() {
+                Some(_) if front.len() == self.bordered.len() => front.copy_from_slice(&self.bordered),
+                Some(_) => *front = self.bordered.clone().into_boxed_slice(),
+                None => self.gb.mem.screen_swap(&mut front),
+            }
+        }
+        self.to_main.send(Output::Frame).unwrap();
+        self.frames_sent += 1;
+        if self.settings.watch && self.frames_sent.is_multiple_of(WATCH_FRAMES) {
+            self.check_rom();
+        }
+        loop {
+            match self.from_main.recv() {
+                Ok(Input::Next) => return true,
+                Ok(Input::Speed(speed)) => self.speed = speed.max(1),
+                Ok(Input::Reset) => self.reset(),
+                Ok(Input::Reload) => self.reload(),
+                Ok(Input::Video) => self.toggle_video(),
+                Ok(Input::Movie) => self.toggle_movie(),
+                Ok(Input::Palette(colours)) => self.settings.palette = colours,
+                Ok(Input::Quit) => {
+                    if let Some(video) = self.video.take() {
+                        video.finish();
+                    }
+                    self.gb.mem.flush_save();
+                    return false;
                 }
+                Err(_) => panic!("Main has gone away"),
             }
-            // _ => None,
         }
     }
 
     fn cycle(mut self) {
         println!("Everything is set up!!!!");
-        'cycle_loop: loop {
-            let time = self.cpu.cycle(&mut self.mem);
-            self.time_passes(time); 
-            if let Some(interupt) = self.mem.check_interupt() {
-                self.cpu.handle_interupt(interupt, &mut self.mem);
-                self.time_passes(16); 
+        loop {
+            let frame = self.gb.step();
+            if !self.locked {
+                if let Some((pc, opcode)) = self.gb.cpu.lockup() {
+                    self.locked = true;
+                    let cpu = format!("{:02X?}", self.gb.cpu);
+                    self.to_main.send(Output::Lockup { pc, opcode, cpu }).unwrap();
+                }
+            }
+            if frame {
+                self.movie_frame();
+                let bordered = self.draw_border();
+                let palette = match self.colour() {
+                    true => None,
+                    false => Some(&self.settings.palette),
+                };
+                if let Some(ref mut video) = self.video {
+                    match bordered {
+                        true => video.frame(&self.bordered, sgb::WIDTH, palette),
+                        false => video.frame(self.gb.mem.screen(), GAMEBOY_WIDTH as usize, palette),
+                    }
+                }
+                self.skipped += 1;
+                if self.skipped >= self.speed {
+                    self.skipped = 0;
+                    if !self.send_frame() {
+                        return;
+                    }
+                }
+                self.skip_rendering();
             }
         }
     }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use gb::{asm, movie};
+    use gb::{connect, GbConnect, Gb, Input, Movie, Output, Settings};
+    use gb::mem::{sgb, Buttons, SharedButtons};
+
+    /// A file in the temporary directory only this test run uses, so runs side by side
+    /// do not write over each other's roms and movies.
+    pub fn temp_path(name: &str) -> String {
+        let path = env::temp_dir().join(format!("fegabo-{}-{}", process::id(), name));
+        path.to_string_lossy().into_owned()
+    }
+
+    /// Writes a 32KB rom only cartrage running `code` from 0150, with the header bytes
+    /// given, and returns where it is.
+    pub fn rom(name: &str, code: &str, header: &[(usize, u8)]) -> String {
+        let mut rom = vec![0; 0x8000];
+        let entry = asm::assemble("NOP\nJP $0150", 0x0100).unwrap();
+        rom[0x0100..0x0100 + entry.len()].copy_from_slice(&entry);
+        let code = asm::assemble(code, 0x0150).unwrap_or_else(|e| panic!("{}", e));
+        rom[0x0150..0x0150 + code.len()].copy_from_slice(&code);
+        for &(at, byte) in header {
+            rom[at] = byte;
+        }
+        let path = temp_path(&format!("{}.gb", name));
+        fs::write(&path, rom).unwrap();
+        path
+    }
 
-    fn time_passes(&mut self, time: usize) {
-        if let Some(rows) = self.mem.time_passes(time) {
-            for r in rows {
-                if self.mem.render(r as usize) {
-                    // Send frame by swapping buffers and telling main to do something.
-                    self.mem.screen_swap(&mut self.front_buffer.lock().unwrap());
-                    self.to_main.send(Output::Frame).unwrap();
-                    // This should be updated button info.
-                    match self.from_main.recv() {
-                        Ok(super::Input::Buttons(buttons)) => self.mem.update_input(buttons),
-                        // Err(_) => break 'cycle_loop,
-                        _ => panic!("Unknown message from main"),
-                    }
+    pub fn settings() -> Settings {
+        Settings {
+            watch: false,
+            keep_ram: false,
+            movie: None,
+            video: None,
+            palette: [[0xFF; 3], [0xAA; 3], [0x55; 3], [0; 3]],
+            colourise: false,
+            sgb: false,
+            link: None,
+        }
+    }
+
+    /// Run a gameboy thread for some frames, returning what it finished with.
+    fn frames(gb: &GbConnect, frames: usize) -> Option<Output> {
+        for _ in 0..frames {
+            match gb.from_gb.recv().unwrap() {
+                Output::Frame => gb.to_gb.send(Input::Next).unwrap(),
+                Output::MovieFinished { desynced } => return Some(Output::MovieFinished { desynced }),
+                _ => {}
+            }
+        }
+        None
+    }
+
+    /// Steps to the end of the frame being run.
+    fn frame(gb: &mut Gb) {
+        while !gb.step() {}
+    }
+
+    /// Keeps writing the joypad to C000, so the buttons held change memory.
+    const READ_JOYPAD: &str = "loop: LD A, 20\nLD ($FF00), A\nLD A, ($FF00)\nLD ($C000), A\nJR loop";
+
+    /// Code that sends a Super Game Boy packet through the joypad register.
+    fn send_packet(packet: &[u8; 16]) -> String {
+        let pulse = |lines: u8| format!("LD A, {:02X}\nLD ($FF00), A\nLD A, 30\nLD ($FF00), A\n", lines);
+        let mut code = pulse(0x00);
+        for bit in (0..128).map(|i| packet[i / 8] >> (i % 8) & 1) {
+            code += &pulse(if bit == 1 { 0x10 } else { 0x20 });
+        }
+        code + &pulse(0x20)
+    }
+
+    #[test]
+    fn super_game_boy_frames_are_in_colour() {
+        // PAL01 with colour 0 red, the blank screen is all colour 0.
+        let mut packet = [0; 16];
+        packet[0] = 0x01;
+        packet[1] = 0x1F;
+        let code = send_packet(&packet) + "loop: JR loop";
+        let path = rom("sgb-colour", &code, &[(0x146, 0x03), (0x14B, 0x33)]);
+        let gb = connect((path, None), Settings { sgb: true, ..settings() }).unwrap();
+
+        let mut colour = false;
+        let mut frames = 0;
+        while frames < 3 {
+            match gb.from_gb.recv().unwrap() {
+                Output::Colour(in_colour) => colour = in_colour,
+                Output::Frame => {
+                    frames += 1;
+                    gb.to_gb.send(Input::Next).unwrap();
                 }
+                _ => {}
             }
         }
+        assert!(colour, "Main would draw the frame with its greys");
+        {
+            let frame = gb.canvas.lock().unwrap();
+            assert_eq!(frame.len(), sgb::WIDTH * sgb::HEIGHT * 3);
+            let middle = ((sgb::TOP + 72) * sgb::WIDTH + sgb::LEFT + 80) * 3;
+            assert_eq!(&frame[middle..middle + 3], &[0xFF, 0, 0]);
+        }
+        gb.stop();
+    }
+
+    #[test]
+    fn a_button_wakes_stop() {
+        let code = "LD A, 10\nLD ($FF00), A\nSTOP\nLD A, 42\nLD ($C000), A\nloop: JR loop";
+        let mut gb = Gb::headless(rom("stop", code, &[]));
+        let input = SharedButtons::default();
+        gb.mem.connect_input(input.clone());
+        for _ in 0..1000 {
+            gb.step();
+        }
+        assert_ne!(gb.mem.load_8(0xC000), 0x42, "Ran on past STOP");
+
+        input.set(Buttons { start: true, ..Buttons::default() });
+        for _ in 0..1000 {
+            gb.step();
+        }
+        assert_eq!(gb.mem.load_8(0xC000), 0x42, "Still stopped with a button down");
+    }
+
+    #[test]
+    fn a_broken_rom_is_not_loaded() {
+        let missing = temp_path("missing.gb");
+        assert!(connect((missing, None), settings()).is_err());
+
+        let path = rom("reload", "loop: JR loop", &[]);
+        let gb = connect((path.clone(), None), settings()).unwrap();
+        fs::write(&path, [0; 0x100]).unwrap();
+        gb.to_gb.send(Input::Reload).unwrap();
+        let mut frames = 0;
+        while frames < 3 {
+            if let Output::Frame = gb.from_gb.recv().expect("The reload stopped the game") {
+                frames += 1;
+                gb.to_gb.send(Input::Next).unwrap();
+            }
+        }
+        gb.stop();
+        let error = connect((path, None), settings()).err().unwrap();
+        assert!(error.contains("too short"), "{}", error);
+    }
+
+    #[test]
+    fn a_saved_state_carries_on_the_same() {
+        for &(name, header) in [("state", &[][..]), ("state-cgb", &[(0x143, 0x80)][..])].iter() {
+            let path = rom(name, READ_JOYPAD, header);
+            let mut first = Gb::headless(path.clone());
+            for _ in 0..20 {
+                frame(&mut first);
+            }
+            let state = first.save_state();
+            let mut second = Gb::headless(path);
+            second.load_state(&state).unwrap();
+            for _ in 0..20 {
+                frame(&mut first);
+                frame(&mut second);
+                assert_eq!(first.checksum(), second.checksum(), "{}", name);
+            }
+            assert!(second.load_state(&state[..state.len() - 1]).is_err());
+        }
+        let state = Gb::headless(rom("state", READ_JOYPAD, &[])).save_state();
+        let mut other = Gb::headless(rom("state-other", "loop: JR loop", &[]));
+        let error = other.load_state(&state).unwrap_err();
+        assert!(error.contains("different rom"), "{}", error);
+    }
+
+    #[test]
+    fn a_movie_plays_from_its_state() {
+        let path = rom("movie-state", READ_JOYPAD, &[]);
+        let mut gb = Gb::headless(path.clone());
+        let input = SharedButtons::default();
+        gb.mem.connect_input(input.clone());
+        for _ in 0..10 {
+            frame(&mut gb);
+        }
+        let start = movie::Start { boot_rom: false, sgb: false, colourise: false,
+                                   state: Some(gb.save_state()) };
+        let movie_path = temp_path("state.movie");
+        let mut recorder = movie::Recorder::create(&movie_path, &start).unwrap();
+        for i in 0..30 {
+            let buttons = Buttons { down: i % 3 == 0, ..Buttons::default() };
+            input.set(buttons);
+            frame(&mut gb);
+            recorder.record(&movie::Event::Frame(buttons, gb.checksum()));
+        }
+        drop(recorder);
+
+        let playing = Settings { movie: Some(Movie::Play(movie_path.clone())), ..settings() };
+        let player = connect((path, None), playing).unwrap();
+        match frames(&player, 100) {
+            Some(Output::MovieFinished { desynced }) => assert!(!desynced, "It played back differently"),
+            _ => panic!("The movie did not finish"),
+        }
+        player.stop();
+
+        let other = rom("movie-other", "loop: JR loop", &[]);
+        let playing = Settings { movie: Some(Movie::Play(movie_path)), ..settings() };
+        assert!(connect((other, None), playing).is_err());
+    }
+
+    #[test]
+    fn colours_are_picked_by_the_movie() {
+        let path = rom("movie-colour", "loop: JR loop", &[]);
+        let movie_path = temp_path("colour.movie");
+        // Right and B for the inverted colours, whatever is held live.
+        let movie = "FeGaBo movie 2\nstart after-boot\nsgb no\ncolourise yes\n".to_string()
+            + &"...R..B. 0\n".repeat(10);
+        fs::write(&movie_path, movie).unwrap();
+
+        for (movie, colour) in [(None, [0xFF; 3]), (Some(movie_path), [0; 3])] {
+            let playing = Settings {
+                colourise: true,
+                movie: movie.map(Movie::Play),
+                ..settings()
+            };
+            let gb = connect((path.clone(), None), playing).unwrap();
+            // The first frame sent is blank.
+            frames(&gb, 5);
+            assert_eq!(&gb.canvas.lock().unwrap()[..3], &colour, "Colour 0");
+            gb.stop();
+        }
     }
 }
@@ -0,0 +1,290 @@
+//! Input movies, the buttons held for every frame from power on so a run can be played back
+//! exactly.  Every frame also keeps a checksum of the machine, so playback can tell the
+//! moment it stops matching the recording.
+//!
+//! Movies are text.  A `FeGaBo movie 2` line comes first, then how the machine started:
+//!
+//! ```text
+//! start after-boot
+//! sgb no
+//! colourise yes
+//! state 00a1...
+//! ```
+//!
+//! `start power-on` is with a boot rom and `start after-boot` without, which resets go back
+//! to as well.  `sgb` and `colourise` are the settings it was recorded with, and play back
+//! with.  A movie recorded part way through a game has the save state it started from, in
+//! hex, otherwise it starts at power on and has no `state` line.
+//!
+//! Then there is a line per frame, `UDLRSsBA 0123456789abcdef`, with `.` for buttons not
+//! held, and `reset` or `reload` lines where those happened.  A battery save loaded at power
+//! on has to be the same one when playing back.
+
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::Write;
+
+use super::mem::Buttons;
+
+const HEADER: &str = "FeGaBo movie 2";
+const BUTTONS: &str = "UDLRSsBA";
+
+pub enum Event {
+    Frame(Buttons, u64),
+    Reset,
+    Reload,
+}
+
+/// How the machine was when the movie started.
+pub struct Start {
+    pub boot_rom: bool,
+    pub sgb: bool,
+    pub colourise: bool,
+    pub state: Option<Vec<u8>>,
+}
+
+fn start_line(boot_rom: bool) -> &'static str {
+    match boot_rom {
+        true => "start power-on",
+        false => "start after-boot",
+    }
+}
+
+fn yes_no(value: bool) -> &'static str {
+    match value {
+        true => "yes",
+        false => "no",
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len()).step_by(2)
+        .map(|i| text.get(i..i + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+        .collect()
+}
+
+fn held(buttons: &Buttons) -> [bool; 8] {
+    let b = buttons;
+    [b.up, b.down, b.left, b.right, b.start, b.select, b.b, b.a]
+}
+
+fn format_buttons(buttons: &Buttons) -> String {
+    held(buttons).iter().zip(BUTTONS.chars())
+        .map(|(held, name)| if *held { name } else { '.' })
+        .collect()
+}
+
+fn parse_buttons(text: &str) -> Option<Buttons> {
+    if text.len() != BUTTONS.len() {
+        return None;
+    }
+    let mut pressed = [false; 8];
+    for ((pressed, c), name) in pressed.iter_mut().zip(text.chars()).zip(BUTTONS.chars()) {
+        match c {
+            '.' => {}
+            c if c == name => *pressed = true,
+            _ => return None,
+        }
+    }
+    let p = pressed;
+    Some(Buttons {
+        up: p[0], down: p[1], left: p[2], right: p[3],
+        start: p[4], select: p[5], b: p[6], a: p[7],
+    })
+}
+
+/// FNV-1a, stable everywhere so checksums can be compared between machines.
+pub fn checksum(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, b| (hash ^ *b as u64).wrapping_mul(0x100000001b3))
+}
+
+pub const CHECKSUM_START: u64 = 0xcbf29ce484222325;
+
+pub struct Recorder {
+    file: File,
+}
+
+impl Recorder {
+    pub fn create(path: &str, start: &Start) -> Result<Recorder, String> {
+        let mut file = File::create(path).map_err(|e| format!("{}: {}", path, e))?;
+        let mut header = format!("{}\n{}\nsgb {}\ncolourise {}\n", HEADER,
+                                 start_line(start.boot_rom), yes_no(start.sgb),
+                                 yes_no(start.colourise));
+        if let Some(ref state) = start.state {
+            header += &format!("state {}\n", to_hex(state));
+        }
+        file.write_all(header.as_bytes()).map_err(|e| format!("{}: {}", path, e))?;
+        Ok(Recorder { file })
+    }
+
+    /// Written straight away, so nothing is lost however the emulator is closed.
+    pub fn record(&mut self, event: &Event) {
+        let line = match event {
+            Event::Frame(buttons, sum) => format!("{} {:016x}", format_buttons(buttons), sum),
+            Event::Reset => "reset".to_string(),
+            Event::Reload => "reload".to_string(),
+        };
+        if let Err(e) = writeln!(self.file, "{}", line) {
+            eprintln!("Could not record movie: {}", e);
+        }
+    }
+}
+
+pub struct Player {
+    start: Start,
+    events: VecDeque<Event>,
+    frame: usize,
+    desynced: bool,
+}
+
+impl Player {
+    pub fn open(path: &str, boot_rom: bool) -> Result<Player, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        Self::parse(&text, boot_rom).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    fn parse(text: &str, boot_rom: bool) -> Result<Player, String> {
+        let mut lines = text.lines().enumerate().peekable();
+        if lines.next().map(|(_, line)| line) != Some(HEADER) {
+            return Err(format!("not a movie, or not one from this version, which starts with \"{}\"",
+                               HEADER));
+        }
+        match lines.next() {
+            Some((_, start)) if start == start_line(boot_rom) => {}
+            Some((_, start)) => return Err(format!("recorded with \"{}\", now \"{}\"",
+                                                   start, start_line(boot_rom))),
+            None => return Err("no start line".to_string()),
+        }
+        let mut setting = |name: &str| match lines.next() {
+            Some((_, line)) if line == format!("{} yes", name) => Ok(true),
+            Some((_, line)) if line == format!("{} no", name) => Ok(false),
+            _ => Err(format!("no {} line after the start", name)),
+        };
+        let (sgb, colourise) = (setting("sgb")?, setting("colourise")?);
+        let state = match lines.peek() {
+            Some((_, line)) if line.starts_with("state ") => {
+                let hex = line["state ".len()..].trim();
+                lines.next();
+                Some(from_hex(hex).ok_or("the state is not hex")?)
+            }
+            _ => None,
+        };
+        let start = Start { boot_rom, sgb, colourise, state };
+        let mut events = VecDeque::new();
+        for (number, line) in lines {
+            let event = match line.trim() {
+                "reset" => Event::Reset,
+                "reload" => Event::Reload,
+                line => {
+                    let mut parts = line.split_whitespace();
+                    let buttons = parts.next().and_then(parse_buttons);
+                    let sum = parts.next().and_then(|s| u64::from_str_radix(s, 16).ok());
+                    match (buttons, sum, parts.next()) {
+                        (Some(buttons), Some(sum), None) => Event::Frame(buttons, sum),
+                        _ => return Err(format!("line {} is not a frame: {}", number + 1, line)),
+                    }
+                }
+            };
+            events.push_back(event);
+        }
+        Ok(Player { start, events, frame: 0, desynced: false })
+    }
+
+    pub fn start(&self) -> &Start {
+        &self.start
+    }
+
+    /// Buttons for the coming frame, after any reset or reload before it, None once the
+    /// movie is over.
+    pub fn buttons(&self) -> Option<Buttons> {
+        self.events.iter()
+            .filter_map(|event| match event {
+                Event::Frame(buttons, _) => Some(*buttons),
+                _ => None,
+            })
+            .next()
+    }
+
+    /// Compare a finished frame with the recording, reporting the first difference.
+    pub fn check(&mut self, sum: u64) {
+        if let Some(Event::Frame(_, recorded)) = self.events.pop_front() {
+            if recorded != sum && !self.desynced {
+                self.desynced = true;
+                eprintln!("Movie desynced at frame {}", self.frame);
+            }
+        }
+        self.frame += 1;
+    }
+
+    /// A reset or reload that happened before the coming frame.
+    pub fn next_event(&mut self) -> Option<Event> {
+        match self.events.front() {
+            Some(Event::Frame(_, _)) | None => None,
+            Some(_) => self.events.pop_front(),
+        }
+    }
+
+    pub fn desynced(&self) -> bool {
+        self.desynced
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use gb::mem::Buttons;
+    use gb::movie::{format_buttons, from_hex, parse_buttons, to_hex, Event, Player};
+
+    #[test]
+    fn buttons_round_trip() {
+        let buttons = Buttons { up: true, start: true, a: true, ..Buttons::default() };
+        assert_eq!(format_buttons(&buttons), "U...S..A");
+        let parsed = parse_buttons("U...S..A").unwrap();
+        assert_eq!(format_buttons(&parsed), "U...S..A");
+        assert!(parse_buttons("A...S..U").is_none());
+    }
+
+    #[test]
+    fn playback() {
+        let movie = "FeGaBo movie 2\nstart after-boot\nsgb no\ncolourise yes\n\
+                     ........ 01\nreset\n.......A 02\n";
+        assert!(Player::parse(movie, true).is_err());
+        let mut player = Player::parse(movie, false).unwrap();
+        assert!(!player.start().sgb);
+        assert!(player.start().colourise);
+        assert!(player.start().state.is_none());
+        assert!(player.next_event().is_none());
+        assert!(!player.buttons().unwrap().a);
+        player.check(1);
+        // The buttons are there for the reset.
+        assert!(player.buttons().unwrap().a);
+        match player.next_event() {
+            Some(Event::Reset) => {}
+            _ => panic!("Expected the reset"),
+        }
+        assert!(player.buttons().unwrap().a);
+        assert!(!player.desynced());
+        player.check(3);
+        assert!(player.desynced());
+        assert!(player.buttons().is_none());
+    }
+
+    #[test]
+    fn started_from_a_state() {
+        let movie = "FeGaBo movie 2\nstart power-on\nsgb yes\ncolourise no\nstate 00ff1a\n\
+                     ....S... 01\n";
+        let player = Player::parse(movie, true).unwrap();
+        assert!(player.start().sgb);
+        assert_eq!(player.start().state, Some(vec![0x00, 0xFF, 0x1A]));
+        assert!(player.buttons().unwrap().start);
+        assert_eq!(from_hex(&to_hex(&[1, 0xAB])), Some(vec![1, 0xAB]));
+        assert!(from_hex("0g").is_none());
+        assert!(Player::parse("FeGaBo movie 1\nstart power-on\n", true).is_err());
+    }
+}
@@ -0,0 +1,167 @@
+//! Runs the Blargg and Mooneye test roms found in tests/roms without a window, along with
+//! our own, which are assembled from the `.asm` files there first.
+//!
+//! Blargg roms report over serial, ending with "Passed" or "Failed".  Mooneye roms run
+//! `LD B, B` when they are done, with 3, 5, 8, 13, 21 and 34 in B to L if they passed.
+//! Roms in a `mooneye` directory are checked the Mooneye way, the rest the Blargg way.
+//!
+//! The results are checked against tests/roms/RESULTS.md and any rom listed there as passing
+//! that no longer does fails the test.  Set FEGABO_UPDATE_RESULTS to write the new results.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use gb::harness;
+use gb::Gb;
+
+const ROMS: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/roms");
+const RESULTS: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/roms/RESULTS.md");
+/// Instructions before a rom is considered stuck, a minute of emulated time if each took a
+/// single M-cycle and more as they take longer, which covers Blargg's slowest.  Counted in
+/// steps rather than frames so a rom that turns the lcd off still stops.
+const TIMEOUT_STEPS: usize = 60 * 4194304 / 4;
+const FIBONACCI: [u8; 6] = [3, 5, 8, 13, 21, 34];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Outcome {
+    Pass,
+    Fail,
+    Timeout,
+    Crash,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Outcome::Pass => write!(f, "pass"),
+            Outcome::Fail => write!(f, "fail"),
+            Outcome::Timeout => write!(f, "timeout"),
+            Outcome::Crash => write!(f, "crash"),
+        }
+    }
+}
+
+impl Outcome {
+    fn parse(s: &str) -> Option<Outcome> {
+        match s {
+            "pass" => Some(Outcome::Pass),
+            "fail" => Some(Outcome::Fail),
+            "timeout" => Some(Outcome::Timeout),
+            "crash" => Some(Outcome::Crash),
+            _ => None,
+        }
+    }
+}
+
+fn run_rom(path: String, mooneye: bool) -> Outcome {
+    let mut gb = Gb::headless(path);
+    let mut logged = 0;
+    for _ in 0..TIMEOUT_STEPS {
+        if mooneye && gb.mem.load_8(gb.cpu.pc()) == 0x40 {
+            return match gb.cpu.bcdehl() == FIBONACCI {
+                true => Outcome::Pass,
+                false => Outcome::Fail,
+            };
+        }
+        gb.step();
+        if gb.cpu.lockup().is_some() {
+            return Outcome::Crash;
+        }
+        let log = gb.mem.serial_log();
+        if !mooneye && log.len() != logged {
+            logged = log.len();
+            let log = String::from_utf8_lossy(log);
+            if log.contains("Passed") {
+                return Outcome::Pass;
+            }
+            if log.contains("Failed") {
+                return Outcome::Fail;
+            }
+        }
+    }
+    Outcome::Timeout
+}
+
+/// Every rom below a directory, by its path relative to tests/roms.
+fn find_roms(dir: &Path, roms: &mut Vec<String>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for path in entries.filter_map(|e| e.ok()).map(|e| e.path()) {
+        if path.is_dir() {
+            find_roms(&path, roms);
+        }
+        else if path.extension().is_some_and(|ext| ext == "gb" || ext == "asm") {
+            let name = path.strip_prefix(ROMS).unwrap()
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy().into_owned())
+                .collect::<Vec<_>>()
+                .join("/");
+            roms.push(name);
+        }
+    }
+}
+
+fn read_results() -> BTreeMap<String, Outcome> {
+    fs::read_to_string(RESULTS).unwrap_or_default()
+        .lines()
+        .filter_map(|line| {
+            let cells: Vec<_> = line.split('|').map(|c| c.trim()).collect();
+            match cells.len() {
+                4 => Outcome::parse(cells[2]).map(|o| (cells[1].to_string(), o)),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+fn write_results(results: &BTreeMap<String, Outcome>) {
+    let mut out = String::from("# Test rom results\n\n\
+        Written by `FEGABO_UPDATE_RESULTS=1 cargo test test_roms`.\n\
+        Roms that pass here have to keep passing.\n\n\
+        | Rom | Result |\n\
+        |-----|--------|\n");
+    for (rom, outcome) in results {
+        out.push_str(&format!("| {} | {} |\n", rom, outcome));
+    }
+    fs::write(RESULTS, out).unwrap();
+}
+
+#[test]
+fn test_roms() {
+    let mut roms = vec![];
+    find_roms(Path::new(ROMS), &mut roms);
+    harness::require(&roms, ROMS);
+    roms.sort();
+
+    let outcomes = harness::run_all(roms.clone(), |rom| {
+        let path = harness::rom_path(format!("{}/{}", ROMS, rom)).unwrap();
+        run_rom(path, rom.split('/').any(|dir| dir == "mooneye"))
+    });
+    let results: BTreeMap<_, _> = roms.into_iter()
+        .zip(outcomes.into_iter().map(|outcome| outcome.unwrap_or(Outcome::Crash)))
+        .collect();
+
+    let passed = results.values().filter(|o| **o == Outcome::Pass).count();
+    for (rom, outcome) in results.iter() {
+        eprintln!("{:<8} {}", outcome.to_string(), rom);
+    }
+    eprintln!("{} of {} test roms passed", passed, results.len());
+
+    let regressions: Vec<_> = read_results().into_iter()
+        .filter(|(_, outcome)| *outcome == Outcome::Pass)
+        .filter_map(|(rom, _)| match results.get(&rom) {
+            Some(Outcome::Pass) => None,
+            Some(_) => Some(format!("{} no longer passes", rom)),
+            None => Some(format!("{} passed before but is missing", rom)),
+        })
+        .collect();
+    if env::var_os("FEGABO_UPDATE_RESULTS").is_some() {
+        write_results(&results);
+    }
+    harness::report(&regressions, "test roms that passed before do not now");
+}
@@ -0,0 +1,126 @@
+//! Compares frames from test roms with reference images.
+//!
+//! tests/screenshots/screenshots.txt lists a rom, or its source, how many frames to run it for
+//! and the image the last frame should match.  The frames use the fixed grey DMG pallet of `GBP::apply`.
+//! On a mismatch the frame and a diff, with differing pixels in red, are written to
+//! target/screenshots.
+
+use std::fs::{self, File};
+use std::io::BufWriter;
+
+use png;
+
+use gb::{harness, Gb};
+use ::{GAMEBOY_WIDTH, GAMEBOY_HEIGHT};
+
+const SCREENSHOTS: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/screenshots");
+const OUTPUT: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/target/screenshots");
+
+/// Steps in a frame even if every instruction were one machine cycle.
+const FRAME_STEPS: usize = 70224 / 4;
+
+/// Runs a rom for a number of frames, failing if it turns the LCD off long enough that the
+/// frames never come.
+fn run_frames(rom: String, frames: usize) -> Result<Vec<u8>, String> {
+    let mut gb = Gb::headless(rom);
+    let mut count = 0;
+    for _ in 0..frames * FRAME_STEPS {
+        if gb.step() {
+            count += 1;
+        }
+        if count == frames {
+            return Ok(gb.mem.screen().to_vec());
+        }
+    }
+    Err(format!("only {} of {} frames were drawn", count, frames))
+}
+
+/// Reads a png as 8 bit RGB.
+fn read_png(path: &str) -> Result<(u32, u32, Vec<u8>), String> {
+    let file = File::open(path).map_err(|e| format!("{}: {}", path, e))?;
+    let mut decoder = png::Decoder::new(file);
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+    let mut reader = decoder.read_info().map_err(|e| format!("{}: {}", path, e))?;
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buffer).map_err(|e| format!("{}: {}", path, e))?;
+    let buffer = &buffer[..info.buffer_size()];
+    let rgb = match info.color_type {
+        png::ColorType::Rgb => buffer.to_vec(),
+        png::ColorType::Rgba => buffer.chunks(4).flat_map(|p| p[..3].to_vec()).collect(),
+        png::ColorType::Grayscale => buffer.iter().flat_map(|p| vec![*p; 3]).collect(),
+        png::ColorType::GrayscaleAlpha => buffer.chunks(2).flat_map(|p| vec![p[0]; 3]).collect(),
+        png::ColorType::Indexed => unreachable!("Expanded to RGB by the decoder"),
+    };
+    Ok((info.width, info.height, rgb))
+}
+
+fn write_png(path: &str, rgb: &[u8]) {
+    let file = BufWriter::new(File::create(path).unwrap());
+    let mut encoder = png::Encoder::new(file, GAMEBOY_WIDTH, GAMEBOY_HEIGHT);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.write_header().unwrap().write_image_data(rgb).unwrap();
+}
+
+/// Reference dimmed, with every pixel that differs in red.
+fn diff_image(expected: &[u8], actual: &[u8]) -> Vec<u8> {
+    expected.chunks(3).zip(actual.chunks(3))
+        .flat_map(|(e, a)| match e == a {
+            true => e.iter().map(|c| c / 4 + 96).collect::<Vec<_>>(),
+            false => vec![255, 0, 0],
+        })
+        .collect()
+}
+
+/// Returns a description of the mismatch, if any.
+fn check(rom: &str, frames: usize, reference: &str) -> Option<String> {
+    let (width, height, expected) = match read_png(&format!("{}/{}", SCREENSHOTS, reference)) {
+        Ok(image) => image,
+        Err(e) => return Some(e),
+    };
+    if (width, height) != (GAMEBOY_WIDTH, GAMEBOY_HEIGHT) {
+        return Some(format!("{} is {}x{}, not {}x{}",
+                            reference, width, height, GAMEBOY_WIDTH, GAMEBOY_HEIGHT));
+    }
+    let actual = match harness::rom_path(format!("{}/{}", SCREENSHOTS, rom))
+        .and_then(|path| run_frames(path, frames)) {
+        Ok(actual) => actual,
+        Err(e) => return Some(e),
+    };
+    let wrong = expected.chunks(3).zip(actual.chunks(3)).filter(|(e, a)| e != a).count();
+    if wrong == 0 {
+        return None;
+    }
+    fs::create_dir_all(OUTPUT).unwrap();
+    let name = reference.trim_end_matches(".png");
+    write_png(&format!("{}/{}.actual.png", OUTPUT, name), &actual);
+    write_png(&format!("{}/{}.diff.png", OUTPUT, name), &diff_image(&expected, &actual));
+    Some(format!("{} pixels differ, see {}/{}.diff.png", wrong, OUTPUT, name))
+}
+
+#[test]
+fn screenshots() {
+    let list = fs::read_to_string(format!("{}/screenshots.txt", SCREENSHOTS)).unwrap_or_default();
+    let entries: Vec<(String, usize, String)> = list.lines()
+        .map(|line| line.split('#').next().unwrap().trim())
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let fields: Vec<_> = line.split_whitespace().collect();
+            assert_eq!(fields.len(), 3, "Expected \"rom frames reference\", got \"{}\"", line);
+            (fields[0].to_string(), fields[1].parse().unwrap(), fields[2].to_string())
+        })
+        .collect();
+    harness::require(&entries, SCREENSHOTS);
+
+    let results = harness::run_all(entries.clone(), |(rom, frames, reference)| {
+        check(&rom, frames, &reference)
+    });
+    let failures: Vec<_> = entries.iter().zip(results)
+        .filter_map(|((rom, _, _), result)| match result {
+            Some(None) => None,
+            Some(Some(problem)) => Some(format!("{}: {}", rom, problem)),
+            None => Some(format!("{}: crashed", rom)),
+        })
+        .collect();
+    harness::report(&failures, "screenshots do not match");
+}
@@ -0,0 +1,158 @@
+//! Save states, everything about the machine needed to carry on exactly from a point, so a
+//! movie can start part way through a game.  The roms are left out, a state only loads
+//! into a machine made from the same ones.
+//!
+//! Each part goes through its fields once in `State::state`, which saves or loads them
+//! depending on the `Snapshot` it is given, so the two always agree on the layout.
+
+/// Something that can be saved and loaded.
+pub trait State {
+    fn state(&mut self, snapshot: &mut Snapshot);
+}
+
+/// The bytes of a state, being written or read.
+pub struct Snapshot<'a> {
+    saved: Vec<u8>,
+    loading: Option<&'a [u8]>, // What is left to read.
+    error: Option<String>, // Why it could not be loaded.
+}
+
+impl<'a> Snapshot<'a> {
+    pub fn saving() -> Snapshot<'static> {
+        Snapshot { saved: vec![], loading: None, error: None }
+    }
+
+    pub fn loading(bytes: &'a [u8]) -> Snapshot<'a> {
+        Snapshot { saved: vec![], loading: Some(bytes), error: None }
+    }
+
+    pub fn is_loading(&self) -> bool {
+        self.loading.is_some()
+    }
+
+    /// The state saved.
+    pub fn saved(self) -> Vec<u8> {
+        self.saved
+    }
+
+    /// Whether the whole state was read, and nothing more.
+    pub fn loaded(self) -> Result<(), String> {
+        match (self.error, self.loading) {
+            (Some(error), _) => Err(error),
+            (None, Some(left)) if !left.is_empty() => Err("the state is too long".to_string()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Give up loading a state that does not fit this machine.  Only the first reason is
+    /// kept, what is read after it is meaningless.
+    pub fn reject(&mut self, reason: &str) {
+        if self.error.is_none() {
+            self.error = Some(reason.to_string());
+        }
+        self.loading = Some(&[]);
+    }
+
+    pub fn bytes(&mut self, bytes: &mut [u8]) {
+        match self.loading {
+            None => self.saved.extend_from_slice(bytes),
+            Some(left) if left.len() >= bytes.len() => {
+                bytes.copy_from_slice(&left[..bytes.len()]);
+                self.loading = Some(&left[bytes.len()..]);
+            }
+            Some(_) => self.reject("the state is cut short"),
+        }
+    }
+
+    pub fn u8(&mut self, value: &mut u8) {
+        let mut bytes = [*value];
+        self.bytes(&mut bytes);
+        *value = bytes[0];
+    }
+
+    pub fn bool(&mut self, value: &mut bool) {
+        let mut byte = *value as u8;
+        self.u8(&mut byte);
+        *value = byte > 0;
+    }
+
+    pub fn u16(&mut self, value: &mut u16) {
+        let mut bytes = [*value as u8, (*value >> 8) as u8];
+        self.bytes(&mut bytes);
+        *value = bytes[0] as u16 | (bytes[1] as u16) << 8;
+    }
+
+    pub fn u64(&mut self, value: &mut u64) {
+        let mut bytes = [0; 8];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = (*value >> (i * 8)) as u8;
+        }
+        self.bytes(&mut bytes);
+        *value = (0..8).fold(0, |value, i| value | (bytes[i] as u64) << (i * 8));
+    }
+
+    pub fn usize(&mut self, value: &mut usize) {
+        let mut wide = *value as u64;
+        self.u64(&mut wide);
+        *value = wide as usize;
+    }
+
+    /// Bytes that can change length, so the length goes first.
+    pub fn vec(&mut self, value: &mut Vec<u8>) {
+        let mut length = value.len();
+        self.usize(&mut length);
+        if self.is_loading() {
+            let left = self.loading.map_or(0, |left| left.len());
+            value.resize(length.min(left), 0);
+        }
+        self.bytes(value);
+    }
+
+    /// Something that may be missing, with what goes in it.  A part loaded where there was
+    /// none starts as `empty`.
+    pub fn option<T, E, F>(&mut self, value: &mut Option<T>, empty: E, mut inner: F)
+        where E: FnOnce() -> T, F: FnMut(&mut Snapshot, &mut T) {
+        let mut some = value.is_some();
+        self.bool(&mut some);
+        match (some, value.is_some()) {
+            (false, _) => *value = None,
+            (true, false) => *value = Some(empty()),
+            (true, true) => {}
+        }
+        if let Some(ref mut inner_value) = *value {
+            inner(self, inner_value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use gb::state::Snapshot;
+
+    fn fields(snapshot: &mut Snapshot, values: &mut (u8, bool, u16, usize, Vec<u8>, Option<u16>)) {
+        snapshot.u8(&mut values.0);
+        snapshot.bool(&mut values.1);
+        snapshot.u16(&mut values.2);
+        snapshot.usize(&mut values.3);
+        snapshot.vec(&mut values.4);
+        snapshot.option(&mut values.5, || 0, |snapshot, value| snapshot.u16(value));
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut values = (0x12, true, 0x3456, 0x789A_BCDE, vec![1, 2, 3], Some(0xF00D));
+        let mut saving = Snapshot::saving();
+        fields(&mut saving, &mut values);
+        let saved = saving.saved();
+
+        let mut loaded = (0, false, 0, 0, vec![], None);
+        let mut loading = Snapshot::loading(&saved);
+        fields(&mut loading, &mut loaded);
+        assert!(loading.loaded().is_ok());
+        assert_eq!(loaded, values);
+
+        let mut loading = Snapshot::loading(&saved[..saved.len() - 1]);
+        fields(&mut loading, &mut loaded);
+        assert!(loading.loaded().is_err());
+    }
+}
@@ -0,0 +1,91 @@
+//! Gameplay video as YUV4MPEG2 (.y4m), which ffmpeg and most players take as it is.
+//!
+//! Every frame the gameboy finishes is written, so the video runs at the real 4194304 / 70224
+//! (about 59.73) frames a second whatever speed the emulator was going at.  Chroma is not
+//! subsampled, so the four shades and colour games come out exactly.  The size is taken from
+//! the first frame, so the file is only started then.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use super::shade_index;
+
+const CLOCK: usize = 4194304;
+const CYCLES_PER_FRAME: usize = 456 * 154;
+
+pub struct Video {
+    out: BufWriter<File>,
+    path: String,
+    frames: usize,
+    width: usize,
+}
+
+/// BT.601 with the usual limited range.
+fn yuv(rgb: [u8; 3]) -> [u8; 3] {
+    let (r, g, b) = (rgb[0] as i32, rgb[1] as i32, rgb[2] as i32);
+    [
+        (((66 * r + 129 * g + 25 * b + 128) >> 8) + 16) as u8,
+        (((-38 * r - 74 * g + 112 * b + 128) >> 8) + 128) as u8,
+        (((112 * r - 94 * g - 18 * b + 128) >> 8) + 128) as u8,
+    ]
+}
+
+impl Video {
+    pub fn create(path: &str) -> Result<Video, String> {
+        let file = File::create(path).map_err(|e| format!("{}: {}", path, e))?;
+        println!("Recording video to {}", path);
+        Ok(Video { out: BufWriter::new(file), path: path.to_string(), frames: 0, width: 0 })
+    }
+
+    /// Add a finished screen `width` pixels wide, the greys coloured with `palette`, or as it
+    /// is without one.
+    pub fn frame(&mut self, screen: &[u8], width: usize, palette: Option<&[[u8; 3]; 4]>) {
+        let pixels = screen.len() / 3;
+        if self.frames == 0 {
+            self.width = width;
+            let header = format!("YUV4MPEG2 W{} H{} F{}:{} Ip A1:1 C444\n",
+                                 width, pixels / width, CLOCK, CYCLES_PER_FRAME);
+            if let Err(e) = self.out.write_all(header.as_bytes()) {
+                eprintln!("Could not write video to {}: {}", self.path, e);
+            }
+        }
+        if width != self.width {
+            return;
+        }
+        let colours: Option<Vec<[u8; 3]>> = palette.map(|p| p.iter().map(|c| yuv(*c)).collect());
+        let mut data = vec![0; pixels * 3 + 6];
+        data[..6].copy_from_slice(b"FRAME\n");
+        for (i, pixel) in screen.chunks(3).enumerate() {
+            let colour = match colours {
+                Some(ref colours) => colours[shade_index(pixel[0])],
+                None => yuv([pixel[0], pixel[1], pixel[2]]),
+            };
+            for plane in 0..3 {
+                data[6 + plane * pixels + i] = colour[plane];
+            }
+        }
+        if let Err(e) = self.out.write_all(&data) {
+            eprintln!("Could not write video to {}: {}", self.path, e);
+        }
+        self.frames += 1;
+    }
+
+    pub fn finish(mut self) {
+        match self.out.flush() {
+            Ok(()) => println!("Wrote {} frames of video to {}", self.frames, self.path),
+            Err(e) => eprintln!("Could not write video to {}: {}", self.path, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use gb::video::yuv;
+
+    #[test]
+    fn greys_have_no_colour() {
+        assert_eq!(yuv([0, 0, 0]), [16, 128, 128]);
+        assert_eq!(yuv([255, 255, 255]), [235, 128, 128]);
+        assert_eq!(yuv([170, 170, 170])[1..], [128, 128]);
+    }
+}
@@ -1,42 +1,153 @@
 extern crate sdl2;
 #[macro_use]
 extern crate clap;
+#[macro_use]
+extern crate serde;
+extern crate toml;
+extern crate gif;
+extern crate png;
+#[cfg(test)]
+extern crate serde_json;
 
-use std::time::{SystemTime, Duration};
+use std::collections::HashMap;
+use std::thread;
+use std::time::{SystemTime, Duration, Instant};
 
 use sdl2::pixels::Color;
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
+use sdl2::controller::{Axis, Button, GameController};
 use sdl2::video::WindowContext;
 use sdl2::pixels::PixelFormatEnum;
 use sdl2::render::{Texture, TextureCreator};
 
 const GAMEBOY_WIDTH: u32 = 160;
 const GAMEBOY_HEIGHT: u32 = 144;
-const RESOLUTION_MULTEPLYER: u32 = 4;
+/// Length of a gameboy frame, 59.73 a second.
+const FRAME_TIME: Duration = Duration::from_nanos(16_742_706);
+
+/// Speeds to pick from with [ and ], as frames run for each one shown and how many frame
+/// times each shown frame stays up for.
+const SPEEDS: [(&str, usize, u32); 5] = [
+    ("0.25x", 1, 4), ("0.5x", 1, 2), ("1x", 1, 1), ("2x", 2, 1), ("4x", 4, 1),
+];
+const NORMAL_SPEED: usize = 2;
+/// Speed while tab is held.
+const FAST_FORWARD: (&str, usize, u32) = ("8x", 8, 1);
+/// Longest the window waits for a frame before looking at input again.
+const POLL_TIME: Duration = Duration::from_millis(1);
 
 mod gb;
+mod config;
+mod capture;
+mod palette;
 use gb::Output;
 use gb::Input;
+use gb::mem::sgb;
+use config::{Config, Action, PaletteSetting};
+use palette::Palette;
 
 enum TextureType {
     Screen,
+    Bordered,
+}
+
+impl TextureType {
+    fn size(&self) -> (u32, u32) {
+        match *self {
+            TextureType::Screen => (GAMEBOY_WIDTH, GAMEBOY_HEIGHT),
+            TextureType::Bordered => (sgb::WIDTH as u32, sgb::HEIGHT as u32),
+        }
+    }
+}
+
+struct Args {
+    roms: (String, Option<String>),
+    /// Stop with the cpu state as soon as the game locks up.
+    break_on_lockup: bool,
+    headless: bool,
+    frames: Option<usize>,
+    settings: gb::Settings,
+    config: Option<String>,
+    scale: Option<u32>,
+    palette: Option<String>,
+}
+
+/// How the window is driven, from the config file and command line.
+struct Controls {
+    break_on_lockup: bool,
+    bindings: HashMap<Keycode, Action>,
+    pad_bindings: HashMap<Button, Action>,
+    deadzone: i16,
+    palettes: Vec<Palette>,
+    start_palette: usize,
+    rom: String, // Where captures are saved next to.
+    gif_seconds: u32,
+    show_border: bool,
+}
+
+/// The button an action presses, if it is one.
+fn button(buttons: &mut gb::mem::Buttons, action: Action) -> Option<&mut bool> {
+    match action {
+        Action::A => Some(&mut buttons.a),
+        Action::B => Some(&mut buttons.b),
+        Action::Start => Some(&mut buttons.start),
+        Action::Select => Some(&mut buttons.select),
+        Action::Up => Some(&mut buttons.up),
+        Action::Down => Some(&mut buttons.down),
+        Action::Left => Some(&mut buttons.left),
+        Action::Right => Some(&mut buttons.right),
+        _ => None,
+    }
+}
+
+/// Buttons held on any of the inputs.
+fn combine(inputs: &[gb::mem::Buttons]) -> gb::mem::Buttons {
+    inputs.iter().fold(gb::mem::Buttons::default(), |all, b| gb::mem::Buttons {
+        a: all.a || b.a,
+        b: all.b || b.b,
+        start: all.start || b.start,
+        select: all.select || b.select,
+        up: all.up || b.up,
+        down: all.down || b.down,
+        left: all.left || b.left,
+        right: all.right || b.right,
+    })
+}
+
+/// An open controller and what it is holding, apart from the others so unplugging it only
+/// lets go of its own buttons.
+struct Pad {
+    controller: GameController,
+    buttons: gb::mem::Buttons,
+    stick: gb::mem::Buttons,
+}
+
+impl Pad {
+    fn new(controller: GameController) -> Self {
+        Pad { controller, buttons: Default::default(), stick: Default::default() }
+    }
+
+    fn held(&self) -> gb::mem::Buttons {
+        combine(&[self.buttons, self.stick])
+    }
 }
 
 struct Window {
     sdl_context: sdl2::Sdl,
     canvas: sdl2::render::Canvas<sdl2::video::Window>,
     texture_creator: TextureCreator<WindowContext>,
+    scale: u32,
 }
 
 impl Window {
-    fn new() -> Window {
+    fn new(scale: u32) -> Window {
         let sdl_context = sdl2::init().unwrap();
         let video_subsystem = sdl_context.video().unwrap();
         let window = video_subsystem
             .window("FeGaBo",
-                    RESOLUTION_MULTEPLYER * GAMEBOY_WIDTH,
-                    RESOLUTION_MULTEPLYER * GAMEBOY_HEIGHT)
+                    scale * GAMEBOY_WIDTH,
+                    scale * GAMEBOY_HEIGHT)
             .position_centered()
             .build()
             .unwrap();
@@ -57,97 +168,247 @@ impl Window {
         Window {
             sdl_context,
             canvas,
-            texture_creator: texture_creator,
+            texture_creator,
+            scale,
         }
     }
 
-    fn create_screen_internal<'a>(texture_creator: &'a TextureCreator<WindowContext>)
-        -> Texture<'a> {
+    fn create_screen_internal<'a>(texture_creator: &'a TextureCreator<WindowContext>,
+                                  kind: &TextureType) -> Texture<'a> {
+            let (width, height) = kind.size();
             texture_creator
-                .create_texture_streaming(PixelFormatEnum::RGB24, GAMEBOY_WIDTH, GAMEBOY_HEIGHT)
+                .create_texture_streaming(PixelFormatEnum::RGB24, width, height)
                 .unwrap()
         }
 
-    fn event_loop(mut self, gbconnect: gb::GbConnect) {
+    fn event_loop(mut self, gbconnect: gb::GbConnect, controls: Controls) {
+        let Controls { break_on_lockup, bindings, pad_bindings, deadzone, palettes,
+                       start_palette, rom, gif_seconds, mut show_border } = controls;
         let mut live_textures = Vec::new();
-        live_textures
-            .push((TextureType::Screen, Window::create_screen_internal(&self.texture_creator)));
+        for kind in [TextureType::Screen, TextureType::Bordered] {
+            let texture = Window::create_screen_internal(&self.texture_creator, &kind);
+            live_textures.push((kind, texture));
+        }
         //self.create_screen();
         let mut event_pump = self.sdl_context.event_pump().unwrap();
         let mut fps = 0;
         let mut start_time = SystemTime::now();
-        let mut a = false;
-        let mut b = false;
-        let mut start = false;
-        let mut select = false;
-        let mut up = false;
-        let mut down = false;
-        let mut left = false;
-        let mut right = false;
+        let mut buttons = gb::mem::Buttons::default();
+        // Controllers are opened as they are plugged in, SDL also reports those already there.
+        let controller_subsystem = self.sdl_context.game_controller().unwrap();
+        let mut pads: HashMap<i32, Pad> = HashMap::new();
+        let mut speed = NORMAL_SPEED;
+        let mut fast_forward = false;
+        let mut frames_per_shown = 1;
+        let mut paused = false;
+        let mut advance = false;
+        // The gameboy waits to be told to go on after each frame, since this time.
+        let mut waiting: Option<Instant> = None;
+        let mut clip = capture::Clip::new(gif_seconds);
+        let mut current_palette = start_palette;
+        let mut palette = palettes[current_palette].colours;
+        // Colour games are shown as they are, the palette is only for greys.
+        let mut colour = false;
+        // Frames come in the Super Game Boy border, which can be cropped off.
+        let mut border = false;
+        // The last frame as it was shown, for screenshots.
+        let mut shown = vec![0xFF; (GAMEBOY_WIDTH * GAMEBOY_HEIGHT * 3) as usize];
+        let mut shown_width = GAMEBOY_WIDTH as usize;
         'running: loop {
             for event in event_pump.poll_iter() {
                 match event {
-                    Event::Quit { .. } |
-                        Event::KeyDown { keycode: Some(Keycode::Escape), .. } => break 'running,
-                        Event::KeyDown { keycode: Some(Keycode::C), .. } => a = true,
-                        Event::KeyDown { keycode: Some(Keycode::X), .. } => b = true,
-                        Event::KeyDown { keycode: Some(Keycode::B), .. } => start = true,
-                        Event::KeyDown { keycode: Some(Keycode::V), .. } => select = true,
-                        Event::KeyDown { keycode: Some(Keycode::Up), .. } => up = true,
-                        Event::KeyDown { keycode: Some(Keycode::Down), .. } => down = true,
-                        Event::KeyDown { keycode: Some(Keycode::Left), .. } => left = true,
-                        Event::KeyDown { keycode: Some(Keycode::Right), .. } => right = true,
-
-                        Event::KeyUp { keycode: Some(Keycode::C), .. } => a = false,
-                        Event::KeyUp { keycode: Some(Keycode::X), .. } => b = false,
-                        Event::KeyUp { keycode: Some(Keycode::B), .. } => start = false,
-                        Event::KeyUp { keycode: Some(Keycode::V), .. } => select = false,
-                        Event::KeyUp { keycode: Some(Keycode::Up), .. } => up = false,
-                        Event::KeyUp { keycode: Some(Keycode::Down), .. } => down = false,
-                        Event::KeyUp { keycode: Some(Keycode::Left), .. } => left = false,
-                        Event::KeyUp { keycode: Some(Keycode::Right), .. } => right = false,
-                        _ => {}
+                    Event::Quit { .. } => break 'running,
+                    Event::KeyDown { keycode: Some(key), repeat, .. } => {
+                        let action = match bindings.get(&key) {
+                            Some(action) => *action,
+                            None => continue,
+                        };
+                        if let Some(button) = button(&mut buttons, action) {
+                            *button = true;
+                            continue;
+                        }
+                        match action {
+                            Action::Quit => break 'running,
+                            Action::FastForward => fast_forward = true,
+                            // Frame advance, pausing first if needed.
+                            Action::FrameAdvance => {
+                                paused = true;
+                                advance = true;
+                            }
+                            _ if repeat => {}
+                            Action::Slower if speed > 0 => {
+                                speed -= 1;
+                                println!("Speed: {}", SPEEDS[speed].0);
+                            }
+                            Action::Faster if speed + 1 < SPEEDS.len() => {
+                                speed += 1;
+                                println!("Speed: {}", SPEEDS[speed].0);
+                            }
+                            Action::Pause => {
+                                paused = !paused;
+                                println!("{}", if paused { "Paused" } else { "Resumed" });
+                            }
+                            Action::Reset => gbconnect.to_gb.send(Input::Reset).unwrap(),
+                            Action::Reload => gbconnect.to_gb.send(Input::Reload).unwrap(),
+                            Action::Video => gbconnect.to_gb.send(Input::Video).unwrap(),
+                            Action::Movie => gbconnect.to_gb.send(Input::Movie).unwrap(),
+                            Action::Palette => {
+                                current_palette = (current_palette + 1) % palettes.len();
+                                palette = palettes[current_palette].colours;
+                                println!("Palette: {}", palettes[current_palette].name);
+                                gbconnect.to_gb.send(Input::Palette(palette)).unwrap();
+                            }
+                            Action::Border => {
+                                show_border = !show_border;
+                                println!("Border {}", if show_border { "shown" } else { "hidden" });
+                            }
+                            Action::Screenshot => {
+                                let path = gb::capture_path(&rom, "png");
+                                match capture::save_png(&path, &shown, shown_width) {
+                                    Ok(()) => println!("Screenshot saved to {}", path),
+                                    Err(e) => eprintln!("{}", e),
+                                }
+                            }
+                            // Written on the side so the game does not stutter.
+                            Action::Gif => {
+                                let path = gb::capture_path(&rom, "gif");
+                                let (width, frames) = (clip.width(), clip.frames());
+                                thread::spawn(move || {
+                                    match capture::save_gif(&path, width, &frames) {
+                                        Ok(()) => println!("Gif saved to {}", path),
+                                        Err(e) => eprintln!("{}", e),
+                                    }
+                                });
+                            }
+                            _ => {}
+                        }
+                    }
+                    Event::ControllerDeviceAdded { which, .. } => {
+                        if let Ok(controller) = controller_subsystem.open(which) {
+                            println!("Controller connected: {}", controller.name());
+                            pads.insert(controller.instance_id(), Pad::new(controller));
+                        }
+                    }
+                    Event::ControllerDeviceRemoved { which, .. } => {
+                        if let Some(pad) = pads.remove(&which) {
+                            println!("Controller disconnected: {}", pad.controller.name());
+                        }
+                    }
+                    Event::ControllerButtonDown { which, button: pressed, .. } => {
+                        let action = pad_bindings.get(&pressed);
+                        if let (Some(pad), Some(&action)) = (pads.get_mut(&which), action) {
+                            if let Some(b) = button(&mut pad.buttons, action) {
+                                *b = true;
+                            }
+                        }
+                    }
+                    Event::ControllerButtonUp { which, button: released, .. } => {
+                        let action = pad_bindings.get(&released);
+                        if let (Some(pad), Some(&action)) = (pads.get_mut(&which), action) {
+                            if let Some(b) = button(&mut pad.buttons, action) {
+                                *b = false;
+                            }
+                        }
+                    }
+                    Event::ControllerAxisMotion { which, axis: Axis::LeftX, value, .. } => {
+                        if let Some(pad) = pads.get_mut(&which) {
+                            pad.stick.left = value < -deadzone;
+                            pad.stick.right = value > deadzone;
+                        }
+                    }
+                    Event::ControllerAxisMotion { which, axis: Axis::LeftY, value, .. } => {
+                        if let Some(pad) = pads.get_mut(&which) {
+                            pad.stick.up = value < -deadzone;
+                            pad.stick.down = value > deadzone;
+                        }
+                    }
+                    Event::KeyUp { keycode: Some(key), .. } => {
+                        match bindings.get(&key) {
+                            Some(&Action::FastForward) => fast_forward = false,
+                            Some(&action) => if let Some(button) = button(&mut buttons, action) {
+                                *button = false;
+                            },
+                            None => {}
+                        }
+                    }
+                    _ => {}
                 }
             }
-            use std::sync::mpsc::TryRecvError;
 
-            match gbconnect.from_gb.try_recv() {
+            // The gameboy sees changes straight away, not only between frames.
+            let held: Vec<_> = pads.values().map(Pad::held).chain(Some(buttons)).collect();
+            gbconnect.buttons.set(combine(&held));
+
+            let (_, frames, frame_times) = if fast_forward { FAST_FORWARD } else { SPEEDS[speed] };
+            if frames != frames_per_shown {
+                frames_per_shown = frames;
+                gbconnect.to_gb.send(Input::Speed(frames)).unwrap();
+            }
+            if let Some(since) = waiting {
+                // Vsync already held the frame up for one frame time.
+                let due = since.elapsed() >= FRAME_TIME * (frame_times - 1);
+                if (!paused && due) || advance {
+                    advance = false;
+                    waiting = None;
+                    gbconnect.to_gb.send(Input::Next).unwrap();
+                }
+            }
+            use std::sync::mpsc::RecvTimeoutError;
+
+            // Waiting here rather than spinning keeps it idle while paused or waiting
+            // for a frame's time to pass.
+            match gbconnect.from_gb.recv_timeout(POLL_TIME) {
                 Ok(Output::Frame) => {
-                    for texture in &mut live_textures {
-                        match texture.0 {
-                            TextureType::Screen => {
-                                texture
-                                    .1
-                                    .with_lock(None, |buffer: &mut [u8], _pitch: usize| {
-                                        let frame = gbconnect.canvas.lock().unwrap();
-                                        buffer.copy_from_slice(&**frame);
-                                    })
-                                .unwrap();
+                    let kind = match border && show_border {
+                        true => TextureType::Bordered,
+                        false => TextureType::Screen,
+                    };
+                    let (width, height) = kind.size();
+                    if width as usize != shown_width {
+                        shown_width = width as usize;
+                        shown = vec![0xFF; (width * height * 3) as usize];
+                        self.canvas.window_mut().set_size(width * self.scale, height * self.scale)
+                            .unwrap();
+                    }
+                    {
+                        let frame = gbconnect.canvas.lock().unwrap();
+                        // Where the shown part starts and how wide a row of the frame is.
+                        let (start, row) = match border {
+                            true => ((sgb::TOP * sgb::WIDTH + sgb::LEFT) * 3, sgb::WIDTH * 3),
+                            false => (0, GAMEBOY_WIDTH as usize * 3),
+                        };
+                        let start = if show_border { 0 } else { start };
+                        for (y, line) in shown.chunks_mut(shown_width * 3).enumerate() {
+                            let from = &frame[start + y * row..start + y * row + line.len()];
+                            match colour {
+                                true => line.copy_from_slice(from),
+                                false => for (out, shade) in line.chunks_mut(3).zip(from.chunks(3)) {
+                                    out.copy_from_slice(&palette[gb::shade_index(shade[0])]);
+                                },
                             }
                         }
+                    }
+                    clip.push(&shown, shown_width, frames_per_shown);
+                    for texture in live_textures.iter_mut().filter(|t| t.0.size() == (width, height)) {
+                        texture.1
+                            .with_lock(None, |buffer: &mut [u8], pitch: usize| {
+                                for (line, out) in shown.chunks(shown_width * 3).zip(buffer.chunks_mut(pitch)) {
+                                    out[..line.len()].copy_from_slice(line);
+                                }
+                            })
+                        .unwrap();
                         self.canvas.copy(&texture.1, None, None).unwrap();
                     }
                     self.canvas.present();
                     fps += 1;
-
-                    gbconnect.to_gb.send(Input::Buttons(gb::mem::Buttons {
-                        a,
-                        b,
-                        select,
-                        start,
-                        up,
-                        down,
-                        left,
-                        right,
-                    })).unwrap();}
-                    Err(err) => {
-                        match err {
-                            TryRecvError::Disconnected => {
-                                panic!("CPU halted unexpectedly.");
-                            }
-                            _ => {}
-                        };
-                    }
+                    waiting = Some(Instant::now());
+                }
+                Ok(Output::Lockup { pc, opcode, cpu }) => lockup(pc, opcode, &cpu, break_on_lockup),
+                Ok(Output::MovieFinished { .. }) => {}
+                Ok(Output::Colour(in_colour)) => colour = in_colour,
+                Ok(Output::Border(bordered)) => border = bordered,
+                Err(RecvTimeoutError::Disconnected) => panic!("CPU halted unexpectedly."),
+                Err(RecvTimeoutError::Timeout) => {}
             }
 
             if SystemTime::now().duration_since(start_time).unwrap() > Duration::from_secs(1) {
@@ -156,10 +417,48 @@ impl Window {
                 start_time = SystemTime::now();
             }
         }
+        gbconnect.stop();
+    }
+}
+
+fn lockup(pc: u16, opcode: u8, cpu: &str, break_on_lockup: bool) {
+    eprintln!("CPU locked up on illegal opcode {:02X} at {:04X}", opcode, pc);
+    if break_on_lockup {
+        eprintln!("{}", cpu);
+        std::process::exit(1);
     }
 }
 
-fn read_arguments() -> (String, std::option::Option<String>){
+/// Run without a window as fast as possible, for `frames` frames or until the movie
+/// being played ends, which fails if it did not play back the same.
+fn headless(gbconnect: gb::GbConnect, frames: Option<usize>, break_on_lockup: bool) {
+    let mut count = 0;
+    let mut desynced = false;
+    loop {
+        match gbconnect.from_gb.recv() {
+            Ok(Output::Frame) => {
+                count += 1;
+                if Some(count) == frames {
+                    break;
+                }
+                gbconnect.to_gb.send(Input::Next).unwrap();
+            }
+            Ok(Output::Lockup { pc, opcode, cpu }) => lockup(pc, opcode, &cpu, break_on_lockup),
+            Ok(Output::MovieFinished { desynced: different }) => {
+                desynced = different;
+                break;
+            }
+            Ok(Output::Colour(_)) | Ok(Output::Border(_)) => {}
+            Err(_) => panic!("CPU halted unexpectedly."),
+        }
+    }
+    gbconnect.stop();
+    if desynced {
+        std::process::exit(1);
+    }
+}
+
+fn read_arguments() -> Args {
     let app = clap_app!(FeGaBo =>
         (version: "0.1")
         (author: "Raphael BN")
@@ -168,6 +467,21 @@ fn read_arguments() -> (String, std::option::Option<String>){
         (@arg BOOTROM: "Sets the file to use as the bootrom")
         // (@arg debug: -d ... "Sets the level of debugging information")
         (@arg disassemble: -d "Disassemble the given file")
+        (@arg assemble: -a "Assemble the given file, writing the bytes to stdout")
+        (@arg break_on_lockup: -l --("break-on-lockup") "Exit with the cpu state when an illegal opcode is run")
+        (@arg watch: -w --watch "Reload the rom whenever it changes on disk")
+        (@arg keep_ram: -k --("keep-ram") "Keep work ram and cartrage ram over reloads")
+        (@arg record: --record +takes_value conflicts_with[play] "Record the buttons pressed to a movie file")
+        (@arg play: --play +takes_value "Play back a movie file, checking it runs the same")
+        (@arg video: --video +takes_value "Record video of everything from power on to a .y4m file")
+        (@arg headless: --headless "Run without a window, as fast as possible")
+        (@arg frames: --frames +takes_value "Frames to run when headless")
+        (@arg config: -c --config +takes_value "Config file to use instead of ~/.config/fegabo/config.toml")
+        (@arg scale: -s --scale +takes_value "Window size as a multiple of the screen")
+        (@arg link: --link +takes_value "Connect a link cable to another FeGaBo at this address, like 127.0.0.1:8765, waiting for it if it is not running yet")
+        (@arg sgb: --sgb "Run games that support it on a Super Game Boy, with its border and colours")
+        (@arg colourise: --colourise "Show monochrome games in colour like a Game Boy Color, hold a direction and A or B at power on to pick")
+        (@arg palette: -p --palette +takes_value "A palette name, palette file or four colours, lightest first, like #FFFFFF,#AAAAAA,#555555,#000000")
     ).get_matches();
 
     if app.is_present("disassemble") {
@@ -175,18 +489,112 @@ fn read_arguments() -> (String, std::option::Option<String>){
         std::process::exit(0);
     }
 
-    (String::from(app.value_of("ROM").unwrap()), match app.value_of("BOOTROM"){
-        Some(bootrom) => Some(String::from(bootrom)),
-        _ => None,
-    })
+    if app.is_present("assemble") {
+        if let Err(e) = gb::assemble(app.value_of("ROM").unwrap()) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        std::process::exit(0);
+    }
+
+    Args {
+        roms: (String::from(app.value_of("ROM").unwrap()), app.value_of("BOOTROM").map(String::from)),
+        break_on_lockup: app.is_present("break_on_lockup"),
+        headless: app.is_present("headless"),
+        frames: app.value_of("frames").map(|f| f.parse().unwrap_or_else(|_| {
+            eprintln!("Frames must be a number");
+            std::process::exit(1);
+        })),
+        settings: gb::Settings {
+            watch: app.is_present("watch"),
+            keep_ram: app.is_present("keep_ram"),
+            movie: match (app.value_of("record"), app.value_of("play")) {
+                (Some(path), _) => Some(gb::Movie::Record(path.to_string())),
+                (_, Some(path)) => Some(gb::Movie::Play(path.to_string())),
+                _ => None,
+            },
+            video: app.value_of("video").map(String::from),
+            palette: palette::built_in()[0].colours,
+            colourise: app.is_present("colourise"),
+            sgb: app.is_present("sgb"),
+            link: app.value_of("link").map(String::from),
+        },
+        config: app.value_of("config").map(String::from),
+        scale: app.value_of("scale").map(|s| s.parse().unwrap_or_else(|_| {
+            eprintln!("Scale must be a number");
+            std::process::exit(1);
+        })),
+        palette: app.value_of("palette").map(String::from),
+    }
 }
 
 pub fn main() {
-    let roms = read_arguments();
+    let mut args = read_arguments();
+    let fail = |e: String| -> ! {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    };
+
+    // The command line wins over the config file.
+    let mut config = Config::load(args.config.as_deref())
+        .unwrap_or_else(|e| fail(e));
+    if let Some(scale) = args.scale {
+        config.scale = scale;
+    }
+    if let Some(ref palette) = args.palette {
+        config.palette = match palette.contains(',') {
+            true => PaletteSetting::Colours(palette.split(',').map(|c| c.trim().to_string()).collect()),
+            false => PaletteSetting::Name(palette.clone()),
+        };
+    }
+    if args.roms.1.is_none() {
+        args.roms.1 = config.boot_rom.clone();
+    }
+    let (palettes, start_palette) = config.palettes().unwrap_or_else(|e| fail(e));
+    args.settings.palette = palettes[start_palette].colours;
+    args.settings.colourise |= config.colourise;
+    args.settings.sgb |= config.sgb;
+
+    if args.headless {
+        let playing = matches!(args.settings.movie, Some(gb::Movie::Play(_)));
+        if args.frames.is_none() && !playing {
+            fail("Headless needs --frames or a movie to --play, or it would never stop".to_string());
+        }
+        let gbconnect = gb::connect(args.roms, args.settings).unwrap_or_else(|e| fail(e));
+        headless(gbconnect, args.frames, args.break_on_lockup);
+        return;
+    }
+
+    let mut bindings = HashMap::new();
+    for (name, action) in config.bindings() {
+        match Keycode::from_name(name) {
+            Some(key) => { bindings.insert(key, action); }
+            None => fail(format!("Unknown key \"{}\" for {:?}", name, action)),
+        }
+    }
+
+    let mut pad_bindings = HashMap::new();
+    for (name, action) in config.controller_bindings() {
+        match Button::from_string(name) {
+            Some(pressed) => { pad_bindings.insert(pressed, action); }
+            None => fail(format!("Unknown controller button \"{}\" for {:?}", name, action)),
+        }
+    }
 
-    let window = Window::new();
+    let window = Window::new(config.scale);
 
-    let gbconnect = gb::connect(roms);
+    let rom = args.roms.0.clone();
+    let gbconnect = gb::connect(args.roms, args.settings).unwrap_or_else(|e| fail(e));
 
-    window.event_loop(gbconnect);
+    window.event_loop(gbconnect, Controls {
+        break_on_lockup: args.break_on_lockup,
+        bindings,
+        pad_bindings,
+        deadzone: config.controller.deadzone,
+        palettes,
+        start_palette,
+        rom,
+        gif_seconds: config.gif_seconds,
+        show_border: config.sgb_border,
+    });
 }
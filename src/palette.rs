@@ -0,0 +1,78 @@
+//! Colours to show the four shades in.  The gameboy thread only ever draws greys outside
+//! colour mode, so switching palette changes nothing about how a game runs.  Colour games
+//! bring their own colours and are shown as they are.
+
+use std::fs;
+
+/// Colours for the four shades, lightest first.
+pub type Colours = [[u8; 3]; 4];
+
+pub struct Palette {
+    pub name: String,
+    pub colours: Colours,
+}
+
+/// Palettes that are always there, the first is the default.
+pub const BUILT_IN: [(&str, [&str; 4]); 3] = [
+    ("grey", ["#FFFFFF", "#AAAAAA", "#555555", "#000000"]),
+    // The green of the original screen.
+    ("dmg", ["#9BBC0F", "#8BAC0F", "#306230", "#0F380F"]),
+    ("pocket", ["#C4CFA1", "#8B956D", "#4D533C", "#1F1F1F"]),
+];
+
+pub fn built_in() -> Vec<Palette> {
+    BUILT_IN.iter().map(|(name, colours)| Palette {
+        name: name.to_string(),
+        colours: parse(&colours.iter().map(|c| c.to_string()).collect::<Vec<_>>())
+            .expect("Built in palettes are valid"),
+    }).collect()
+}
+
+/// Four "#RRGGBB" colours, lightest first.
+pub fn parse(colours: &[String]) -> Result<Colours, String> {
+    if colours.len() != 4 {
+        return Err(format!("palette needs 4 colours, not {}", colours.len()));
+    }
+    let mut palette = [[0; 3]; 4];
+    for (shade, colour) in palette.iter_mut().zip(colours) {
+        let hex = colour.trim_start_matches('#');
+        let value = match hex.len() {
+            6 => u32::from_str_radix(hex, 16).ok(),
+            _ => None,
+        };
+        match value {
+            Some(value) => *shade = [(value >> 16) as u8, (value >> 8) as u8, value as u8],
+            None => return Err(format!("\"{}\" is not a colour like #RRGGBB", colour)),
+        }
+    }
+    Ok(palette)
+}
+
+/// A palette file has a colour on each line, lightest first, the way .hex palettes are.
+/// Blank lines and lines starting with ; are skipped.
+pub fn load(path: &str) -> Result<Colours, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    let colours: Vec<String> = text.lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with(';'))
+        .map(String::from)
+        .collect();
+    parse(&colours).map_err(|e| format!("{}: {}", path, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use palette::{built_in, parse};
+
+    #[test]
+    fn colours() {
+        let colours = ["#E0F8D0", "#88C070", "346856", "#081820"]
+            .iter().map(|c| c.to_string()).collect::<Vec<_>>();
+        assert_eq!(parse(&colours).unwrap(),
+                   [[0xE0, 0xF8, 0xD0], [0x88, 0xC0, 0x70], [0x34, 0x68, 0x56], [0x08, 0x18, 0x20]]);
+        assert!(parse(&colours[..1]).is_err());
+        assert!(parse(&["white".to_string(), "#AAAAAA".to_string(),
+                        "#555555".to_string(), "#000000".to_string()]).is_err());
+        assert_eq!(built_in()[0].colours[1], [0xAA, 0xAA, 0xAA]);
+    }
+}
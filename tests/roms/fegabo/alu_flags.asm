@@ -0,0 +1,116 @@
+; Results and flags of arithmetic with the half carries and borrows that are easy
+; to get backwards.  Reports over serial like Blargg's roms.
+
+        ORG $0000
+        ORG $0100
+        NOP
+        JP start
+
+        ORG $0150
+start:  LD A, $0F
+        ADD A, $01
+        LD DE, $1020
+        CALL check
+        LD A, $FF
+        ADD A, $01
+        LD DE, $00B0
+        CALL check
+        LD A, $10
+        SUB $01
+        LD DE, $0F60
+        CALL check
+        LD A, $00
+        SUB $01
+        LD DE, $FF70
+        CALL check
+        SCF
+        LD A, $0E
+        ADC A, $01
+        LD DE, $1020
+        CALL check
+        SCF
+        LD A, $10
+        SBC A, $0F
+        LD DE, $00E0
+        CALL check
+        LD A, $05
+        CP $06
+        LD DE, $0570
+        CALL check
+        LD A, $09
+        ADD A, $01
+        DAA
+        LD DE, $1000
+        CALL check
+        LD A, $10
+        SUB $01
+        DAA
+        LD DE, $0940
+        CALL check
+        SCF
+        LD A, $FF
+        INC A
+        LD DE, $00B0
+        CALL check
+        XOR A
+        LD HL, $0FFF
+        LD BC, $0001
+        ADD HL, BC
+        LD DE, $00A0
+        CALL check
+        XOR A
+        LD HL, SP+2
+        LD DE, $0030
+        CALL check
+        XOR A
+        SCF
+        CCF
+        LD DE, $0080
+        CALL check
+        SCF
+        LD A, $F0
+        SWAP A
+        LD DE, $0F00
+        CALL check
+        LD A, $01
+        RRCA
+        LD DE, $8010
+        CALL check
+        SCF
+        LD A, $7F
+        BIT 7, A
+        LD DE, $7FB0
+        CALL check
+        LD HL, passed
+        CALL print
+        JR end
+
+; Fails unless A is D and F is E.
+check:  PUSH AF
+        POP BC
+        LD A, B
+        CP D
+        JR NZ, failed
+        LD A, C
+        CP E
+        JR NZ, failed
+        RET
+
+failed: LD HL, failure
+        CALL print
+end:    JR end
+
+; Sends the zero terminated text at HL over serial.
+print:  LD A, (HL+)
+        CP $00
+        RET Z
+        LD (FF00+$01), A
+        LD A, $81
+        LD (FF00+$02), A
+wait:   LD A, (FF00+$02)
+        BIT 7, A
+        JR NZ, wait
+        JR print
+
+passed:  DB $50, $61, $73, $73, $65, $64, $0A, $00
+failure: DB $46, $61, $69, $6C, $65, $64, $0A, $00
@@ -0,0 +1,53 @@
+; EI only enables interupts after the instruction that follows it, so EI then DI
+; never takes one that is waiting, while EI then NOP does.  Reports over serial
+; like Blargg's roms.
+
+        ORG $0000
+        ORG $0050
+        INC B
+        RETI
+
+        ORG $0100
+        NOP
+        JP start
+
+        ORG $0150
+start:  DI
+        LD B, $00
+        LD A, $04
+        LD (FF00+$FF), A
+        LD (FF00+$0F), A
+        EI
+        DI
+        NOP
+        LD A, B
+        CP $00
+        JR NZ, failed
+        EI
+        NOP
+        NOP
+        LD A, B
+        CP $01
+        JR NZ, failed
+        LD HL, passed
+        CALL print
+        JR end
+
+failed: LD HL, failure
+        CALL print
+end:    JR end
+
+; Sends the zero terminated text at HL over serial.
+print:  LD A, (HL+)
+        CP $00
+        RET Z
+        LD (FF00+$01), A
+        LD A, $81
+        LD (FF00+$02), A
+wait:   LD A, (FF00+$02)
+        BIT 7, A
+        JR NZ, wait
+        JR print
+
+passed:  DB $50, $61, $73, $73, $65, $64, $0A, $00
+failure: DB $46, $61, $69, $6C, $65, $64, $0A, $00
@@ -0,0 +1,49 @@
+; TIMA overflowing raises the timer interupt.  Ends with LD B, B like Mooneye's
+; roms, with 3, 5, 8, 13, 21 and 34 in B to L if it passed.
+
+        ORG $0000
+        ORG $0050
+        LD D, $01
+        RETI
+
+        ORG $0100
+        NOP
+        JP start
+
+        ORG $0150
+start:  DI
+        LD A, $04
+        LD (FF00+$FF), A
+        XOR A
+        LD (FF00+$0F), A
+        LD (FF00+$06), A
+        LD A, $FE
+        LD (FF00+$05), A
+        LD A, $05
+        LD (FF00+$07), A
+        LD D, $00
+        LD E, $00
+        EI
+wait:   LD A, D
+        CP $01
+        JR Z, passed
+        DEC E
+        JR NZ, wait
+
+        LD B, $42
+        LD C, $42
+        LD D, $42
+        LD E, $42
+        LD H, $42
+        LD L, $42
+        LD B, B
+        JR end
+
+passed: LD B, $03
+        LD C, $05
+        LD D, $08
+        LD E, $0D
+        LD H, $15
+        LD L, $22
+        LD B, B
+end:    JR end
@@ -0,0 +1,42 @@
+#!/bin/sh
+# Fetches Blargg's and Mooneye's test roms from the gameboy-test-roms collection
+# into blargg/ and mooneye/, where rom_tests::test_roms runs them.
+#
+#     sh tests/roms/fetch.sh
+#
+# The release is pinned below.  The first fetch writes the archive's sha256 to
+# fetch.sha256 and later ones refuse an archive that does not match it.
+set -e
+cd "$(dirname "$0")"
+version=v7.0
+url=https://github.com/c-sp/gameboy-test-roms/releases/download/$version/game-boy-test-roms-$version.zip
+
+work=$(mktemp -d)
+trap 'rm -rf "$work"' EXIT
+curl --fail --location --silent --show-error --output "$work/roms.zip" "$url"
+sum=$(sha256sum "$work/roms.zip" | cut -d' ' -f1)
+if [ -f fetch.sha256 ] && [ "$(cat fetch.sha256)" != "$sum" ]; then
+    echo "$url does not match fetch.sha256" >&2
+    exit 1
+fi
+echo "$sum" > fetch.sha256
+unzip -q "$work/roms.zip" -d "$work/roms"
+
+# Finds a directory or file in the archive, wherever the release put it.
+find_in() {
+    found=$(find "$work/roms" -path "*/$1" | head -n 1)
+    if [ -z "$found" ]; then
+        echo "No $1 in $url" >&2
+        exit 1
+    fi
+    echo "$found"
+}
+
+rm -rf blargg mooneye
+mkdir -p blargg/cpu_instrs blargg/instr_timing blargg/mem_timing mooneye
+cp -r "$(find_in blargg/cpu_instrs/individual)" blargg/cpu_instrs/
+cp "$(find_in blargg/instr_timing/instr_timing.gb)" blargg/instr_timing/
+cp -r "$(find_in blargg/mem_timing/individual)" blargg/mem_timing/
+cp "$(find_in blargg/halt_bug.gb)" blargg/
+cp -r "$(find_in mooneye-test-suite/acceptance)" mooneye/
+echo "Fetched $(find blargg mooneye -name '*.gb' | wc -l) roms"
@@ -0,0 +1,85 @@
+; A still scene for the screenshot test, drawn by scene.py into fegabo-acid.png
+; without the emulator.  It covers the scroll registers, both object pallets and
+; the object flip and priority bits, the parts of dmg-acid2 the ppu draws so far.
+; The window is left out until the ppu has one.
+;
+; Tiles: 0 blank, 1 solid colour 3, 2 rows of colour 0 and 2, 3 quarters of
+; colour 3 and 0 above 1 and 2.  The background is tile 2 with a bar of tile 1,
+; scrolled by 4, 2.  Five objects of tile 3 sit on line 40: plain, X flipped,
+; Y flipped, OBP1 and behind the background.
+
+        ORG $0000
+        ORG $0100
+        NOP
+        JP start
+
+        ORG $0150
+start:  DI
+vblank: LD A, (FF00+$44)
+        CP $90
+        JR NZ, vblank
+        XOR A
+        LD (FF00+$40), A
+
+        LD HL, $8000
+        LD DE, tiles
+        LD B, $40
+tile:   LD A, (DE)
+        INC DE
+        LD (HL+), A
+        DEC B
+        JR NZ, tile
+
+        LD HL, $9800
+        LD BC, $0400
+        LD E, $02
+        CALL fill
+        LD HL, $9842
+        LD BC, $0010
+        LD E, $01
+        CALL fill
+
+        LD HL, $FE00
+        LD BC, $00A0
+        LD E, $00
+        CALL fill
+        LD HL, $FE00
+        LD DE, objects
+        LD B, $14
+object: LD A, (DE)
+        INC DE
+        LD (HL+), A
+        DEC B
+        JR NZ, object
+
+        LD A, $02
+        LD (FF00+$42), A
+        LD A, $04
+        LD (FF00+$43), A
+        LD A, $E4
+        LD (FF00+$47), A
+        LD (FF00+$48), A
+        LD A, $1B
+        LD (FF00+$49), A
+        LD A, $93
+        LD (FF00+$40), A
+end:    JR end
+
+; Writes E to BC bytes from HL.
+fill:   LD A, E
+        LD (HL+), A
+        DEC BC
+        LD A, B
+        OR C
+        JR NZ, fill
+        RET
+
+tiles:   DB $00, $00, $00, $00, $00, $00, $00, $00, $00, $00, $00, $00, $00, $00, $00, $00
+         DB $FF, $FF, $FF, $FF, $FF, $FF, $FF, $FF, $FF, $FF, $FF, $FF, $FF, $FF, $FF, $FF
+         DB $00, $00, $00, $FF, $00, $00, $00, $FF, $00, $00, $00, $FF, $00, $00, $00, $FF
+         DB $F0, $F0, $F0, $F0, $F0, $F0, $F0, $F0, $F0, $0F, $F0, $0F, $F0, $0F, $F0, $0F
+objects: DB $38, $18, $03, $00
+         DB $38, $28, $03, $20
+         DB $38, $38, $03, $40
+         DB $38, $48, $03, $10
+         DB $38, $58, $03, $80
@@ -0,0 +1,91 @@
+#!/usr/bin/env python3
+"""Draws fegabo-acid.png, what fegabo-acid.asm should put on screen.
+
+Follows the Pan Docs description of the DMG screen rather than FeGaBo's ppu, so
+the two can be checked against each other.  Needs only the standard library.
+
+    python3 tests/screenshots/scene.py
+"""
+
+import os
+import struct
+import zlib
+
+WIDTH, HEIGHT = 160, 144
+GREYS = [255, 170, 85, 0]
+
+# Colour numbers of tiles 0 to 3 as rows of 8 pixels.
+TILES = [
+    [[0] * 8] * 8,
+    [[3] * 8] * 8,
+    [[0] * 8 if row % 2 == 0 else [2] * 8 for row in range(8)],
+    [[3] * 4 + [0] * 4] * 4 + [[1] * 4 + [2] * 4] * 4,
+]
+
+SCY, SCX = 2, 4
+BGP, OBP0, OBP1 = 0xE4, 0xE4, 0x1B
+
+# y, x, tile, attributes, in OAM's coordinates.
+OBJECTS = [(0x38, 0x18, 3, 0x00), (0x38, 0x28, 3, 0x20), (0x38, 0x38, 3, 0x40),
+           (0x38, 0x48, 3, 0x10), (0x38, 0x58, 3, 0x80)]
+
+
+def shade(pallet, colour):
+    return (pallet >> (colour * 2)) & 3
+
+
+def background_map():
+    tiles = [2] * 0x400
+    for i in range(0x42, 0x52):
+        tiles[i] = 1
+    return tiles
+
+
+def pixel(tile_map, x, y):
+    return TILES[tile_map[(y // 8) * 32 + x // 8]][y % 8][x % 8]
+
+
+def scene():
+    background = background_map()
+    screen = []
+    for y in range(HEIGHT):
+        row = []
+        for x in range(WIDTH):
+            colour = pixel(background, (x + SCX) % 256, (y + SCY) % 256)
+            shown = shade(BGP, colour)
+            for oam_y, oam_x, tile, attributes in OBJECTS:
+                ty, tx = y - (oam_y - 16), x - (oam_x - 8)
+                if not (0 <= ty < 8 and 0 <= tx < 8):
+                    continue
+                if attributes & 0x40:
+                    ty = 7 - ty
+                if attributes & 0x20:
+                    tx = 7 - tx
+                object_colour = TILES[tile][ty][tx]
+                if object_colour == 0:
+                    continue
+                if attributes & 0x80 and colour != 0:
+                    break
+                shown = shade(OBP1 if attributes & 0x10 else OBP0, object_colour)
+                break
+            row.append(GREYS[shown])
+        screen.append(row)
+    return screen
+
+
+def write_png(path, screen):
+    def chunk(kind, data):
+        body = kind + data
+        return struct.pack(">I", len(data)) + body + struct.pack(">I", zlib.crc32(body))
+
+    raw = b"".join(b"\0" + bytes(grey for grey in row for _ in range(3)) for row in screen)
+    with open(path, "wb") as png:
+        png.write(b"\x89PNG\r\n\x1a\n")
+        png.write(chunk(b"IHDR", struct.pack(">IIBBBBB", WIDTH, HEIGHT, 8, 2, 0, 0, 0)))
+        png.write(chunk(b"IDAT", zlib.compress(raw, 9)))
+        png.write(chunk(b"IEND", b""))
+
+
+if __name__ == "__main__":
+    write_png(os.path.join(os.path.dirname(os.path.abspath(__file__)), "fegabo-acid.png"),
+              scene())
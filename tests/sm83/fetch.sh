@@ -0,0 +1,28 @@
+#!/bin/sh
+# Fetches the public SM83 single step tests into public/, which
+# cpu::tests::single_step_vectors runs along with the generated files here.
+#
+#     sh tests/sm83/fetch.sh [commit]
+#
+# The commit fetched is written to public.commit, and fetched again by later runs
+# unless another is given, so the files stay the same until that changes.
+set -e
+cd "$(dirname "$0")"
+repo=https://github.com/SingleStepTests/sm83
+commit=${1:-$(cat public.commit 2>/dev/null || true)}
+
+checkout=$(mktemp -d)
+trap 'rm -rf "$checkout"' EXIT
+git clone --quiet "$repo" "$checkout"
+if [ -n "$commit" ]; then
+    git -C "$checkout" checkout --quiet "$commit"
+fi
+if [ ! -d "$checkout/v1" ]; then
+    echo "No v1 directory in $repo, the layout has changed" >&2
+    exit 1
+fi
+rm -rf public
+mkdir public
+cp "$checkout"/v1/*.json public/
+git -C "$checkout" rev-parse HEAD > public.commit
+echo "Fetched $(ls public | wc -l) files from $(cat public.commit)"
@@ -0,0 +1,407 @@
+#!/usr/bin/env python3
+"""Writes single step test vectors for the SM83 in the format of
+https://github.com/SingleStepTests/sm83, from a model of the cpu written from
+the documentation rather than from FeGaBo, so the two can check each other.
+
+    python3 generate.py [cases per opcode]
+
+Every opcode but STOP and the illegal ones gets a file, `00.json` to `ff.json`
+and `cb 00.json` to `cb ff.json`.  Registers, flags, ime and memory start out
+random from a fixed seed, so the files only change when the model does.
+"""
+
+import json
+import os
+import random
+import sys
+
+HERE = os.path.dirname(os.path.abspath(__file__))
+ILLEGAL = {0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB, 0xEC, 0xED, 0xF4, 0xFC, 0xFD}
+# STOP depends on the joypad and speed switching, which a flat memory does not have.
+SKIPPED = {0x10}
+# Opcodes whose flags depend on more than a few cases reach: DAA, ADC, SBC and the
+# signed offsets from SP.
+MORE_CASES = {0x27: 400, 0x8E: 100, 0x9E: 100, 0xCE: 100, 0xDE: 100, 0xE8: 100, 0xF8: 100}
+
+Z, N, H, C = 0x80, 0x40, 0x20, 0x10
+R8 = ["b", "c", "d", "e", "h", "l", "(hl)", "a"]
+
+
+class Machine:
+    def __init__(self, rng, state):
+        self.rng = rng
+        self.r = dict(state)
+        self.ime = state["ime"]
+        self.memory = {}
+        self.initial = {}
+        self.cycles = []
+
+    def peek(self, addr):
+        """Memory as it is, anything not seen yet gets a random byte."""
+        addr &= 0xFFFF
+        if addr not in self.memory:
+            value = self.rng.randrange(256)
+            self.memory[addr] = value
+            self.initial[addr] = value
+        return self.memory[addr]
+
+    def read(self, addr):
+        value = self.peek(addr)
+        self.cycles.append([addr & 0xFFFF, value, "r-m"])
+        return value
+
+    def write(self, addr, value):
+        self.peek(addr)
+        self.memory[addr & 0xFFFF] = value & 0xFF
+        self.cycles.append([addr & 0xFFFF, value & 0xFF, "-wm"])
+
+    def idle(self):
+        self.cycles.append([None, None, "---"])
+
+    def fetch(self):
+        value = self.read(self.r["pc"])
+        self.r["pc"] = (self.r["pc"] + 1) & 0xFFFF
+        return value
+
+    def fetch16(self):
+        low = self.fetch()
+        return low | self.fetch() << 8
+
+    def flag(self, mask):
+        return self.r["f"] & mask != 0
+
+    def flags(self, z=None, n=None, h=None, c=None):
+        for mask, value in ((Z, z), (N, n), (H, h), (C, c)):
+            if value is not None:
+                self.r["f"] = (self.r["f"] | mask) if value else (self.r["f"] & ~mask)
+
+    def pair(self, name):
+        return self.r[name[0]] << 8 | self.r[name[1]]
+
+    def set_pair(self, name, value):
+        self.r[name[0]] = value >> 8 & 0xFF
+        self.r[name[1]] = value & 0xFF
+
+    def r16(self, index):
+        name = ["bc", "de", "hl", "sp"][index]
+        return self.r["sp"] if name == "sp" else self.pair(name)
+
+    def set_r16(self, index, value):
+        name = ["bc", "de", "hl", "sp"][index]
+        if name == "sp":
+            self.r["sp"] = value & 0xFFFF
+        else:
+            self.set_pair(name, value & 0xFFFF)
+
+    def get8(self, index):
+        if index == 6:
+            return self.read(self.pair("hl"))
+        return self.r[R8[index]]
+
+    def set8(self, index, value):
+        if index == 6:
+            self.write(self.pair("hl"), value)
+        else:
+            self.r[R8[index]] = value & 0xFF
+
+    def push(self, value):
+        self.r["sp"] = (self.r["sp"] - 1) & 0xFFFF
+        self.write(self.r["sp"], value >> 8)
+        self.r["sp"] = (self.r["sp"] - 1) & 0xFFFF
+        self.write(self.r["sp"], value)
+
+    def pop(self):
+        low = self.read(self.r["sp"])
+        self.r["sp"] = (self.r["sp"] + 1) & 0xFFFF
+        high = self.read(self.r["sp"])
+        self.r["sp"] = (self.r["sp"] + 1) & 0xFFFF
+        return high << 8 | low
+
+    def condition(self, index):
+        return [not self.flag(Z), self.flag(Z), not self.flag(C), self.flag(C)][index]
+
+    def alu(self, op, value):
+        a = self.r["a"]
+        carry = 1 if self.flag(C) else 0
+        if op in (0, 1):  # ADD, ADC
+            cin = carry if op == 1 else 0
+            result = a + value + cin
+            self.flags(result & 0xFF == 0, False, (a & 0xF) + (value & 0xF) + cin > 0xF, result > 0xFF)
+            self.r["a"] = result & 0xFF
+        elif op in (2, 3, 7):  # SUB, SBC, CP
+            cin = carry if op == 3 else 0
+            result = a - value - cin
+            self.flags(result & 0xFF == 0, True, (a & 0xF) < (value & 0xF) + cin, result < 0)
+            if op != 7:
+                self.r["a"] = result & 0xFF
+        elif op == 4:  # AND
+            self.r["a"] = a & value
+            self.flags(self.r["a"] == 0, False, True, False)
+        elif op == 5:  # XOR
+            self.r["a"] = a ^ value
+            self.flags(self.r["a"] == 0, False, False, False)
+        else:  # OR
+            self.r["a"] = a | value
+            self.flags(self.r["a"] == 0, False, False, False)
+
+    def sp_offset(self):
+        offset = self.fetch()
+        sp = self.r["sp"]
+        self.flags(False, False, (sp & 0xF) + (offset & 0xF) > 0xF, (sp & 0xFF) + offset > 0xFF)
+        signed = offset - 256 if offset > 127 else offset
+        return (sp + signed) & 0xFFFF
+
+    def rotate(self, op, value):
+        """The CB shifts and rotates, which the A only ones share."""
+        carry = 1 if self.flag(C) else 0
+        if op == 0:  # RLC
+            out, result = value >> 7, value << 1 | value >> 7
+        elif op == 1:  # RRC
+            out, result = value & 1, value >> 1 | (value & 1) << 7
+        elif op == 2:  # RL
+            out, result = value >> 7, value << 1 | carry
+        elif op == 3:  # RR
+            out, result = value & 1, value >> 1 | carry << 7
+        elif op == 4:  # SLA
+            out, result = value >> 7, value << 1
+        elif op == 5:  # SRA
+            out, result = value & 1, value >> 1 | value & 0x80
+        elif op == 6:  # SWAP
+            out, result = 0, (value << 4 | value >> 4)
+        else:  # SRL
+            out, result = value & 1, value >> 1
+        result &= 0xFF
+        self.flags(result == 0, False, False, out == 1)
+        return result
+
+    def cb(self):
+        op = self.fetch()
+        index, bit = op & 7, op >> 3 & 7
+        value = self.get8(index)
+        if op < 0x40:
+            self.set8(index, self.rotate(op >> 3, value))
+        elif op < 0x80:
+            self.flags(value >> bit & 1 == 0, False, True)
+        elif op < 0xC0:
+            self.set8(index, value & ~(1 << bit))
+        else:
+            self.set8(index, value | 1 << bit)
+
+    def step(self):
+        """Runs one instruction, returning its M-cycles."""
+        op = self.fetch()
+        x, y, z = op >> 6, op >> 3 & 7, op & 7
+        p, q = y >> 1, y & 1
+        if op == 0x00:
+            pass
+        elif op == 0x08:
+            addr = self.fetch16()
+            self.write(addr, self.r["sp"])
+            self.write(addr + 1, self.r["sp"] >> 8)
+        elif op == 0x18:
+            offset = self.fetch()
+            self.r["pc"] = (self.r["pc"] + offset - (256 if offset > 127 else 0)) & 0xFFFF
+            self.idle()
+        elif x == 0 and z == 0 and y >= 4:  # JR cc
+            offset = self.fetch()
+            if self.condition(y - 4):
+                self.r["pc"] = (self.r["pc"] + offset - (256 if offset > 127 else 0)) & 0xFFFF
+                self.idle()
+        elif x == 0 and z == 1 and q == 0:
+            self.set_r16(p, self.fetch16())
+        elif x == 0 and z == 1:  # ADD HL, rr
+            hl, value = self.pair("hl"), self.r16(p)
+            self.flags(None, False, (hl & 0xFFF) + (value & 0xFFF) > 0xFFF, hl + value > 0xFFFF)
+            self.set_pair("hl", (hl + value) & 0xFFFF)
+            self.idle()
+        elif x == 0 and z == 2:
+            addr = [self.pair("bc"), self.pair("de"), self.pair("hl"), self.pair("hl")][p]
+            if q == 0:
+                self.write(addr, self.r["a"])
+            else:
+                self.r["a"] = self.read(addr)
+            if p == 2:
+                self.set_pair("hl", (addr + 1) & 0xFFFF)
+            elif p == 3:
+                self.set_pair("hl", (addr - 1) & 0xFFFF)
+        elif x == 0 and z == 3:
+            self.set_r16(p, self.r16(p) + (1 if q == 0 else -1))
+            self.idle()
+        elif x == 0 and z in (4, 5):  # INC and DEC
+            value = self.get8(y)
+            if z == 4:
+                result = (value + 1) & 0xFF
+                self.flags(result == 0, False, value & 0xF == 0xF)
+            else:
+                result = (value - 1) & 0xFF
+                self.flags(result == 0, True, value & 0xF == 0)
+            self.set8(y, result)
+        elif x == 0 and z == 6:
+            self.set8(y, self.fetch())
+        elif x == 0 and z == 7:
+            if y < 4:  # RLCA, RRCA, RLA, RRA
+                self.r["a"] = self.rotate(y, self.r["a"])
+                self.flags(False)
+            elif y == 4:  # DAA
+                a = self.r["a"]
+                if not self.flag(N):
+                    if self.flag(C) or a > 0x99:
+                        a += 0x60
+                        self.flags(c=True)
+                    if self.flag(H) or a & 0xF > 9:
+                        a += 0x06
+                else:
+                    if self.flag(C):
+                        a -= 0x60
+                    if self.flag(H):
+                        a -= 0x06
+                a &= 0xFF
+                self.r["a"] = a
+                self.flags(a == 0, None, False)
+            elif y == 5:  # CPL
+                self.r["a"] ^= 0xFF
+                self.flags(None, True, True)
+            elif y == 6:  # SCF
+                self.flags(None, False, False, True)
+            else:  # CCF
+                self.flags(None, False, False, not self.flag(C))
+        elif op == 0x76:  # HALT
+            pass
+        elif x == 1:
+            self.set8(y, self.get8(z))
+        elif x == 2:
+            self.alu(y, self.get8(z))
+        elif x == 3 and z == 0 and y < 4:  # RET cc
+            self.idle()
+            if self.condition(y):
+                self.r["pc"] = self.pop()
+                self.idle()
+        elif op in (0xE0, 0xF0):
+            addr = 0xFF00 | self.fetch()
+            if op == 0xE0:
+                self.write(addr, self.r["a"])
+            else:
+                self.r["a"] = self.read(addr)
+        elif op == 0xE8:
+            self.r["sp"] = self.sp_offset()
+            self.idle()
+            self.idle()
+        elif op == 0xF8:
+            self.set_pair("hl", self.sp_offset())
+            self.idle()
+        elif x == 3 and z == 1 and q == 0:  # POP
+            value = self.pop()
+            if p == 3:
+                self.set_pair("af", value & 0xFFF0)
+            else:
+                self.set_r16(p, value)
+        elif op in (0xC9, 0xD9):  # RET, RETI
+            self.r["pc"] = self.pop()
+            self.idle()
+            if op == 0xD9:
+                self.ime = 1
+        elif op == 0xE9:
+            self.r["pc"] = self.pair("hl")
+        elif op == 0xF9:
+            self.r["sp"] = self.pair("hl")
+            self.idle()
+        elif x == 3 and z == 2 and y < 4:  # JP cc
+            addr = self.fetch16()
+            if self.condition(y):
+                self.r["pc"] = addr
+                self.idle()
+        elif op in (0xE2, 0xF2):
+            addr = 0xFF00 | self.r["c"]
+            if op == 0xE2:
+                self.write(addr, self.r["a"])
+            else:
+                self.r["a"] = self.read(addr)
+        elif op in (0xEA, 0xFA):
+            addr = self.fetch16()
+            if op == 0xEA:
+                self.write(addr, self.r["a"])
+            else:
+                self.r["a"] = self.read(addr)
+        elif op == 0xC3:
+            self.r["pc"] = self.fetch16()
+            self.idle()
+        elif op == 0xCB:
+            self.cb()
+        elif op == 0xF3:
+            self.ime = 0
+        elif op == 0xFB:
+            # Set straight away as the public tests do, though it only takes effect after
+            # the next instruction.
+            self.ime = 1
+        elif x == 3 and z == 4:  # CALL cc
+            addr = self.fetch16()
+            if self.condition(y):
+                self.idle()
+                self.push(self.r["pc"])
+                self.r["pc"] = addr
+        elif x == 3 and z == 5 and q == 0:  # PUSH
+            value = self.pair("af") if p == 3 else self.r16(p)
+            self.idle()
+            self.push(value)
+        elif op == 0xCD:
+            addr = self.fetch16()
+            self.idle()
+            self.push(self.r["pc"])
+            self.r["pc"] = addr
+        elif x == 3 and z == 6:
+            self.alu(y, self.fetch())
+        elif x == 3 and z == 7:  # RST
+            self.idle()
+            self.push(self.r["pc"])
+            self.r["pc"] = y * 8
+        else:
+            raise ValueError("No model of {:02X}".format(op))
+        return len(self.cycles)
+
+
+def state(r, ime, ram):
+    out = {name: r[name] for name in ("pc", "sp", "a", "b", "c", "d", "e", "f", "h", "l")}
+    out["ime"] = ime
+    out["ram"] = sorted([addr, value] for addr, value in ram.items())
+    return out
+
+
+def case(rng, opcode, number):
+    r = {name: rng.randrange(256) for name in "abcdehl"}
+    r["f"] = rng.randrange(16) << 4
+    r["sp"] = rng.randrange(0x10000)
+    r["pc"] = rng.randrange(0x10000)
+    r["ime"] = rng.randrange(2)
+    machine = Machine(rng, r)
+    prefix = opcode >> 8
+    machine.memory[r["pc"]] = machine.initial[r["pc"]] = prefix or opcode
+    if prefix:
+        at = (r["pc"] + 1) & 0xFFFF
+        machine.memory[at] = machine.initial[at] = opcode & 0xFF
+    machine.step()
+    name = "{:02x} {:04x}".format(opcode & 0xFF, number) if not prefix else \
+        "cb {:02x} {:04x}".format(opcode & 0xFF, number)
+    return {
+        "name": name,
+        "initial": state(r, r["ime"], machine.initial),
+        "final": state(machine.r, machine.ime, machine.memory),
+        "cycles": machine.cycles,
+    }
+
+
+def main():
+    cases = int(sys.argv[1]) if len(sys.argv) > 1 else 20
+    opcodes = [op for op in range(0x100) if op not in ILLEGAL | SKIPPED | {0xCB}]
+    opcodes += [0xCB00 | op for op in range(0x100)]
+    for opcode in opcodes:
+        rng = random.Random(opcode)
+        name = "{:02x}".format(opcode) if opcode < 0x100 else "cb {:02x}".format(opcode & 0xFF)
+        with open(os.path.join(HERE, name + ".json"), "w") as out:
+            out.write("[\n")
+            out.write(",\n".join(json.dumps(case(rng, opcode, n), separators=(",", ":"))
+                                 for n in range(max(cases, MORE_CASES.get(opcode, 0)))))
+            out.write("\n]\n")
+
+
+if __name__ == "__main__":
+    main()